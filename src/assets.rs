@@ -0,0 +1,317 @@
+use image::{RgbImage, Rgb};
+use image::io::Reader as ImageReader;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result, LoadPolicy, warn};
+use crate::texture::{MipMap, WrapMode};
+
+/// 相対パスのファイルを探すディレクトリ
+#[derive(Debug, Clone, Default)]
+pub struct SearchPaths {
+    pub dirs: Vec<PathBuf>,
+}
+impl SearchPaths {
+    pub fn new() -> SearchPaths {
+        SearchPaths::default()
+    }
+    pub fn push<P: AsRef<Path>>(&mut self, dir: P) {
+        self.dirs.push(dir.as_ref().to_path_buf());
+    }
+    /// `name`を`base`, 探索ディレクトリの順に探す. 見つからなければ`name`のまま(作業ディレクトリから)
+    pub fn resolve(&self, name: &str, base: Option<&Path>) -> String {
+        let path = Path::new(name);
+        if name.is_empty() || path.is_absolute() {
+            return name.to_string();
+        }
+        base.into_iter()
+            .chain(self.dirs.iter().map(|dir| dir.as_path()))
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+            .map(|found| {
+                // 別の書き方で同じファイルを指しても同じ名前になるようにする
+                let found = found.canonicalize().unwrap_or(found);
+                found.to_string_lossy().into_owned()
+            })
+            .unwrap_or_else(|| name.to_string())
+    }
+    /// 材質のテクスチャ名を`base`(MTLファイルのディレクトリ)から解決した名前に書き換える. オプションは残す
+    pub fn resolve_textures(&self, material: &mut tobj::Material, base: Option<&Path>) {
+        for texture in texture_names_mut(material) {
+            let mut spec = TextureSpec::parse(texture);
+            spec.file = self.resolve(&spec.file, base);
+            *texture = spec.to_string();
+        }
+    }
+}
+
+/// `unknown_param`に書かれるテクスチャ (MTLのPBR拡張と変位マップ)
+const PARAM_TEXTURES: [&str; 6] = ["map_Pr", "map_Pm", "map_Ps", "map_Pc", "norm", "disp"];
+
+/// 引数を1つだけとるオプション. それ以外は続く数を全て引数とする
+const WORD_OPTIONS: [&str; 7] = ["-blendu", "-blendv", "-cc", "-clamp", "-imfchan", "-type", "-wrap"];
+
+/// MTLのテクスチャ指定 (`-bm 0.5 brick.png`など) のオプションとファイル名
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TextureSpec {
+    pub file: String,
+    pub options: Vec<(String, Vec<String>)>,
+}
+impl TextureSpec {
+    pub fn parse(value: &str) -> TextureSpec {
+        let mut spec = TextureSpec::default();
+        let mut tokens = value.split_whitespace().peekable();
+        while let Some(token) = tokens.next_if(|token| token.starts_with('-') && token.parse::<f32>().is_err()) {
+            let mut args = Vec::new();
+            if WORD_OPTIONS.contains(&token) {
+                args.extend(tokens.next().map(String::from));
+            } else {
+                while let Some(arg) = tokens.next_if(|arg| arg.parse::<f32>().is_ok()) {
+                    args.push(arg.to_string());
+                }
+            }
+            spec.options.push((token.to_string(), args));
+        }
+        // ファイル名は空白を含むことがある
+        spec.file = tokens.collect::<Vec<_>>().join(" ");
+        spec
+    }
+    /// オプションの数の引数. 読めなければNone
+    pub fn numbers(&self, option: &str) -> Option<Vec<f32>> {
+        let (_, args) = self.options.iter().find(|(name, _)| name == option)?;
+        args.iter().map(|arg| arg.parse().ok()).collect()
+    }
+    /// `-clamp on`か, 拡張の`-wrap repeat|clamp|mirror`で指定したはみ出したUVの扱い
+    pub fn wrap(&self) -> Option<WrapMode> {
+        let word = |option: &str| self.options.iter()
+            .find(|(name, _)| name == option)
+            .and_then(|(_, args)| args.first())
+            .map(|arg| arg.as_str());
+        match (word("-wrap"), word("-clamp")) {
+            (Some("repeat"), _) | (None, Some("off")) => Some(WrapMode::Repeat),
+            (Some("clamp"), _) | (None, Some("on")) => Some(WrapMode::Clamp),
+            (Some("mirror"), _) => Some(WrapMode::Mirror),
+            (Some(value), _) => {
+                warn(&format!("unknown -wrap `{}` for {}, expected repeat, clamp or mirror", value, self.file));
+                None
+            },
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for TextureSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, args) in self.options.iter() {
+            write!(f, "{} ", name)?;
+            for arg in args.iter() {
+                write!(f, "{} ", arg)?;
+            }
+        }
+        write!(f, "{}", self.file)
+    }
+}
+
+/// 材質が色の画像として参照するテクスチャ名. 切り抜きのマスク (map_d) は`alpha_key`の名前で別に読む
+pub fn texture_names(material: &tobj::Material) -> Vec<&String> {
+    let mut names = vec![
+        &material.ambient_texture,
+        &material.diffuse_texture,
+        &material.specular_texture,
+        &material.normal_texture,
+        &material.shininess_texture,
+    ];
+    names.extend(PARAM_TEXTURES.iter().filter_map(|key| material.unknown_param.get(*key)));
+    names
+}
+
+fn texture_names_mut(material: &mut tobj::Material) -> Vec<&mut String> {
+    let mut names = vec![
+        &mut material.ambient_texture,
+        &mut material.diffuse_texture,
+        &mut material.specular_texture,
+        &mut material.normal_texture,
+        &mut material.shininess_texture,
+        &mut material.dissolve_texture,
+    ];
+    names.extend(material.unknown_param.iter_mut()
+        .filter(|(key, _)| PARAM_TEXTURES.contains(&key.as_str()))
+        .map(|(_, value)| value));
+    names
+}
+
+/// 読めないテクスチャの代わりに使うマゼンタと黒の市松模様
+pub fn missing_texture() -> RgbImage {
+    RgbImage::from_fn(64, 64, |x, y| if (x / 8 + y / 8) % 2 == 0 {
+        Rgb([255, 0, 255])
+    } else {
+        Rgb([0, 0, 0])
+    })
+}
+
+fn load_texture(path: &str) -> Result<RgbImage> {
+    let reader = ImageReader::open(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    let image = reader.decode().map_err(|source| Error::Image { path: path.to_string(), source })?;
+    Ok(image.to_rgb8())
+}
+
+/// 切り抜きのマスクを登録する名前. 同じ画像を色として使っても別に持つ
+pub fn alpha_key(file: &str) -> String {
+    format!("{}#alpha", file)
+}
+
+/// 画像にアルファがあればそれを, なければ明るさを灰色の画像にする
+fn load_mask(path: &str) -> Result<RgbImage> {
+    let reader = ImageReader::open(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    let image = reader.decode().map_err(|source| Error::Image { path: path.to_string(), source })?;
+    Ok(if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| Rgb([rgba.get_pixel(x, y)[3]; 3]))
+    } else {
+        let luma = image.to_luma8();
+        RgbImage::from_fn(luma.width(), luma.height(), |x, y| Rgb([luma.get_pixel(x, y)[0]; 3]))
+    })
+}
+
+/// `TextureCache`に登録したテクスチャの番号
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TextureId(usize);
+
+/// シーン全体で共有するテクスチャ. 同じ名前のファイルは一度だけ読む
+#[derive(Debug, Default)]
+pub struct TextureCache {
+    ids: HashMap<String, TextureId>,
+    images: Vec<MipMap>,
+}
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache::default()
+    }
+    /// 同じ名前があれば画像を置き換える. ミップマップはここで作る
+    pub fn insert(&mut self, name: String, image: RgbImage) -> TextureId {
+        let image = MipMap::new(image);
+        match self.ids.get(&name) {
+            Some(&id) => {
+                self.images[id.0] = image;
+                id
+            },
+            None => {
+                let id = TextureId(self.images.len());
+                self.images.push(image);
+                self.ids.insert(name, id);
+                id
+            },
+        }
+    }
+    pub fn id(&self, name: &str) -> Option<TextureId> {
+        self.ids.get(name).copied()
+    }
+    pub fn get(&self, id: TextureId) -> Option<&RgbImage> {
+        self.images.get(id.0).map(|mipmap| mipmap.image())
+    }
+    pub fn mipmap(&self, id: TextureId) -> Option<&MipMap> {
+        self.images.get(id.0)
+    }
+    /// u, v方向のはみ出したUVの扱いを変える
+    pub fn set_wrap(&mut self, id: TextureId, wrap: [WrapMode; 2]) {
+        if let Some(mipmap) = self.images.get_mut(id.0) {
+            mipmap.wrap = wrap;
+        }
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.ids.contains_key(name)
+    }
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+    /// 材質が使うテクスチャのうちまだないものをファイルから読む
+    pub fn load_materials(&mut self, materials: &[tobj::Material], policy: &LoadPolicy) -> Result<()> {
+        for material in materials.iter() {
+            for texture in texture_names(material) {
+                let spec = TextureSpec::parse(texture);
+                if spec.file.is_empty() {
+                    continue;
+                }
+                let id = match self.id(&spec.file) {
+                    Some(id) => id,
+                    None => {
+                        let image = match load_texture(&spec.file) {
+                            Ok(image) => image,
+                            Err(e) if policy.substitute_textures => {
+                                warn(&format!("{}, using a checker texture", e));
+                                missing_texture()
+                            },
+                            Err(e) => return Err(e),
+                        };
+                        self.insert(spec.file.clone(), image)
+                    },
+                };
+                if let Some(wrap) = spec.wrap() {
+                    self.set_wrap(id, [wrap; 2]);
+                }
+            }
+            let spec = TextureSpec::parse(&material.dissolve_texture);
+            if spec.file.is_empty() {
+                continue;
+            }
+            let id = match self.id(&alpha_key(&spec.file)) {
+                Some(id) => id,
+                None => {
+                    let image = match load_mask(&spec.file) {
+                        Ok(image) => image,
+                        // 読めなければ切り抜かない
+                        Err(e) if policy.substitute_textures => {
+                            warn(&format!("{}, ignoring the cutout", e));
+                            RgbImage::from_pixel(1, 1, Rgb([255; 3]))
+                        },
+                        Err(e) => return Err(e),
+                    };
+                    self.insert(alpha_key(&spec.file), image)
+                },
+            };
+            if let Some(wrap) = spec.wrap() {
+                self.set_wrap(id, [wrap; 2]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ファイルの探し方, 読めないときの扱い, 読み込んだテクスチャ
+#[derive(Debug, Default)]
+pub struct Assets {
+    pub search_paths: SearchPaths,
+    pub policy: LoadPolicy,
+    pub textures: TextureCache,
+}
+impl Assets {
+    pub fn new(search_paths: SearchPaths, policy: LoadPolicy) -> Assets {
+        Assets {
+            search_paths,
+            policy,
+            textures: TextureCache::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texture_options_are_split_from_file_name() {
+        let spec = TextureSpec::parse("-bm 0.5 -clamp on -o 0.1 0.2 my bricks.png");
+        assert_eq!(spec.file, "my bricks.png");
+        assert_eq!(spec.numbers("-bm"), Some(vec![0.5]));
+        assert_eq!(spec.numbers("-o"), Some(vec![0.1, 0.2]));
+        assert_eq!(spec.numbers("-clamp"), None);
+        assert_eq!(spec.to_string(), "-bm 0.5 -clamp on -o 0.1 0.2 my bricks.png");
+        assert_eq!(TextureSpec::parse("plain.png").to_string(), "plain.png");
+        assert_eq!(spec.wrap(), Some(WrapMode::Clamp));
+        assert_eq!(TextureSpec::parse("-wrap mirror -clamp on tile.png").wrap(), Some(WrapMode::Mirror));
+        assert_eq!(TextureSpec::parse("tile.png").wrap(), None);
+    }
+}
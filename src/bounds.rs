@@ -0,0 +1,115 @@
+use crate::vector::Vector3;
+use crate::ray::Ray;
+
+/// 軸平行境界箱 (AABB)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds3 {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+#[allow(dead_code)]
+impl Bounds3 {
+    pub fn new(min: Vector3, max: Vector3) -> Bounds3 {
+        Bounds3 {
+            min,
+            max,
+        }
+    }
+    pub fn empty() -> Bounds3 {
+        Bounds3 {
+            min: Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+    pub fn from_points(points: &[Vector3]) -> Bounds3 {
+        points.iter().fold(Bounds3::empty(), |acc, p| acc.union_point(p))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.max.x < self.min.x || self.max.y < self.min.y || self.max.z < self.min.z
+    }
+    pub fn is_finite(&self) -> bool {
+        self.min.x.is_finite() && self.min.y.is_finite() && self.min.z.is_finite()
+            && self.max.x.is_finite() && self.max.y.is_finite() && self.max.z.is_finite()
+    }
+    pub fn union(&self, other: &Bounds3) -> Bounds3 {
+        Bounds3 {
+            min: Vector3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vector3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+    pub fn union_point(&self, p: &Vector3) -> Bounds3 {
+        Bounds3 {
+            min: Vector3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z)),
+            max: Vector3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z)),
+        }
+    }
+    pub fn centroid(&self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+    pub fn diagonal(&self) -> Vector3 {
+        self.max - self.min
+    }
+    pub fn max_extent(&self) -> usize {
+        let d = self.diagonal();
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let d = self.diagonal();
+        2. * (d.x*d.y + d.y*d.z + d.z*d.x)
+    }
+    /// スラブ法による交差判定. 箱に入る距離と出る距離を返す
+    pub fn intersection(&self, ray: &Ray, t_max: f32) -> Option<(f32, f32)> {
+        let inv_dir = Vector3::new(1. / ray.direction.x, 1. / ray.direction.y, 1. / ray.direction.z);
+        self.intersection_inv(&ray.origin, &inv_dir, t_max)
+    }
+    /// 方向の逆数を事前に計算してある場合の`intersection`
+    pub fn intersection_inv(&self, origin: &Vector3, inv_dir: &Vector3, t_max: f32) -> Option<(f32, f32)> {
+        let t1 = (self.min - *origin) * *inv_dir;
+        let t2 = (self.max - *origin) * *inv_dir;
+        let t_near = t1.x.min(t2.x).max(t1.y.min(t2.y)).max(t1.z.min(t2.z)).max(0.);
+        let t_far = t1.x.max(t2.x).min(t1.y.max(t2.y)).min(t1.z.max(t2.z)).min(t_max);
+        if t_near <= t_far {
+            Some((t_near, t_far))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slab_test() {
+        let b = Bounds3::new(Vector3::new(-1., -1., -1.), Vector3::new(1., 1., 1.));
+        let ray = Ray::new(Vector3::new(-5., 0., 0.), Vector3::new(1., 0., 0.));
+        assert_eq!(b.intersection(&ray, f32::INFINITY), Some((4., 6.)));
+        assert_eq!(b.intersection(&ray, 3.), None);
+        let ray = Ray::new(Vector3::new(-5., 2., 0.), Vector3::new(1., 0., 0.));
+        assert_eq!(b.intersection(&ray, f32::INFINITY), None);
+        let ray = Ray::new(Vector3::new(0., 0., 0.), Vector3::new(0., 0., -1.));
+        assert_eq!(b.intersection(&ray, f32::INFINITY), Some((0., 1.)));
+    }
+
+    #[test]
+    fn union_and_area() {
+        let a = Bounds3::from_points(&[Vector3::new(0., 0., 0.), Vector3::new(1., 1., 1.)]);
+        let b = Bounds3::from_points(&[Vector3::new(2., 0., 0.)]);
+        let u = a.union(&b);
+        assert_eq!(u.max, Vector3::new(2., 1., 1.));
+        assert_eq!(u.centroid(), Vector3::new(1., 0.5, 0.5));
+        assert_eq!(u.surface_area(), 10.);
+        assert_eq!(u.max_extent(), 0);
+        assert_eq!(Bounds3::empty().surface_area(), 0.);
+    }
+}
@@ -0,0 +1,354 @@
+use std::f32::consts::PI;
+
+use crate::vector::{Vector3, Vector2};
+use crate::color::Color;
+use crate::microfacet::{Ggx, Conductor, schlick, fresnel_dielectric};
+
+/// BSDFから選んだ方向
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BsdfSample {
+    /// 局所座標の入射方向
+    pub wi: Vector3,
+    /// BSDF cosθi / pdf
+    pub weight: Color,
+    /// 屈折や鏡面反射のように光源サンプリングで数えない方向
+    pub delta: bool,
+}
+
+/// Disneyのprincipled BSDF. 拡散, シーン, 鏡面, クリアコート, 透過の層を重ねる.
+/// ベクトルは法線をz軸とする局所座標で, 裏側から見たときは法線を反転して扱う.
+/// 透過の層は誘電体で, 裏側から当たったときは物体の内側にいるとみなす
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Principled {
+    /// 0-1
+    pub base_color: Color,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub specular: f32,
+    pub sheen: f32,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub anisotropy: f32,
+    /// 局所座標のx軸から測った異方性の向き(0-1で1周)
+    pub anisotropy_rotation: f32,
+    pub transmission: f32,
+    pub ior: f32,
+    /// 金属の部分の複素屈折率. なければ`base_color`を垂直入射の反射率とする
+    pub conductor: Option<Conductor>,
+}
+
+fn luminance(c: Color) -> f32 {
+    0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+}
+
+fn mix(a: Color, b: Color, t: f32) -> Color {
+    a * (1. - t) + b * t
+}
+
+/// 法線をz軸に向けて, 裏側の方向を表側に折り返す
+fn upper(w: &Vector3) -> Vector3 {
+    Vector3::new(w.x, w.y, w.z.abs())
+}
+
+impl Principled {
+    fn specular_ggx(&self) -> Ggx {
+        Ggx::anisotropic(self.roughness.max(0.), self.anisotropy)
+    }
+    fn clearcoat_ggx(&self) -> Ggx {
+        Ggx::from_roughness(self.clearcoat_roughness.max(0.))
+    }
+    /// 異方性の向きに局所座標を回す
+    fn rotate(&self, w: &Vector3, sign: f32) -> Vector3 {
+        if self.anisotropy_rotation == 0. {
+            return *w;
+        }
+        let (sin, cos) = (sign * 2. * PI * self.anisotropy_rotation).sin_cos();
+        Vector3::new(cos * w.x + sin * w.y, -sin * w.x + cos * w.y, w.z)
+    }
+    /// 鏡面反射のフレネル反射率
+    fn fresnel(&self, cos: f32) -> Color {
+        match self.conductor {
+            Some(conductor) => mix(schlick(Color::ones() * (0.08 * self.specular), cos), conductor.fresnel(cos), self.metallic),
+            None => schlick(mix(Color::ones() * (0.08 * self.specular), self.base_color, self.metallic), cos),
+        }
+    }
+    fn diffuse_weight(&self) -> f32 {
+        (1. - self.metallic) * (1. - self.transmission)
+    }
+    /// 誘電体の層の割合. その部分の鏡面反射も誘電体の層が受け持つ
+    fn transmission_weight(&self) -> f32 {
+        (1. - self.metallic) * self.transmission
+    }
+    /// 拡散, 鏡面, クリアコート, 透過を選ぶ確率
+    fn lobe_probabilities(&self, wo: &Vector3) -> [f32; 4] {
+        let weights = [
+            self.diffuse_weight() * (luminance(self.base_color) + self.sheen),
+            (1. - self.transmission_weight()) * luminance(self.fresnel(wo.z)),
+            self.clearcoat * schlick(Color::ones() * 0.04, wo.z).r,
+            self.transmission_weight(),
+        ];
+        let total: f32 = weights.iter().sum();
+        if total <= 0. {
+            return [0.; 4];
+        }
+        weights.map(|w| w / total)
+    }
+    /// 反射のBSDF (cosθiを含まない). 誘電体の層は光源サンプリングしないので含まない
+    pub fn eval(&self, wo: &Vector3, wi: &Vector3) -> Color {
+        // 反射は同じ側の方向どうしのときだけ
+        if wo.z * wi.z <= 0. {
+            return Color::zeros();
+        }
+        let (wo, wi) = (upper(wo), upper(wi));
+        let h = (wo + wi).normalize();
+        let cos_d = wi.inner(&h);
+        // Burleyの拡散とシーン
+        let fd90 = 0.5 + 2. * self.roughness * cos_d * cos_d;
+        let fl = (1. - wi.z).powi(5);
+        let fv = (1. - wo.z).powi(5);
+        let diffuse = self.base_color / PI * (1. + (fd90 - 1.) * fl) * (1. + (fd90 - 1.) * fv);
+        let sheen = Color::ones() * (self.sheen * (1. - cos_d).powi(5));
+        let specular = self.fresnel(cos_d) * (self.specular_ggx().eval(&self.rotate(&wo, 1.), &self.rotate(&wi, 1.)) * (1. - self.transmission_weight()));
+        let clearcoat = self.clearcoat * schlick(Color::ones() * 0.04, cos_d).r * self.clearcoat_ggx().eval(&wo, &wi);
+        (diffuse + sheen) * self.diffuse_weight() + specular + clearcoat
+    }
+    /// `sample`で反射の方向`wi`を選ぶ確率密度
+    pub fn pdf(&self, wo: &Vector3, wi: &Vector3) -> f32 {
+        if wo.z * wi.z <= 0. {
+            return 0.;
+        }
+        let (wo, wi) = (upper(wo), upper(wi));
+        let [p_diffuse, p_specular, p_clearcoat, _] = self.lobe_probabilities(&wo);
+        p_diffuse * wi.z / PI
+            + p_specular * self.specular_ggx().pdf(&self.rotate(&wo, 1.), &self.rotate(&wi, 1.))
+            + p_clearcoat * self.clearcoat_ggx().pdf(&wo, &wi)
+    }
+    /// 誘電体の層で, フレネル反射率に従って`u_fresnel`で反射か屈折を選ぶ. 粗い面では見える微小面を選んでから決める
+    pub fn sample_dielectric(&self, wo: &Vector3, u_fresnel: f32, u: &Vector2) -> Option<BsdfSample> {
+        let weight = self.transmission_weight();
+        if weight <= 0. || wo.z == 0. {
+            return None;
+        }
+        let side = wo.z.signum();
+        let wo_up = upper(wo);
+        // 入るときは1/ior, 出るときはiorが相対屈折率になる
+        let eta = if 0. < side { 1. / self.ior } else { self.ior };
+        let ggx = Ggx::from_roughness(self.roughness.max(0.));
+        let h = if ggx.is_smooth() {
+            Vector3::new(0., 0., 1.)
+        } else {
+            ggx.sample_vndf(&wo_up, u)
+        };
+        let cos_h = wo_up.inner(&h);
+        if cos_h <= 0. {
+            return None;
+        }
+        // 全反射ならフレネル反射率が1なので必ず反射する
+        let reflect = u_fresnel < fresnel_dielectric(cos_h, eta);
+        let (wi, tint) = if reflect {
+            (h * (2. * cos_h) - wo_up, Color::ones())
+        } else {
+            let tint = Color::new(self.base_color.r.sqrt(), self.base_color.g.sqrt(), self.base_color.b.sqrt());
+            (refract(&wo_up, &h, eta)?, tint)
+        };
+        // 粗い面で反対側に抜けた方向は捨てる
+        if reflect != (0. < wi.z) {
+            return None;
+        }
+        // フレネル反射率は選ぶ確率と打ち消しあう
+        let shadowing = if ggx.is_smooth() { 1. } else { ggx.g2(&wo_up, &wi) / ggx.g1(&wo_up) };
+        Some(BsdfSample {
+            wi: Vector3::new(wi.x, wi.y, wi.z * side),
+            weight: tint * (weight * shadowing),
+            delta: true,
+        })
+    }
+    /// 層を1つ選んで方向を選ぶ. `u_lobe`は層の選択, `u`は方向に使う
+    pub fn sample(&self, wo: &Vector3, u_lobe: f32, u: &Vector2) -> Option<BsdfSample> {
+        if wo.z == 0. {
+            return None;
+        }
+        let side = wo.z.signum();
+        let wo_up = upper(wo);
+        let [p_diffuse, p_specular, p_clearcoat, p_transmission] = self.lobe_probabilities(&wo_up);
+        let p_reflection = p_diffuse + p_specular + p_clearcoat;
+        if p_reflection <= u_lobe {
+            if p_transmission <= 0. {
+                return None;
+            }
+            let u_fresnel = ((u_lobe - p_reflection) / p_transmission).min(1.);
+            let sample = self.sample_dielectric(wo, u_fresnel, u)?;
+            return Some(BsdfSample {
+                weight: sample.weight / p_transmission,
+                ..sample
+            });
+        }
+        let wi = if u_lobe < p_diffuse {
+            // 余弦に比例させる
+            let r = u.x.sqrt();
+            let phi = 2. * PI * u.y;
+            Vector3::new(r * phi.cos(), r * phi.sin(), (1. - u.x).max(0.).sqrt())
+        } else if u_lobe < p_diffuse + p_specular {
+            let (wi, _, _) = self.specular_ggx().sample(&self.rotate(&wo_up, 1.), u)?;
+            self.rotate(&wi, -1.)
+        } else {
+            let (wi, _, _) = self.clearcoat_ggx().sample(&wo_up, u)?;
+            wi
+        };
+        let pdf = self.pdf(&wo_up, &wi);
+        if wi.z <= 0. || pdf <= 0. {
+            return None;
+        }
+        Some(BsdfSample {
+            wi: Vector3::new(wi.x, wi.y, wi.z * side),
+            weight: self.eval(&wo_up, &wi) * (wi.z / pdf),
+            delta: false,
+        })
+    }
+}
+
+/// 法線`n`側の`wo`を相対屈折率`eta`で屈折させた方向. 全反射ならNone
+pub fn refract(wo: &Vector3, n: &Vector3, eta: f32) -> Option<Vector3> {
+    let cos_o = wo.inner(n);
+    let sin2_t = eta * eta * (1. - cos_o * cos_o).max(0.);
+    if 1. <= sin2_t {
+        return None;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    Some(*wo * -eta + *n * (eta * cos_o - cos_t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler;
+
+    fn material() -> Principled {
+        Principled {
+            base_color: Color::new(0.8, 0.5, 0.2),
+            metallic: 0.3,
+            roughness: 0.4,
+            specular: 0.5,
+            sheen: 0.5,
+            clearcoat: 0.7,
+            clearcoat_roughness: 0.2,
+            anisotropy: 0.5,
+            anisotropy_rotation: 0.1,
+            transmission: 0.,
+            ior: 1.5,
+            conductor: None,
+        }
+    }
+
+    #[test]
+    fn sampling_weights_match_eval_and_pdf() {
+        let bsdf = material();
+        let wo = Vector3::new(0.3, -0.2, 0.9).normalize();
+        sampler::scoped(Some(5), || {
+            for _ in 0..1000 {
+                let u = Vector2::new(sampler::uniform(), sampler::uniform());
+                if let Some(s) = bsdf.sample(&wo, sampler::uniform(), &u) {
+                    let expected = bsdf.eval(&wo, &s.wi) * (s.wi.z / bsdf.pdf(&wo, &s.wi));
+                    assert!((s.weight.r - expected.r).abs() < 1e-3 * expected.r.max(1.));
+                    assert!(0. < s.wi.z && !s.delta);
+                }
+            }
+        });
+        // 裏側からも同じように反射する
+        let back = Vector3::new(wo.x, wo.y, -wo.z);
+        let wi = Vector3::new(-0.1, 0.4, 0.6).normalize();
+        assert_eq!(bsdf.eval(&wo, &wi), bsdf.eval(&back, &Vector3::new(wi.x, wi.y, -wi.z)));
+    }
+
+    #[test]
+    fn white_furnace_albedo_is_bounded() {
+        let bsdf = Principled {
+            base_color: Color::ones(),
+            metallic: 0.,
+            sheen: 0.,
+            clearcoat: 0.,
+            anisotropy: 0.,
+            ..material()
+        };
+        for &cos in [0.9f32, 0.5, 0.1].iter() {
+            let wo = Vector3::new((1. - cos * cos).sqrt(), 0., cos);
+            let n = 20000;
+            let albedo = sampler::scoped(Some(9), || (0..n).map(|_| {
+                let u = Vector2::new(sampler::uniform(), sampler::uniform());
+                bsdf.sample(&wo, sampler::uniform(), &u).map_or(0., |s| luminance(s.weight))
+            }).sum::<f32>()) / n as f32;
+            // Burleyの拡散は浅い角度で少し1を超える
+            assert!(0.5 < albedo && albedo < 1.25, "{} {}", cos, albedo);
+        }
+    }
+
+    #[test]
+    fn transmission_refracts() {
+        let bsdf = Principled {
+            base_color: Color::ones(),
+            metallic: 0.,
+            roughness: 0.,
+            transmission: 1.,
+            ..material()
+        };
+        let wo = Vector3::new(0.6, 0., 0.8);
+        let s = bsdf.sample(&wo, 0.999, &Vector2::new(0.5, 0.5)).unwrap();
+        assert!(s.delta && s.wi.z < 0.);
+        // スネルの法則 sinθo = 1.5 sinθi
+        assert!((0.6 - 1.5 * (1. - s.wi.z * s.wi.z).sqrt()).abs() < 1e-5);
+        // 内側から浅い角度で当たると全反射する
+        let inside = Vector3::new(0.8, 0., -0.6);
+        assert_eq!(refract(&Vector3::new(0.8, 0., 0.6), &Vector3::new(0., 0., 1.), 1.5), None);
+        let s = bsdf.sample_dielectric(&inside, 0.999, &Vector2::new(0.5, 0.5)).unwrap();
+        assert_eq!(s.wi, Vector3::new(-0.8, 0., -0.6));
+    }
+
+    #[test]
+    fn dielectric_reflects_by_fresnel() {
+        let smooth = Principled {
+            base_color: Color::ones(),
+            metallic: 0.,
+            roughness: 0.,
+            transmission: 1.,
+            clearcoat: 0.,
+            ..material()
+        };
+        let wo = Vector3::new(0.8, 0., 0.6);
+        let n = 20000;
+        let reflected = sampler::scoped(Some(4), || (0..n).filter(|_| {
+            let s = smooth.sample(&wo, sampler::uniform(), &Vector2::new(0.5, 0.5)).unwrap();
+            // 滑らかな白いガラスはエネルギーを保つ
+            assert_eq!(s.weight, Color::ones());
+            0. < s.wi.z
+        }).count()) as f32 / n as f32;
+        assert!((reflected - fresnel_dielectric(0.6, 1. / 1.5)).abs() < 1e-2, "{}", reflected);
+        // 粗いガラスは微小面の遮蔽で少し暗くなる
+        let rough = Principled { roughness: 0.5, ..smooth };
+        let albedo = sampler::scoped(Some(6), || (0..n).map(|_| {
+            let u = Vector2::new(sampler::uniform(), sampler::uniform());
+            rough.sample(&wo, sampler::uniform(), &u).map_or(0., |s| s.weight.r)
+        }).sum::<f32>()) / n as f32;
+        assert!(0.8 < albedo && albedo <= 1., "{}", albedo);
+    }
+
+    #[test]
+    fn conductor_tints_reflection() {
+        let gold = Principled {
+            base_color: Color::ones(),
+            metallic: 1.,
+            roughness: 0.3,
+            sheen: 0.,
+            clearcoat: 0.,
+            anisotropy: 0.,
+            conductor: Conductor::preset("gold"),
+            ..material()
+        };
+        let wo = Vector3::new(0., 0., 1.);
+        let n = 20000;
+        let albedo = sampler::scoped(Some(2), || (0..n).fold(Color::zeros(), |acc, _| {
+            let u = Vector2::new(sampler::uniform(), sampler::uniform());
+            acc + gold.sample(&wo, sampler::uniform(), &u).map_or(Color::zeros(), |s| s.weight)
+        })) / n as f32;
+        assert!(albedo.b < albedo.r && albedo.r < 1., "{:?}", albedo);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::vector::Vector3;
+use crate::ray::Ray;
+use crate::bounds::Bounds3;
+
+const NUM_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const MAX_PRIMS_IN_LEAF: usize = 16;
+const TRAVERSAL_COST: f32 = 0.125;
+
+fn axis(v: &Vector3, axis: usize) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+#[derive(Debug)]
+struct BvhNode {
+    bounds: Bounds3,
+    // 葉ならprimitiveの開始位置, 節なら右の子の位置 (左の子は直後に並ぶ)
+    offset: usize,
+    count: usize,
+    axis: usize,
+}
+
+/// SAHで構築したBVH. `indices`はprimitiveの並び替え後の番号
+#[derive(Debug)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+impl Bvh {
+    pub fn build(bounds: &[Bounds3]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            let centroids = bounds.iter().map(|b| b.centroid()).collect::<Vec<_>>();
+            bvh.build_recursive(bounds, &centroids, 0, bounds.len());
+        }
+        bvh
+    }
+    pub fn bounds(&self) -> Bounds3 {
+        match self.nodes.first() {
+            Some(node) => node.bounds,
+            None => Bounds3::empty(),
+        }
+    }
+    fn build_recursive(&mut self, bounds: &[Bounds3], centroids: &[Vector3], start: usize, end: usize) -> usize {
+        let node_index = self.nodes.len();
+        let node_bounds = self.indices[start..end].iter().fold(Bounds3::empty(), |acc, &i| acc.union(&bounds[i]));
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            offset: start,
+            count: end - start,
+            axis: 0,
+        });
+        let count = end - start;
+        if count <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+
+        let centroid_bounds = Bounds3::from_points(&self.indices[start..end].iter().map(|&i| centroids[i]).collect::<Vec<_>>());
+        let extent = centroid_bounds.diagonal();
+        let split_axis = centroid_bounds.max_extent();
+        let c_min = axis(&centroid_bounds.min, split_axis);
+        let c_extent = axis(&extent, split_axis);
+        if c_extent <= f32::EPSILON {
+            return node_index;
+        }
+
+        let bin_of = |c: &Vector3| -> usize {
+            let b = ((axis(c, split_axis) - c_min) / c_extent * NUM_BINS as f32) as usize;
+            b.min(NUM_BINS - 1)
+        };
+        let mut bin_bounds = [Bounds3::empty(); NUM_BINS];
+        let mut bin_counts = [0usize; NUM_BINS];
+        for &i in self.indices[start..end].iter() {
+            let b = bin_of(&centroids[i]);
+            bin_counts[b] += 1;
+            bin_bounds[b] = bin_bounds[b].union(&bounds[i]);
+        }
+
+        // 左右から累積してbinの境界ごとのSAHコストを求める
+        let mut costs = [0f32; NUM_BINS - 1];
+        let mut left_bounds = Bounds3::empty();
+        let mut left_count = 0;
+        for i in 0..NUM_BINS - 1 {
+            left_bounds = left_bounds.union(&bin_bounds[i]);
+            left_count += bin_counts[i];
+            costs[i] = left_count as f32 * left_bounds.surface_area();
+        }
+        let mut right_bounds = Bounds3::empty();
+        let mut right_count = 0;
+        for i in (1..NUM_BINS).rev() {
+            right_bounds = right_bounds.union(&bin_bounds[i]);
+            right_count += bin_counts[i];
+            costs[i - 1] += right_count as f32 * right_bounds.surface_area();
+        }
+        let (best_bin, best_cost) = costs.iter().enumerate().fold((0, f32::INFINITY), |acc, (i, &c)| {
+            if c < acc.1 { (i, c) } else { acc }
+        });
+        let best_cost = TRAVERSAL_COST + best_cost / node_bounds.surface_area().max(f32::EPSILON);
+        if count <= MAX_PRIMS_IN_LEAF && count as f32 <= best_cost {
+            return node_index;
+        }
+
+        let mut mid = start;
+        for i in start..end {
+            if bin_of(&centroids[self.indices[i]]) <= best_bin {
+                self.indices.swap(i, mid);
+                mid += 1;
+            }
+        }
+        if mid == start || mid == end {
+            mid = (start + end) / 2;
+            self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+                axis(&centroids[a], split_axis).partial_cmp(&axis(&centroids[b], split_axis)).unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+
+        self.build_recursive(bounds, centroids, start, mid);
+        let right = self.build_recursive(bounds, centroids, mid, end);
+        let node = &mut self.nodes[node_index];
+        node.offset = right;
+        node.count = 0;
+        node.axis = split_axis;
+        node_index
+    }
+    /// 手前のノードから順に辿り, `hit`にprimitiveの番号と現在の最短距離を渡す.
+    /// `hit`がより近い交点の距離を返すと, それより遠いノードは打ち切られる
+    pub fn traverse<F>(&self, ray: &Ray, t_max: f32, mut hit: F)
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = Vector3::new(1. / ray.direction.x, 1. / ray.direction.y, 1. / ray.direction.z);
+        let dir_is_neg = [inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.];
+        let mut t_max = t_max;
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.bounds.intersection_inv(&ray.origin, &inv_dir, t_max).is_none() {
+                continue;
+            }
+            if 0 < node.count {
+                for &i in self.indices[node.offset..node.offset + node.count].iter() {
+                    if let Some(t) = hit(i, t_max) {
+                        if t < t_max {
+                            t_max = t;
+                        }
+                    }
+                }
+            } else if dir_is_neg[node.axis] {
+                stack.push(node_index + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(node_index + 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Plane;
+    use crate::shape::Shape;
+    use rand::Rng;
+
+    fn random_planes(n: usize) -> Vec<Plane> {
+        let mut rng = rand::thread_rng();
+        (0..n).map(|_| {
+            let c = Vector3::new(rng.gen_range(-10., 10.), rng.gen_range(-10., 10.), rng.gen_range(-10., 10.));
+            let v1 = c + Vector3::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.), rng.gen_range(-1., 1.));
+            let v2 = c + Vector3::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.), rng.gen_range(-1., 1.));
+            let v3 = c + Vector3::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.), rng.gen_range(-1., 1.));
+            Plane::new(v1, v2, v3, None, None, None)
+        }).collect()
+    }
+
+    #[test]
+    fn nearest_hit_matches_brute_force() {
+        let planes = random_planes(500);
+        let bounds = planes.iter().map(|p| p.bounds()).collect::<Vec<_>>();
+        let bvh = Bvh::build(&bounds);
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let origin = Vector3::new(rng.gen_range(-15., 15.), rng.gen_range(-15., 15.), rng.gen_range(-15., 15.));
+            let direction = Vector3::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.), rng.gen_range(-1., 1.));
+            let ray = Ray::new(origin, direction);
+            let expected = planes.iter().enumerate()
+                .filter_map(|(i, p)| p.intersection(&ray).map(|v| (i, v.x)))
+                .fold(None, |acc: Option<(usize, f32)>, (i, t)| match acc {
+                    Some((_, best)) if best <= t => acc,
+                    _ => Some((i, t)),
+                });
+            let mut found: Option<(usize, f32)> = None;
+            bvh.traverse(&ray, f32::INFINITY, |i, t_max| {
+                let t = planes[i].intersection(&ray)?.x;
+                if t < t_max {
+                    found = Some((i, t));
+                    Some(t)
+                } else {
+                    None
+                }
+            });
+            assert_eq!(expected.map(|e| e.0), found.map(|f| f.0));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+pub const USAGE: &str = "\
+Usage: render [OPTIONS] [SCENE] [KEY=VALUE]...
+
+Renders SCENE (default: ./scene.json) to a PNG image.
+
+Options:
+  -o, --output <FILE>        output image (default: save.png)
+  -r, --resolution <WxH>     image size, e.g. 1280x720
+  -s, --samples <N>          samples per bounce (camera.sampling)
+  -b, --bounces <N>          bounce depth (camera.num_of_bounce)
+  -j, --threads <N>          number of render threads (default: all cores)
+      --seed <N>             seed for a reproducible image
+  -I, --search-path <DIR>    also look for meshes, MTL files and textures in DIR
+      --allow-missing        replace unreadable textures and MTL files with
+                             placeholders instead of failing
+  -h, --help                 print this help
+
+KEY=VALUE overrides a scene setting by its JSON path, e.g.
+  camera.fov=60  objectlist.0.translate=[0,1,0]
+VALUE is parsed as JSON, or taken as a string otherwise.";
+
+/// コマンドライン引数
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub scene: String,
+    pub output: String,
+    pub threads: Option<usize>,
+    pub seed: Option<u64>,
+    /// 読めないテクスチャや材質を置き換えて続ける
+    pub allow_missing: bool,
+    /// 追加の探索ディレクトリ
+    pub search_paths: Vec<String>,
+    /// シーン設定の上書き. オプションで指定した解像度なども含む
+    pub overrides: Vec<(String, String)>,
+    pub help: bool,
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("invalid value `{}` for {}", value, flag))
+}
+
+/// 引数を解釈する. 先頭のプログラム名は含めない
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut parsed = Args {
+        scene: String::from("./scene.json"),
+        output: String::from("save.png"),
+        threads: None,
+        seed: None,
+        allow_missing: false,
+        search_paths: Vec::new(),
+        overrides: Vec::new(),
+        help: false,
+    };
+    let mut scene = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // --flag=value の形も受け付ける
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with('-') => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| format!("{} needs a value", flag));
+        match flag.as_str() {
+            "-h" | "--help" => parsed.help = true,
+            "-o" | "--output" => parsed.output = value()?,
+            "-r" | "--resolution" => {
+                let v = value()?;
+                let (w, h) = v.split_once('x').ok_or_else(|| format!("invalid value `{}` for {}, expected WxH", v, flag))?;
+                let (w, h) = (parse_number::<u32>(&flag, w)?, parse_number::<u32>(&flag, h)?);
+                parsed.overrides.push((String::from("camera.image_size"), format!("[{}, {}]", w, h)));
+            },
+            "-s" | "--samples" => {
+                let n = parse_number::<usize>(&flag, &value()?)?;
+                parsed.overrides.push((String::from("camera.sampling"), n.to_string()));
+            },
+            "-b" | "--bounces" => {
+                let n = parse_number::<usize>(&flag, &value()?)?;
+                parsed.overrides.push((String::from("camera.num_of_bounce"), n.to_string()));
+            },
+            "-j" | "--threads" => parsed.threads = Some(parse_number(&flag, &value()?)?),
+            "--seed" => parsed.seed = Some(parse_number(&flag, &value()?)?),
+            "--allow-missing" => parsed.allow_missing = true,
+            "-I" | "--search-path" => parsed.search_paths.push(value()?),
+            f if f.starts_with('-') && 1 < f.len() => return Err(format!("unknown option {}", f)),
+            _ => match arg.split_once('=') {
+                Some((key, value)) => parsed.overrides.push((key.to_string(), value.to_string())),
+                None if scene.is_none() => scene = Some(arg),
+                None => return Err(format!("unexpected argument {}", arg)),
+            },
+        }
+    }
+    if let Some(scene) = scene {
+        parsed.scene = scene;
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<Args, String> {
+        parse(s.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn options_and_overrides() {
+        let a = args("room.json -o out.png -r 64x32 --samples=8 -j 2 --seed 42 camera.fov=60").unwrap();
+        assert_eq!(a.scene, "room.json");
+        assert_eq!(a.output, "out.png");
+        assert_eq!(a.threads, Some(2));
+        assert_eq!(a.seed, Some(42));
+        assert_eq!(a.overrides, vec![
+            (String::from("camera.image_size"), String::from("[64, 32]")),
+            (String::from("camera.sampling"), String::from("8")),
+            (String::from("camera.fov"), String::from("60")),
+        ]);
+        assert_eq!(args("").unwrap().scene, "./scene.json");
+        assert!(args("--help").unwrap().help);
+        assert!(args("--allow-missing").unwrap().allow_missing);
+        assert_eq!(args("-I a --search-path=b").unwrap().search_paths, vec!["a", "b"]);
+        assert!(args("-r 64").is_err());
+        assert!(args("--bogus").is_err());
+        assert!(args("-o").is_err());
+        assert!(args("a.json b.json").is_err());
+    }
+}
@@ -23,6 +23,7 @@ impl Color {
             b: pixel[2] as f32,
         }
     }
+    #[allow(clippy::ptr_arg)]
     pub fn from_vector(v: &Vec<f32>) -> Color {
         assert_eq!(v.len(),3);
         Color {
@@ -39,6 +40,7 @@ impl Color {
             b: l[2],
         }
     }
+    #[allow(clippy::manual_clamp)]
     pub fn reform(&self) -> Color {
         let r = if 255. < self.r {
             255.
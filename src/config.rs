@@ -0,0 +1,648 @@
+use serde::{Deserialize, Deserializer};
+use serde::de::{Error as _, Visitor, SeqAccess, IgnoredAny};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use serde_json::Value;
+
+use crate::vector::Vector3;
+use crate::scene::RenderSettings;
+use crate::color::Color;
+use crate::microfacet::Conductor;
+use crate::material::{NormalMapFormat, AlphaTest};
+use crate::texture::TextureFilter;
+
+/// scene.jsonの内容
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneConfig {
+    #[serde(default)]
+    pub camera: CameraConfig,
+    #[serde(default)]
+    pub objectlist: Vec<ObjectConfig>,
+    #[serde(default)]
+    pub shapes: Vec<ShapeConfig>,
+    /// メッシュ, MTL, テクスチャを探すディレクトリ. 相対パスはscene.jsonのディレクトリから
+    #[serde(default)]
+    pub search_paths: Vec<String>,
+    /// 材質名ごとの上書き
+    #[serde(default)]
+    pub materials: HashMap<String, MaterialConfig>,
+}
+
+/// MTLなどから読んだ材質を上書きする値
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialConfig {
+    /// 金属のプリセット名か, 複素屈折率 {"eta": [r, g, b], "k": [r, g, b]}
+    #[serde(default, deserialize_with = "conductor")]
+    pub conductor: Option<Conductor>,
+    pub roughness: Option<f32>,
+    pub metallic: Option<f32>,
+    /// 法線マップの緑チャンネルの向き ("opengl"か"directx")
+    pub normal_format: Option<NormalMapFormat>,
+    /// バンプマップの強さ (MTLの-bm)
+    pub bump_scale: Option<f32>,
+    /// 切り抜きの決め方 ("stochastic"か{"threshold": アルファ})
+    pub alpha_test: Option<AlphaTest>,
+}
+
+/// カメラと描画の設定
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct CameraConfig {
+    pub num_of_bounce: usize,
+    pub num_of_diffuse: usize,
+    #[serde(deserialize_with = "at_least_one")]
+    pub sampling: usize,
+    pub position: Vector3,
+    #[serde(deserialize_with = "nonzero_vector")]
+    pub top: Vector3,
+    #[serde(deserialize_with = "nonzero_vector")]
+    pub forward: Vector3,
+    /// 横の画角(度)
+    #[serde(deserialize_with = "fov")]
+    pub fov: f32,
+    #[serde(deserialize_with = "image_size")]
+    pub image_size: [u32; 2],
+    /// テクスチャの引き方 ("nearest", "bilinear", "trilinear", "ewa")
+    pub texture_filter: TextureFilter,
+}
+impl Default for CameraConfig {
+    fn default() -> CameraConfig {
+        CameraConfig {
+            num_of_bounce: 4,
+            num_of_diffuse: 1,
+            sampling: 50,
+            position: Vector3::new(0., 0., 0.),
+            top: Vector3::new(0., 1., 0.),
+            forward: Vector3::new(0., 0., -1.),
+            fov: 90.,
+            image_size: [640, 480],
+            texture_filter: TextureFilter::default(),
+        }
+    }
+}
+
+impl CameraConfig {
+    pub fn render_settings(&self, seed: Option<u64>) -> RenderSettings {
+        RenderSettings {
+            num_of_bounce: self.num_of_bounce,
+            sampling: self.sampling,
+            num_of_diffuse: self.num_of_diffuse,
+            seed,
+        }
+    }
+}
+
+/// メッシュファイル(OBJ, PLY, STL, glTF)の配置
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectConfig {
+    pub name: String,
+    #[serde(default)]
+    pub rotate: Vector3,
+    #[serde(default = "one")]
+    pub scale: Vector3,
+    #[serde(default)]
+    pub translate: Vector3,
+    /// glTFのカメラを使う
+    #[serde(default)]
+    pub camera: bool,
+    /// STLの頂点をまとめて滑らかな法線を作る
+    pub weld: Option<bool>,
+    /// STLの折り目の角度(度)
+    #[serde(default, deserialize_with = "crease_angle")]
+    pub crease_angle: Option<f32>,
+    pub mtl: Option<String>,
+    pub material: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsgOpConfig {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// 解析的な形状. `mtl`と`material`で材質を指定する
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ShapeConfig {
+    Sphere {
+        center: Vector3,
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Disk {
+        center: Vector3,
+        #[serde(deserialize_with = "nonzero_vector")]
+        normal: Vector3,
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Quad {
+        corner: Vector3,
+        edge1: Vector3,
+        edge2: Vector3,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Plane {
+        point: Vector3,
+        #[serde(deserialize_with = "nonzero_vector")]
+        normal: Vector3,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Cylinder {
+        base: Vector3,
+        #[serde(deserialize_with = "nonzero_vector")]
+        axis: Vector3,
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+        #[serde(default = "yes")]
+        caps: bool,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Cone {
+        base: Vector3,
+        #[serde(deserialize_with = "nonzero_vector")]
+        axis: Vector3,
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+        #[serde(default = "yes")]
+        caps: bool,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Torus {
+        center: Vector3,
+        #[serde(deserialize_with = "nonzero_vector")]
+        axis: Vector3,
+        #[serde(deserialize_with = "positive")]
+        major_radius: f32,
+        #[serde(deserialize_with = "positive")]
+        minor_radius: f32,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Sdf {
+        sdf: SdfConfig,
+        mtl: Option<String>,
+        material: Option<String>,
+    },
+    Csg {
+        op: CsgOpConfig,
+        children: Vec<ShapeConfig>,
+    },
+    /// CSGで使う閉じたメッシュ
+    Mesh {
+        name: String,
+        #[serde(default)]
+        rotate: Vector3,
+        #[serde(default = "one")]
+        scale: Vector3,
+        #[serde(default)]
+        translate: Vector3,
+    },
+}
+
+/// 符号付き距離関数の式
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SdfConfig {
+    Sphere {
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+    },
+    Box {
+        half_extents: Vector3,
+    },
+    Torus {
+        #[serde(deserialize_with = "positive")]
+        major_radius: f32,
+        #[serde(deserialize_with = "positive")]
+        minor_radius: f32,
+    },
+    Cylinder {
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+        #[serde(deserialize_with = "positive")]
+        half_height: f32,
+    },
+    Mandelbulb {
+        #[serde(default = "mandelbulb_power")]
+        power: f32,
+        #[serde(default = "mandelbulb_iterations", deserialize_with = "at_least_one")]
+        iterations: usize,
+    },
+    Union {
+        children: Vec<SdfConfig>,
+    },
+    Intersection {
+        children: Vec<SdfConfig>,
+    },
+    Difference {
+        children: Vec<SdfConfig>,
+    },
+    SmoothUnion {
+        #[serde(deserialize_with = "positive")]
+        k: f32,
+        children: Vec<SdfConfig>,
+    },
+    SmoothIntersection {
+        #[serde(deserialize_with = "positive")]
+        k: f32,
+        children: Vec<SdfConfig>,
+    },
+    SmoothDifference {
+        #[serde(deserialize_with = "positive")]
+        k: f32,
+        children: Vec<SdfConfig>,
+    },
+    Translate {
+        translate: Vector3,
+        child: Box<SdfConfig>,
+    },
+    Rotate {
+        rotate: Vector3,
+        child: Box<SdfConfig>,
+    },
+    Scale {
+        #[serde(deserialize_with = "positive")]
+        scale: f32,
+        child: Box<SdfConfig>,
+    },
+    Repeat {
+        #[serde(deserialize_with = "positive_vector")]
+        period: Vector3,
+        child: Box<SdfConfig>,
+    },
+    Round {
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+        child: Box<SdfConfig>,
+    },
+}
+
+struct Vector3Visitor;
+impl<'de> Visitor<'de> for Vector3Visitor {
+    type Value = Vector3;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of 3 numbers")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vector3, A::Error> {
+        let mut v = [0.; 3];
+        for (i, x) in v.iter_mut().enumerate() {
+            *x = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(i, &self))?;
+        }
+        // 余分な要素はその場で報告して行番号を合わせる
+        if seq.next_element::<IgnoredAny>()?.is_some() {
+            return Err(A::Error::invalid_length(4, &self));
+        }
+        Ok(Vector3::new(v[0], v[1], v[2]))
+    }
+}
+/// 長さ3の数の配列をベクトルとして読む
+impl<'de> Deserialize<'de> for Vector3 {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Vector3, D::Error> {
+        d.deserialize_seq(Vector3Visitor)
+    }
+}
+
+fn one() -> Vector3 {
+    Vector3::new(1., 1., 1.)
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn mandelbulb_power() -> f32 {
+    8.
+}
+
+fn mandelbulb_iterations() -> usize {
+    8
+}
+
+fn positive<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    let v = f32::deserialize(d)?;
+    if 0. < v {
+        Ok(v)
+    } else {
+        Err(D::Error::custom(format!("expected a positive number, got {}", v)))
+    }
+}
+
+fn positive_vector<'de, D: Deserializer<'de>>(d: D) -> Result<Vector3, D::Error> {
+    let v = Vector3::deserialize(d)?;
+    if 0. < v.x && 0. < v.y && 0. < v.z {
+        Ok(v)
+    } else {
+        Err(D::Error::custom(format!("expected positive components, got {:?}", v)))
+    }
+}
+
+fn nonzero_vector<'de, D: Deserializer<'de>>(d: D) -> Result<Vector3, D::Error> {
+    let v = Vector3::deserialize(d)?;
+    if v != Vector3::new(0., 0., 0.) {
+        Ok(v)
+    } else {
+        Err(D::Error::custom("expected a non-zero vector"))
+    }
+}
+
+fn at_least_one<'de, D: Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
+    let v = usize::deserialize(d)?;
+    if 1 <= v {
+        Ok(v)
+    } else {
+        Err(D::Error::custom("expected at least 1"))
+    }
+}
+
+fn fov<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    let v = f32::deserialize(d)?;
+    if 0. < v && v < 180. {
+        Ok(v)
+    } else {
+        Err(D::Error::custom(format!("expected a field of view between 0 and 180 degrees, got {}", v)))
+    }
+}
+
+fn crease_angle<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f32>, D::Error> {
+    let v = f32::deserialize(d)?;
+    if (0. ..=180.).contains(&v) {
+        Ok(Some(v))
+    } else {
+        Err(D::Error::custom(format!("expected an angle between 0 and 180 degrees, got {}", v)))
+    }
+}
+
+fn conductor<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Conductor>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ConductorValue {
+        Preset(String),
+        Custom { eta: Vector3, k: Vector3 },
+    }
+    match ConductorValue::deserialize(d) {
+        Ok(ConductorValue::Preset(name)) => Conductor::preset(&name).map(Some).ok_or_else(|| {
+            D::Error::custom(format!("unknown conductor `{}`, expected one of {}", name, Conductor::PRESETS.join(", ")))
+        }),
+        Ok(ConductorValue::Custom { eta, k }) => Ok(Some(Conductor {
+            eta: Color::new(eta.x, eta.y, eta.z),
+            k: Color::new(k.x, k.y, k.z),
+        })),
+        Err(_) => Err(D::Error::custom("expected a conductor name or {\"eta\": [r, g, b], \"k\": [r, g, b]}")),
+    }
+}
+
+fn image_size<'de, D: Deserializer<'de>>(d: D) -> Result<[u32; 2], D::Error> {
+    let v = Vec::<u32>::deserialize(d)?;
+    match v.as_slice() {
+        [w, h] if 0 < *w && 0 < *h => Ok([*w, *h]),
+        _ => Err(D::Error::custom(format!("expected a non-empty image size [width, height], got {:?}", v))),
+    }
+}
+
+/// シーンファイルの誤り. JSON内の場所と行を示す
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: String,
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 行番号はファイルの中の誤りにだけある
+        if 0 < self.line {
+            write!(f, "{}:{}:{}: ", self.file, self.line, self.column)?;
+        } else {
+            write!(f, "{}: ", self.file)?;
+        }
+        if !self.path.is_empty() && self.path != "." {
+            write!(f, "at `{}`: ", self.path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    fn new(file: &str, path: &str, message: String) -> ConfigError {
+        ConfigError {
+            file: file.to_string(),
+            path: path.to_string(),
+            line: 0,
+            column: 0,
+            message,
+        }
+    }
+    fn from_json(file: &str, e: serde_path_to_error::Error<serde_json::Error>) -> ConfigError {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        // serde_jsonのメッセージ末尾の位置は別に出す
+        let message = inner.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(i) => message[..i].to_string(),
+            None => message,
+        };
+        ConfigError {
+            file: file.to_string(),
+            path,
+            line: inner.line(),
+            column: inner.column(),
+            message,
+        }
+    }
+}
+
+/// CSGの子が足りているか, 内外の決まる閉じた形状か. 開いた子があるとCSG全体が見えなくなる
+fn validate_csg(shape: &ShapeConfig, path: &str, file: &str) -> Result<(), ConfigError> {
+    let (op, children) = match shape {
+        ShapeConfig::Csg { op, children } => (op, children),
+        _ => return Ok(()),
+    };
+    let (name, min) = match op {
+        CsgOpConfig::Union => ("union", 1),
+        CsgOpConfig::Intersection => ("intersection", 2),
+        CsgOpConfig::Difference => ("difference", 2),
+    };
+    if children.len() < min {
+        return Err(ConfigError::new(file, &format!("{}.children", path),
+            format!("csg {} needs at least {} children, got {}", name, min, children.len())));
+    }
+    for (i, child) in children.iter().enumerate() {
+        let path = format!("{}.children[{}]", path, i);
+        let open = match child {
+            ShapeConfig::Plane { .. } | ShapeConfig::Disk { .. } | ShapeConfig::Quad { .. } => true,
+            ShapeConfig::Cylinder { caps, .. } | ShapeConfig::Cone { caps, .. } => !caps,
+            _ => false,
+        };
+        if open {
+            return Err(ConfigError::new(file, &path, String::from("csg children must be closed shapes (cylinders and cones need \"caps\": true)")));
+        }
+        validate_csg(child, &path, file)?;
+    }
+    Ok(())
+}
+
+/// `camera.fov`や`objectlist.0.translate`のような`.`区切りの場所に値を書き込む
+fn set_path(root: &mut Value, key: &str, value: Value) -> Result<(), String> {
+    let mut target = root;
+    for segment in key.split('.') {
+        target = match target {
+            Value::Object(map) => map.entry(segment).or_insert(Value::Null),
+            Value::Array(list) => {
+                let len = list.len();
+                segment.parse::<usize>().ok()
+                    .and_then(move |i| list.get_mut(i))
+                    .ok_or_else(|| format!("`{}` is not an index of an array of length {}", segment, len))?
+            },
+            Value::Null => {
+                *target = Value::Object(serde_json::Map::new());
+                match target {
+                    Value::Object(map) => map.entry(segment).or_insert(Value::Null),
+                    _ => unreachable!(),
+                }
+            },
+            _ => return Err(format!("can not set `{}` inside a non-object value", segment)),
+        };
+    }
+    *target = value;
+    Ok(())
+}
+
+impl SceneConfig {
+    pub fn parse(file: &str, content: &str) -> Result<SceneConfig, ConfigError> {
+        let de = &mut serde_json::Deserializer::from_str(content);
+        let config: SceneConfig = serde_path_to_error::deserialize(de).map_err(|e| ConfigError::from_json(file, e))?;
+        config.validate(file)?;
+        Ok(config)
+    }
+    /// `key=value`の上書きを適用して読む. 値はJSONとして読めなければ文字列として扱う
+    pub fn parse_with_overrides(file: &str, content: &str, overrides: &[(String, String)]) -> Result<SceneConfig, ConfigError> {
+        // 上書き前のファイルを先に検証して行番号つきの誤りを出す
+        let config = SceneConfig::parse(file, content)?;
+        if overrides.is_empty() {
+            return Ok(config);
+        }
+        let mut root: Value = serde_json::from_str(content).map_err(|e| ConfigError::new(file, "", e.to_string()))?;
+        for (key, value) in overrides.iter() {
+            let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.clone()));
+            set_path(&mut root, key, value).map_err(|e| ConfigError::new("command line", key, e))?;
+        }
+        let config: SceneConfig = serde_path_to_error::deserialize(root).map_err(|e| ConfigError::from_json("command line", e))?;
+        config.validate("command line")?;
+        Ok(config)
+    }
+    /// 型だけでは分からない誤りを調べる
+    fn validate(&self, file: &str) -> Result<(), ConfigError> {
+        for (i, shape) in self.shapes.iter().enumerate() {
+            validate_csg(shape, &format!("shapes[{}]", i), file)?;
+        }
+        Ok(())
+    }
+    pub fn load(file: &str, overrides: &[(String, String)]) -> Result<SceneConfig, ConfigError> {
+        let content = fs::read_to_string(file).map_err(|e| ConfigError::new(file, "", e.to_string()))?;
+        SceneConfig::parse_with_overrides(file, &content, overrides)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = SceneConfig::parse("scene.json", r#"{"objectlist": [{"name": "cube.obj"}]}"#).unwrap();
+        assert_eq!(config.camera.sampling, 50);
+        assert_eq!(config.objectlist[0].scale, Vector3::new(1., 1., 1.));
+        assert!(config.shapes.is_empty());
+        assert!(config.search_paths.is_empty());
+        assert!(config.materials.is_empty());
+    }
+
+    #[test]
+    fn csg_children_are_checked() {
+        let sphere = r#"{"type": "sphere", "center": [0, 0, 0], "radius": 1}"#;
+        let csg = |op: &str, children: &str| format!(r#"{{"shapes": [{}, {{"type": "csg", "op": "{}", "children": [{}]}}]}}"#, sphere, op, children);
+        assert!(SceneConfig::parse("scene.json", &csg("union", sphere)).is_ok());
+        let e = SceneConfig::parse("scene.json", &csg("difference", sphere)).unwrap_err();
+        assert_eq!(e.path, "shapes[1].children");
+        assert!(e.message.contains("at least 2 children"), "{}", e);
+        let open = r#"{"type": "cylinder", "base": [0, 0, 0], "axis": [0, 1, 0], "radius": 1, "caps": false}"#;
+        let nested = format!(r#"{{"type": "csg", "op": "union", "children": [{}]}}"#, open);
+        let e = SceneConfig::parse("scene.json", &csg("intersection", &format!("{}, {}", sphere, nested))).unwrap_err();
+        assert_eq!(e.path, "shapes[1].children[1].children[0]");
+        assert!(e.message.contains("closed"), "{}", e);
+    }
+
+    #[test]
+    fn material_overrides() {
+        let content = r#"{"materials": {"ring": {"conductor": "gold", "roughness": 0.2}, "pipe": {"conductor": {"eta": [1, 1, 1], "k": [3, 3, 3]}}}}"#;
+        let config = SceneConfig::parse("scene.json", content).unwrap();
+        assert_eq!(config.materials["ring"].conductor, Conductor::preset("gold"));
+        assert_eq!(config.materials["ring"].roughness, Some(0.2));
+        assert_eq!(config.materials["pipe"].conductor.unwrap().k, Color::new(3., 3., 3.));
+        let config = SceneConfig::parse("scene.json", r#"{"materials": {"wall": {"normal_format": "directx"}}}"#).unwrap();
+        assert_eq!(config.materials["wall"].normal_format, Some(NormalMapFormat::DirectX));
+        assert_eq!(config.materials["wall"].bump_scale, None);
+        let config = SceneConfig::parse("scene.json", r#"{"materials": {"leaf": {"alpha_test": {"threshold": 0.3}}, "fern": {"alpha_test": "stochastic"}}}"#).unwrap();
+        assert_eq!(config.materials["leaf"].alpha_test, Some(AlphaTest::Threshold(0.3)));
+        assert_eq!(config.materials["fern"].alpha_test, Some(AlphaTest::Stochastic));
+        let e = SceneConfig::parse("scene.json", r#"{"materials": {"ring": {"conductor": "brass"}}}"#).unwrap_err();
+        assert_eq!(e.path, "materials.ring.conductor");
+        assert!(e.message.contains("unknown conductor `brass`"), "{}", e.message);
+    }
+
+    #[test]
+    fn error_points_at_path_and_line() {
+        let content = "{\n  \"camera\": {\n    \"position\": [0.0, 0.0, 0,0]\n  }\n}";
+        let e = SceneConfig::parse("scene.json", content).unwrap_err();
+        assert_eq!(e.path, "camera.position");
+        assert_eq!(e.line, 3);
+        assert!(e.message.contains("invalid length 4"), "{}", e);
+
+        let content = "{\"camera\": {\"fov\": 200}}";
+        let e = SceneConfig::parse("scene.json", content).unwrap_err();
+        assert_eq!(e.path, "camera.fov");
+
+        let content = "{\"objectlist\": [{\"name\": \"a.obj\"}, {\"nmae\": \"b.obj\"}]}";
+        let e = SceneConfig::parse("scene.json", content).unwrap_err();
+        assert_eq!(e.path, "objectlist[1].nmae");
+        assert!(e.message.contains("nmae"), "{}", e);
+    }
+
+    #[test]
+    fn overrides() {
+        let content = r#"{"camera": {"fov": 60}, "objectlist": [{"name": "a.obj"}]}"#;
+        let overrides = vec![
+            (String::from("camera.fov"), String::from("45")),
+            (String::from("camera.image_size"), String::from("[32, 16]")),
+            (String::from("camera.texture_filter"), String::from("ewa")),
+            (String::from("objectlist.0.name"), String::from("b.obj")),
+        ];
+        let config = SceneConfig::parse_with_overrides("scene.json", content, &overrides).unwrap();
+        assert_eq!(config.camera.fov, 45.);
+        assert_eq!(config.camera.texture_filter, TextureFilter::Ewa);
+        assert_eq!(config.camera.image_size, [32, 16]);
+        assert_eq!(config.objectlist[0].name, "b.obj");
+
+        let overrides = vec![(String::from("camera.fov"), String::from("0"))];
+        let e = SceneConfig::parse_with_overrides("scene.json", content, &overrides).unwrap_err();
+        assert_eq!(e.path, "camera.fov");
+        assert_eq!(e.file, "command line");
+    }
+}
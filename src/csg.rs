@@ -0,0 +1,164 @@
+use crate::vector::{Vector3, Vector2};
+use crate::ray::Ray;
+use crate::bounds::Bounds3;
+use crate::shape::{Shape, Surface, Crossing, Crossings, degenerate_surface};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    /// 最初の子から残りの子を引く
+    Difference,
+}
+impl CsgOp {
+    fn inside(&self, inside: &[bool]) -> bool {
+        match self {
+            CsgOp::Union => inside.iter().any(|&i| i),
+            CsgOp::Intersection => inside.iter().all(|&i| i),
+            CsgOp::Difference => inside.split_first().is_some_and(|(&first, rest)| first && !rest.iter().any(|&i| i)),
+        }
+    }
+}
+
+/// 閉じた形状の集合演算. 子の内外の区間を光線に沿って合成する
+#[derive(Debug)]
+pub struct Csg {
+    op: CsgOp,
+    children: Vec<Box<dyn Shape>>,
+    bounds: Bounds3,
+}
+impl Csg {
+    pub fn new(op: CsgOp, children: Vec<Box<dyn Shape>>) -> Csg {
+        let bounds = match op {
+            CsgOp::Union => children.iter().fold(Bounds3::empty(), |acc, c| acc.union(&c.bounds())),
+            CsgOp::Intersection => {
+                let mut iter = children.iter().map(|c| c.bounds());
+                let first = iter.next().unwrap_or_else(Bounds3::empty);
+                iter.fold(first, |acc, b| Bounds3::new(
+                    Vector3::new(acc.min.x.max(b.min.x), acc.min.y.max(b.min.y), acc.min.z.max(b.min.z)),
+                    Vector3::new(acc.max.x.min(b.max.x), acc.max.y.min(b.max.y), acc.max.z.min(b.max.z)),
+                ))
+            },
+            CsgOp::Difference => children.first().map_or(Bounds3::empty(), |c| c.bounds()),
+        };
+        Csg {
+            op,
+            children,
+            bounds,
+        }
+    }
+}
+impl Shape for Csg {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        self.crossings(ray)?.hits.first().map(|c| c.hit)
+    }
+    fn bounds(&self) -> Bounds3 {
+        self.bounds
+    }
+    /// 子の表面積の和で近似する
+    fn area(&self) -> f32 {
+        self.children.iter().map(|c| c.area()).sum()
+    }
+    /// 最初の子の表面から選ぶ. 削られた部分の点も返すので光源サンプリングには使わない
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        self.children.first().map_or(self.bounds.centroid(), |c| c.sample(u))
+    }
+    fn samplable(&self) -> bool {
+        false
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        // 許容誤差の境目で2回目の判定が食い違うことがあるので, そのときは最初の子の面を使う
+        let crossings = self.crossings(ray);
+        let crossing = crossings.as_ref().and_then(|crossings| crossings.hits.iter()
+            .find(|c| c.hit.x == hit.x)
+            .or_else(|| crossings.hits.first()));
+        let crossing = match crossing {
+            Some(crossing) => crossing,
+            None => return match self.children.first() {
+                Some(child) => child.surface(ray, hit),
+                None => degenerate_surface(ray, hit),
+            },
+        };
+        let mut surface = crossing.shape.surface(ray, &crossing.hit);
+        if crossing.flip {
+            surface.normal = surface.normal * -1.;
+            surface.geometric_normal = surface.geometric_normal * -1.;
+        }
+        surface
+    }
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        let children = self.children.iter().map(|c| c.crossings(ray)).collect::<Option<Vec<_>>>()?;
+        let mut inside = children.iter().map(|c| c.inside).collect::<Vec<_>>();
+        let mut events = children.iter().enumerate()
+            .flat_map(|(i, c)| c.hits.iter().map(move |hit| (i, *hit)))
+            .collect::<Vec<_>>();
+        events.sort_by(|a, b| a.1.hit.x.partial_cmp(&b.1.hit.x).unwrap_or(std::cmp::Ordering::Equal));
+
+        let start = self.op.inside(&inside);
+        let mut state = start;
+        let mut hits = Vec::new();
+        for (i, crossing) in events.into_iter() {
+            inside[i] = !inside[i];
+            let next = self.op.inside(&inside);
+            if next != state {
+                // 引かれる側の表面は法線を裏返す
+                let flip = crossing.flip ^ (self.op == CsgOp::Difference && 0 < i);
+                hits.push(Crossing {
+                    flip,
+                    ..crossing
+                });
+                state = next;
+            }
+        }
+        Some(Crossings {
+            inside: start,
+            hits,
+        })
+    }
+}
+
+/// 面が見つからないときの材質のない面. 描画では何も返さない
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Sphere;
+
+    #[test]
+    fn sphere_minus_sphere() {
+        let csg = Csg::new(CsgOp::Difference, vec![
+            Box::new(Sphere::new(Vector3::new(0., 0., 0.), 1., Some(0))),
+            Box::new(Sphere::new(Vector3::new(1., 0., 0.), 1., Some(1))),
+        ]);
+        // 引いた球の内側の面に当たる
+        let ray = Ray::new(Vector3::new(5., 0., 0.), Vector3::new(-1., 0., 0.));
+        let hit = csg.intersection(&ray).unwrap();
+        assert!((hit.x - 5.).abs() < 1e-4);
+        let surface = csg.surface(&ray, &hit);
+        assert_eq!(surface.material_id, Some(1));
+        assert!((surface.normal.x - 1.).abs() < 1e-4);
+        // 削られていない部分は元の球の面
+        let ray = Ray::new(Vector3::new(-5., 0., 0.), Vector3::new(1., 0., 0.));
+        let hit = csg.intersection(&ray).unwrap();
+        assert!((hit.x - 4.).abs() < 1e-4);
+        assert_eq!(csg.surface(&ray, &hit).material_id, Some(0));
+        // 両方の球の外を通る光線
+        let ray = Ray::new(Vector3::new(5., 3., 0.), Vector3::new(-1., 0., 0.));
+        assert!(csg.intersection(&ray).is_none());
+        // 当たらない光線で面を求めても止まらない
+        assert_eq!(csg.surface(&ray, &Vector3::new(1., 0., 0.)).material_id, Some(0));
+        assert_eq!(Csg::new(CsgOp::Union, Vec::new()).surface(&ray, &Vector3::new(1., 0., 0.)).material_id, None);
+    }
+
+    #[test]
+    fn intersection_of_spheres_from_inside() {
+        let csg = Csg::new(CsgOp::Intersection, vec![
+            Box::new(Sphere::new(Vector3::new(-0.5, 0., 0.), 1., None)),
+            Box::new(Sphere::new(Vector3::new(0.5, 0., 0.), 1., None)),
+        ]);
+        let ray = Ray::new(Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.));
+        let crossings = csg.crossings(&ray).unwrap();
+        assert!(crossings.inside);
+        assert_eq!(crossings.hits.len(), 1);
+        assert!((crossings.hits[0].hit.x - 0.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use crate::vector::{Vector3, Vector2};
+use crate::color::Color;
+use crate::object::{Plane, Normcoord, Texcoord, Colorcoord};
+use crate::material::Material;
+use crate::assets::TextureCache;
+use crate::texture::MipMap;
+
+/// 細分割してできる三角形の数の上限
+const MAX_TRIANGLES: usize = 1 << 22;
+/// 1つの三角形の辺を分ける数の上限
+const MAX_SEGMENTS: usize = 256;
+
+/// UVでの変位マップの明るさ(0-1). 元の画像を双線形補間し, 範囲外はテクスチャの設定に従う
+fn height(mipmap: &MipMap, uv: &Vector2) -> f32 {
+    let c = mipmap.bilinear(0, uv);
+    (c.r + c.g + c.b) / (3. * 255.)
+}
+
+/// 細分割した三角形の頂点
+#[derive(Debug, Copy, Clone)]
+struct Vertex {
+    point: Vector3,
+    normal: Vector3,
+    uv: Vector2,
+    color: Option<Color>,
+}
+
+fn key(v: &Vector3) -> [u32; 3] {
+    [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]
+}
+
+/// 変位マップのある材質の三角形を細分割し, 頂点を法線方向に動かす.
+/// 隣りあう三角形の境目がずれないように, 同じ材質の三角形は同じ数に分ける
+pub fn displace(planes: Vec<Plane>, materials: &[Material], textures: &TextureCache) -> Vec<Plane> {
+    let image_of = |plane: &Plane| {
+        let material = materials.get(plane.material_id?)?;
+        let image = textures.mipmap(material.displacement_texture?)?;
+        // UVがなければ変位の場所が決まらない
+        plane.vt.as_ref()?;
+        Some((material, image))
+    };
+    // 頂点法線のない三角形は, 同じ位置の頂点で面の法線を面積の重みで平均し, 隣と同じ向きに動かす
+    let mut welded = HashMap::new();
+    for plane in planes.iter().filter(|plane| plane.vn.is_none() && image_of(plane).is_some()) {
+        let [v1, v2, v3] = plane.vertices();
+        let normal = (v2 - v1).cross(&(v3 - v1));
+        for v in [v1, v2, v3].iter() {
+            let sum = welded.entry(key(v)).or_insert_with(|| Vector3::new(0., 0., 0.));
+            *sum = *sum + normal;
+        }
+    }
+    // 材質ごとに, UVで見た辺の長さが1テクセル程度になる分割数を選ぶ
+    let mut segments = vec![1; materials.len()];
+    let mut counts = vec![0; materials.len()];
+    for plane in planes.iter() {
+        if let (Some((_, image)), Some(vt), Some(id)) = (image_of(plane), plane.vt.as_ref(), plane.material_id) {
+            let size = image.image().width().max(image.image().height()) as f32;
+            let longest = [vt.v2 - vt.v1, vt.v3 - vt.v2, vt.v1 - vt.v3].iter()
+                .map(|d| (d.x * d.x + d.y * d.y).sqrt() * size)
+                .fold(0., f32::max);
+            segments[id] = segments[id].max(longest.ceil() as usize);
+            counts[id] += 1;
+        }
+    }
+    for (segment, count) in segments.iter_mut().zip(counts.iter()) {
+        let limit = ((MAX_TRIANGLES / count.max(&1)) as f32).sqrt() as usize;
+        *segment = (*segment).min(MAX_SEGMENTS).min(limit).max(1);
+    }
+    let mut result = Vec::with_capacity(planes.len());
+    for plane in planes.into_iter() {
+        match (image_of(&plane), plane.vt.as_ref()) {
+            (Some((material, image)), Some(vt)) => {
+                let n = segments[plane.material_id.unwrap_or(0)];
+                let normals = match &plane.vn {
+                    Some(vn) => [vn.v1, vn.v2, vn.v3],
+                    None => plane.vertices().map(|v| welded[&key(&v)]),
+                };
+                result.extend(subdivide(&plane, vt, normals, n, |uv| material.displacement_base + material.displacement_gain * height(image, uv)));
+            },
+            _ => result.push(plane),
+        }
+    }
+    result
+}
+
+/// 三角形の辺を`n`等分した格子に分け, 頂点の`normals`を補間した向きに高さ`offset`だけ動かす
+fn subdivide<F: Fn(&Vector2) -> f32>(plane: &Plane, vt: &Texcoord, normals: [Vector3; 3], n: usize, offset: F) -> Vec<Plane> {
+    let [v1, v2, v3] = plane.vertices();
+    let [n1, n2, n3] = normals;
+    // 格子点(i, j)は重心座標(1 - i/n - j/n, i/n, j/n)
+    let index = |i: usize, j: usize| i * (2 * n + 3 - i) / 2 + j;
+    let mut vertices = Vec::with_capacity((n + 1) * (n + 2) / 2);
+    for i in 0..=n {
+        for j in 0..=(n - i) {
+            let (b1, b2) = (i as f32 / n as f32, j as f32 / n as f32);
+            let b0 = 1. - b1 - b2;
+            let normal = (n1 * b0 + n2 * b1 + n3 * b2).normalize();
+            let uv = vt.v1 * b0 + vt.v2 * b1 + vt.v3 * b2;
+            vertices.push(Vertex {
+                point: v1 * b0 + v2 * b1 + v3 * b2 + normal * offset(&uv),
+                normal,
+                uv,
+                color: plane.vc.as_ref().map(|vc| vc.v1 * b0 + vc.v2 * b1 + vc.v3 * b2),
+            });
+        }
+    }
+    let mut triangles = Vec::with_capacity(n * n);
+    for i in 0..n {
+        for j in 0..(n - i) {
+            triangles.push([index(i, j), index(i + 1, j), index(i, j + 1)]);
+            if i + j + 1 < n {
+                triangles.push([index(i + 1, j), index(i + 1, j + 1), index(i, j + 1)]);
+            }
+        }
+    }
+    // 動かした面の法線を頂点ごとに平均する
+    let mut normals = vec![Vector3::new(0., 0., 0.); vertices.len()];
+    for t in triangles.iter() {
+        let [a, b, c] = t.map(|k| vertices[k].point);
+        let normal = (b - a).cross(&(c - a));
+        for &k in t.iter() {
+            normals[k] = normals[k] + normal;
+        }
+    }
+    let normals = normals.iter().zip(vertices.iter()).map(|(normal, vertex)| {
+        if normal.inner(normal) <= 0. {
+            return vertex.normal;
+        }
+        // 元の法線と同じ側に向ける
+        let normal = normal.normalize();
+        if normal.inner(&vertex.normal) < 0. { normal * -1. } else { normal }
+    }).collect::<Vec<_>>();
+    triangles.iter().map(|t| {
+        let [a, b, c] = t.map(|k| vertices[k]);
+        let [na, nb, nc] = t.map(|k| normals[k]);
+        let mut sub = Plane::new(a.point, b.point, c.point, Some(Normcoord::new(na, nb, nc)), Some(Texcoord::new(a.uv, b.uv, c.uv)), plane.material_id);
+        if let (Some(ca), Some(cb), Some(cc)) = (a.color, b.color, c.color) {
+            sub.vc = Some(Colorcoord::new(ca, cb, cc));
+        }
+        sub
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Shape;
+    use image::{RgbImage, Rgb};
+
+    #[test]
+    fn flat_triangle_is_raised_by_height_map() {
+        let mut textures = TextureCache::new();
+        let id = textures.insert(String::from("disp.png"), RgbImage::from_pixel(2, 2, Rgb([255, 255, 255])));
+        let material = Material {
+            displacement_texture: Some(id),
+            displacement_gain: 0.5,
+            ..Default::default()
+        };
+        let vt = Texcoord::new(Vector2::new(0., 0.), Vector2::new(1., 0.), Vector2::new(0., 1.));
+        let plane = Plane::new(Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.), None, Some(vt), Some(0));
+        let total = plane.area();
+        let planes = displace(vec![plane], &[material], &textures);
+        // 斜辺がUVで2.8テクセルなので辺を3等分して9つ
+        assert_eq!(planes.len(), 9);
+        for plane in planes.iter() {
+            assert!(plane.vertices().iter().all(|v| (v.z - 0.5).abs() < 1e-6));
+        }
+        assert!((planes.iter().map(|p| p.area()).sum::<f32>() - total).abs() < 1e-5);
+        // 変位マップのない材質はそのまま
+        let plane = Plane::new(Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.), None, None, Some(0));
+        assert_eq!(displace(vec![plane], &[Material::default()], &textures).len(), 1);
+    }
+
+    #[test]
+    fn shared_edge_stays_closed_without_vertex_normals() {
+        let mut textures = TextureCache::new();
+        let id = textures.insert(String::from("disp.png"), RgbImage::from_pixel(2, 2, Rgb([255, 255, 255])));
+        let material = Material {
+            displacement_texture: Some(id),
+            displacement_gain: 0.5,
+            ..Default::default()
+        };
+        // 対角線で折れた2枚の三角形
+        let (p, q) = (Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.));
+        let a = Plane::new(Vector3::new(0., 0., 0.), p, q, None,
+            Some(Texcoord::new(Vector2::new(0., 0.), Vector2::new(1., 0.), Vector2::new(0., 1.))), Some(0));
+        let b = Plane::new(q, p, Vector3::new(1., 1., 1.), None,
+            Some(Texcoord::new(Vector2::new(0., 1.), Vector2::new(1., 0.), Vector2::new(1., 1.))), Some(0));
+        let planes = displace(vec![a, b], &[material], &textures);
+        assert_eq!(planes.len(), 18);
+        // 1つの三角形にしか使われない辺は外周の4辺をそれぞれ3等分したものだけ
+        let round = |v: &Vector3| [v.x, v.y, v.z].map(|x| (x * 1e4).round() as i64);
+        let mut edges = HashMap::new();
+        for plane in planes.iter() {
+            let [v1, v2, v3] = plane.vertices().map(|v| round(&v));
+            for &(s, t) in [(v1, v2), (v2, v3), (v3, v1)].iter() {
+                *edges.entry(if s < t { (s, t) } else { (t, s) }).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(edges.values().filter(|&&count| count == 1).count(), 12);
+    }
+}
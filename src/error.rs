@@ -0,0 +1,73 @@
+use std::fmt;
+
+use crate::config::ConfigError;
+
+/// 読み込みと描画の誤り
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, source: std::io::Error },
+    Obj { path: String, source: tobj::LoadError },
+    Mtl { path: String, source: tobj::LoadError },
+    Image { path: String, source: image::ImageError },
+    Gltf { path: String, source: gltf::Error },
+    /// PLYやSTLの中身が読めない
+    Parse { path: String, message: String },
+    /// 材質の値が読めない
+    Material { name: String, message: String },
+    Config(ConfigError),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "can not read {}: {}", path, source),
+            Error::Obj { path, source } => write!(f, "can not open OBJ file {}: {}", path, source),
+            Error::Mtl { path, source } => write!(f, "can not open MTL file {}: {}", path, source),
+            Error::Image { path, source } => write!(f, "can not open texture {}: {}", path, source),
+            Error::Gltf { path, source } => write!(f, "can not open glTF file {}: {}", path, source),
+            Error::Parse { path, message } => write!(f, "invalid file {}: {}", path, message),
+            Error::Material { name, message } => write!(f, "invalid material {}: {}", name, message),
+            Error::Config(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Obj { source, .. } | Error::Mtl { source, .. } => Some(source),
+            Error::Image { source, .. } => Some(source),
+            Error::Gltf { source, .. } => Some(source),
+            Error::Parse { .. } | Error::Material { .. } => None,
+            Error::Config(e) => Some(e),
+        }
+    }
+}
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Error {
+        Error::Config(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// 読み込めないファイルの扱い. 置き換えたときは警告を出す
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct LoadPolicy {
+    /// 読めないテクスチャをマゼンタの市松模様にする
+    pub substitute_textures: bool,
+    /// 読めないMTLファイルの代わりに既定の材質を使う
+    pub substitute_materials: bool,
+}
+impl LoadPolicy {
+    /// どちらも置き換える
+    pub fn lenient() -> LoadPolicy {
+        LoadPolicy {
+            substitute_textures: true,
+            substitute_materials: true,
+        }
+    }
+}
+
+pub fn warn(message: &str) {
+    eprintln!("warning: {}", message);
+}
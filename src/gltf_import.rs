@@ -0,0 +1,425 @@
+use image::{RgbImage, Rgb};
+
+use gltf::khr_lights_punctual::Kind;
+use gltf::camera::Projection;
+use gltf::material::AlphaMode;
+use gltf::mesh::Mode;
+
+use crate::vector::{Vector3, Vector2};
+use crate::object::{Object, Plane, Normcoord, Texcoord};
+use crate::shape::{Shape, Sphere, Disk};
+use crate::scene::Camera;
+use crate::error::{Error, Result, warn};
+use crate::assets::{TextureCache, TextureId};
+use crate::texture::WrapMode;
+use crate::material::{Material, AlphaTest};
+use crate::color::Color;
+
+/// 点光源を表す球の半径
+const POINT_LIGHT_RADIUS: f32 = 0.1;
+/// 平行光源を置く距離と円盤の半径
+const DIRECTIONAL_LIGHT_DISTANCE: f32 = 1000.;
+const DIRECTIONAL_LIGHT_RADIUS: f32 = 10.;
+
+type Matrix = [[f32; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1., 0., 0., 0.],
+    [0., 1., 0., 0.],
+    [0., 0., 1., 0.],
+    [0., 0., 0., 1.],
+];
+
+/// 列優先の4x4行列の積
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.; 4]; 4];
+    for (c, column) in m.iter_mut().enumerate() {
+        for (r, value) in column.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[k][r] * b[c][k]).sum();
+        }
+    }
+    m
+}
+
+fn column(m: &Matrix, c: usize) -> Vector3 {
+    Vector3::new(m[c][0], m[c][1], m[c][2])
+}
+
+fn transform_point(m: &Matrix, p: [f32; 3]) -> Vector3 {
+    column(m, 0) * p[0] + column(m, 1) * p[1] + column(m, 2) * p[2] + column(m, 3)
+}
+
+fn transform_vector(m: &Matrix, v: [f32; 3]) -> Vector3 {
+    column(m, 0) * v[0] + column(m, 1) * v[1] + column(m, 2) * v[2]
+}
+
+/// 法線は上3x3の余因子行列(逆転置行列の行列式倍)で変換する
+fn transform_normal(m: &Matrix, n: [f32; 3]) -> Vector3 {
+    let (a0, a1, a2) = (column(m, 0), column(m, 1), column(m, 2));
+    let v = a1.cross(&a2) * n[0] + a2.cross(&a0) * n[1] + a0.cross(&a1) * n[2];
+    let v = if determinant(m) < 0. { v * -1. } else { v };
+    v.normalize()
+}
+
+fn determinant(m: &Matrix) -> f32 {
+    column(m, 0).inner(&column(m, 1).cross(&column(m, 2)))
+}
+
+/// glTFのカメラ. 画像サイズはシーンファイル側で決める
+#[derive(Debug, Clone, Copy)]
+pub struct GltfCamera {
+    pub position: Vector3,
+    pub top: Vector3,
+    pub forward: Vector3,
+    /// 縦の画角(度)
+    pub yfov: f32,
+    pub aspect_ratio: Option<f32>,
+}
+impl GltfCamera {
+    pub fn to_camera(self, image_size: (u32, u32)) -> Camera {
+        // Cameraは横の画角を使う
+        let aspect = self.aspect_ratio.unwrap_or(image_size.0 as f32 / image_size.1 as f32);
+        let fov = 2. * ((self.yfov.to_radians() / 2.).tan() * aspect).atan();
+        // glTFは右手系なので右方向は forward x top
+        let right = self.forward.cross(&self.top);
+        Camera::new(self.position, self.top, self.forward, right, fov.to_degrees(), image_size)
+    }
+}
+
+#[derive(Debug)]
+pub struct GltfScene {
+    pub objects: Vec<Object>,
+    pub camera: Option<GltfCamera>,
+}
+
+/// objectlistの回転・拡大・平行移動
+struct Placement {
+    r: Vector3,
+    s: Vector3,
+    t: Vector3,
+}
+impl Placement {
+    fn point(&self, p: Vector3) -> Vector3 {
+        p.rotate(&self.r).scale(&self.s).translate(&self.t)
+    }
+    fn vector(&self, v: Vector3) -> Vector3 {
+        v.rotate(&self.r)
+    }
+}
+
+/// glTF 2.0 (.gltf/.glb)を読み込み, メッシュ全体を1つの物体, 光源をそれぞれ発光物体にする
+/// テクスチャは`textures`に登録する
+pub fn import(gltf_file: &str, r: Vector3, s: Vector3, t: Vector3, textures: &mut TextureCache) -> Result<GltfScene> {
+    let (document, buffers, images) = gltf::import(gltf_file).map_err(|source| Error::Gltf { path: gltf_file.to_string(), source })?;
+    Ok(from_document(gltf_file, &document, &buffers, &images, Placement { r, s, t }, textures))
+}
+
+fn from_document(name: &str, document: &gltf::Document, buffers: &[gltf::buffer::Data],
+    images: &[gltf::image::Data], placement: Placement, textures: &mut TextureCache) -> GltfScene {
+    let mut materials = load_materials(name, document, images, textures);
+    let default_material = materials.len();
+    materials.push(Material::default());
+
+    let mut planes = Vec::new();
+    let mut lights = Vec::new();
+    let mut camera = None;
+    let scene = document.default_scene().or_else(|| document.scenes().next());
+    let mut stack = scene.iter()
+        .flat_map(|scene| scene.nodes())
+        .map(|node| (node, IDENTITY))
+        .collect::<Vec<_>>();
+    while let Some((node, parent)) = stack.pop() {
+        let m = mul(&parent, &node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                let material_id = primitive.material().index().unwrap_or(default_material);
+                load_primitive(name, &primitive, buffers, &m, &placement, material_id, &mut planes);
+            }
+        }
+        if let Some(light) = node.light() {
+            lights.push(load_light(&light, &m, &placement));
+        }
+        if let (None, Some(c)) = (camera, node.camera()) {
+            match c.projection() {
+                Projection::Perspective(p) => camera = Some(GltfCamera {
+                    position: placement.point(transform_point(&m, [0., 0., 0.])),
+                    top: placement.vector(transform_vector(&m, [0., 1., 0.])).normalize(),
+                    forward: placement.vector(transform_vector(&m, [0., 0., -1.])).normalize(),
+                    yfov: p.yfov().to_degrees(),
+                    aspect_ratio: p.aspect_ratio(),
+                }),
+                Projection::Orthographic(_) => warn(&format!("{}: orthographic cameras are not supported, ignoring the camera", name)),
+            }
+        }
+        stack.extend(node.children().map(|child| (child, m)));
+    }
+
+    let mut objects = Vec::new();
+    if !planes.is_empty() {
+        let shapes = planes.into_iter().map(|plane| Box::new(plane) as Box<dyn Shape>).collect();
+        objects.push(Object::from_shapes(shapes, materials));
+    }
+    objects.extend(lights);
+    GltfScene {
+        objects,
+        camera,
+    }
+}
+
+fn load_primitive(name: &str, primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data], m: &Matrix,
+    placement: &Placement, material_id: usize, planes: &mut Vec<Plane>) {
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+    let positions = match reader.read_positions() {
+        Some(positions) => positions.map(|p| placement.point(transform_point(m, p))).collect::<Vec<_>>(),
+        None => return,
+    };
+    let normals = reader.read_normals()
+        .map(|normals| normals.map(|n| placement.vector(transform_normal(m, n))).collect::<Vec<_>>());
+    // glTFのUVは左上原点なのでOBJと同じ左下原点にそろえる
+    let texcoords = reader.read_tex_coords(0)
+        .map(|texcoords| texcoords.into_f32().map(|uv| Vector2::new(uv[0], 1. - uv[1])).collect::<Vec<_>>());
+    let indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().map(|i| i as usize).collect::<Vec<_>>(),
+        None => (0..positions.len()).collect(),
+    };
+    let triangles = match primitive.mode() {
+        Mode::Triangles => indices.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect::<Vec<_>>(),
+        Mode::TriangleStrip => (2..indices.len()).map(|i| if i % 2 == 0 {
+            [indices[i-2], indices[i-1], indices[i]]
+        } else {
+            [indices[i-1], indices[i-2], indices[i]]
+        }).collect(),
+        Mode::TriangleFan => (2..indices.len()).map(|i| [indices[0], indices[i-1], indices[i]]).collect(),
+        mode => {
+            warn(&format!("{}: primitive mode {:?} is not supported, skipping the primitive", name, mode));
+            return;
+        },
+    };
+    // 負のスケールでは巻き順が反転する
+    let flip = determinant(m) < 0.;
+    for [i1, mut i2, mut i3] in triangles {
+        if positions.len() <= i1.max(i2).max(i3) {
+            continue;
+        }
+        if flip {
+            std::mem::swap(&mut i2, &mut i3);
+        }
+        let vn = normals.as_ref().map(|n| Normcoord::new(n[i1], n[i2], n[i3]));
+        let vt = texcoords.as_ref().map(|t| Texcoord::new(t[i1], t[i2], t[i3]));
+        planes.push(Plane::new(positions[i1], positions[i2], positions[i3], vn, vt, Some(material_id)));
+    }
+}
+
+/// 点光源・スポットライトは小さな発光球, 平行光源は遠方の発光円盤にする. スポットの円錐は無視する
+fn load_light(light: &gltf::khr_lights_punctual::Light, m: &Matrix, placement: &Placement) -> Object {
+    let position = placement.point(transform_point(m, [0., 0., 0.]));
+    let direction = placement.vector(transform_vector(m, [0., 0., -1.])).normalize();
+    let color = light.color();
+    let (shape, radiance): (Box<dyn Shape>, f32) = match light.kind() {
+        Kind::Directional => {
+            // 照度を円盤の立体角で割って放射輝度にする
+            let solid_angle = std::f32::consts::PI * (DIRECTIONAL_LIGHT_RADIUS / DIRECTIONAL_LIGHT_DISTANCE).powi(2);
+            let disk = Disk::new(position - direction * DIRECTIONAL_LIGHT_DISTANCE, direction, DIRECTIONAL_LIGHT_RADIUS, Some(0));
+            (Box::new(disk), light.intensity() / solid_angle)
+        },
+        Kind::Point | Kind::Spot { .. } => {
+            // 光度を球の投影面積で割って放射輝度にする
+            let area = std::f32::consts::PI * POINT_LIGHT_RADIUS * POINT_LIGHT_RADIUS;
+            (Box::new(Sphere::new(position, POINT_LIGHT_RADIUS, Some(0))), light.intensity() / area)
+        },
+    };
+    let material = Material {
+        name: light.name().unwrap_or("light").to_string(),
+        ..Material::light(emission(color, radiance))
+    };
+    Object::from_shapes(vec![shape], vec![material])
+}
+
+/// 発光色を0-255のスケールにする
+fn emission(color: [f32; 3], strength: f32) -> Color {
+    Color::from_list(color) * (strength * 255.)
+}
+
+/// メタリック・ラフネスの材質を変換する. 係数はテクスチャに焼き込み, 画像は`ファイル名#材質番号/用途`の名前で登録する
+fn load_materials(name: &str, document: &gltf::Document, images: &[gltf::image::Data], textures: &mut TextureCache) -> Vec<Material> {
+    let mut materials = Vec::new();
+    for (i, m) in document.materials().enumerate() {
+        let pbr = m.pbr_metallic_roughness();
+        let base = pbr.base_color_factor();
+        let texture = |info: Option<gltf::texture::Texture>| info.and_then(|t| Some((images.get(t.source().index())?, sampler_wrap(&t.sampler()))));
+        let key = |usage: &str| format!("{}#{}/{}", name, i, usage);
+        let mut material = Material {
+            name: m.name().map_or_else(|| format!("material{}", i), String::from),
+            diffuse: Color::new(base[0], base[1], base[2]),
+            roughness: pbr.roughness_factor(),
+            metallic: pbr.metallic_factor(),
+            ior: m.ior().unwrap_or(1.5),
+            ..Default::default()
+        };
+        if let Some((data, wrap)) = texture(pbr.base_color_texture().map(|t| t.texture())) {
+            material.diffuse_texture = Some(insert_texture(textures, &key("base_color"), wrap,
+                || to_rgb(data, |c| [c[0] * base[0], c[1] * base[1], c[2] * base[2]])));
+        }
+        // ラフネスはG, メタリックはBチャンネル
+        if let Some((data, wrap)) = texture(pbr.metallic_roughness_texture().map(|t| t.texture())) {
+            let (roughness, metallic) = (pbr.roughness_factor(), pbr.metallic_factor());
+            material.roughness_texture = Some(insert_texture(textures, &key("roughness"), wrap, || to_rgb(data, |c| [c[1] * roughness; 3])));
+            material.metallic_texture = Some(insert_texture(textures, &key("metallic"), wrap, || to_rgb(data, |c| [c[2] * metallic; 3])));
+        }
+        if let Some((data, wrap)) = texture(m.normal_texture().map(|t| t.texture())) {
+            material.normal_texture = Some(insert_texture(textures, &key("normal"), wrap, || to_rgb(data, |c| [c[0], c[1], c[2]])));
+        }
+        let emissive = m.emissive_factor();
+        if emissive.iter().any(|&e| 0. < e) {
+            material.emission = emission(emissive, m.emissive_strength().unwrap_or(1.));
+        }
+        if let Some(transmission) = m.transmission() {
+            material.transparency = transmission.transmission_factor();
+        }
+        match m.alpha_mode() {
+            AlphaMode::Blend => material.transparency = 1. - base[3],
+            // 切り抜きは基本色のアルファをマスクにする
+            AlphaMode::Mask => if let Some((data, wrap)) = texture(pbr.base_color_texture().map(|t| t.texture())) {
+                material.alpha_texture = Some(insert_texture(textures, &key("alpha"), wrap, || to_rgb(data, |c| [c[3] * base[3]; 3])));
+                material.alpha_test = AlphaTest::Threshold(m.alpha_cutoff().unwrap_or(0.5));
+            },
+            AlphaMode::Opaque => (),
+        }
+        materials.push(material);
+    }
+    materials
+}
+
+/// 同じファイルを何度読み込んでも変換は一度だけにする
+fn insert_texture<F: FnOnce() -> RgbImage>(textures: &mut TextureCache, name: &str, wrap: [WrapMode; 2], f: F) -> TextureId {
+    let id = match textures.id(name) {
+        Some(id) => id,
+        None => textures.insert(name.to_string(), f()),
+    };
+    textures.set_wrap(id, wrap);
+    id
+}
+
+/// サンプラーのu(s), v(t)方向のはみ出したUVの扱い
+fn sampler_wrap(sampler: &gltf::texture::Sampler) -> [WrapMode; 2] {
+    use gltf::texture::WrappingMode;
+    let mode = |mode: WrappingMode| match mode {
+        WrappingMode::ClampToEdge => WrapMode::Clamp,
+        WrappingMode::MirroredRepeat => WrapMode::Mirror,
+        WrappingMode::Repeat => WrapMode::Repeat,
+    };
+    [mode(sampler.wrap_s()), mode(sampler.wrap_t())]
+}
+
+/// glTFの画像を0-1の各画素(RGBA)に`f`を適用してRGB画像にする. アルファがなければ1
+fn to_rgb<F: Fn([f32; 4]) -> [f32; 3]>(data: &gltf::image::Data, f: F) -> RgbImage {
+    use gltf::image::Format;
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let value = |p: usize, c: usize| {
+        let i = (p * channels + c) * bytes;
+        let b = &data.pixels[i..i + bytes];
+        match bytes {
+            1 => b[0] as f32 / 255.,
+            2 => u16::from_ne_bytes([b[0], b[1]]) as f32 / 65535.,
+            _ => f32::from_ne_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    };
+    RgbImage::from_fn(data.width, data.height, |x, y| {
+        let p = (y * data.width + x) as usize;
+        // 1, 2チャンネルは輝度(とアルファ)
+        let alpha = match channels {
+            2 => value(p, 1),
+            4 => value(p, 3),
+            _ => 1.,
+        };
+        let c = if channels < 3 {
+            [value(p, 0), value(p, 0), value(p, 0), alpha]
+        } else {
+            [value(p, 0), value(p, 1), value(p, 2), alpha]
+        };
+        let c = f(c);
+        Rgb([
+            (c[0] * 255.).clamp(0., 255.) as u8,
+            (c[1] * 255.).clamp(0., 255.) as u8,
+            (c[2] * 255.).clamp(0., 255.) as u8,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 三角形1枚を子ノードに持ち, 親ノードを平行移動したシーン
+    const TRIANGLE: &str = r#"{
+        "asset": {"version": "2.0"},
+        "extensionsUsed": ["KHR_lights_punctual"],
+        "extensions": {"KHR_lights_punctual": {"lights": [{"type": "point", "intensity": 1.0}]}},
+        "scene": 0,
+        "scenes": [{"nodes": [0, 2]}],
+        "nodes": [
+            {"translation": [0.0, 0.0, -5.0], "children": [1]},
+            {"mesh": 0, "scale": [2.0, 2.0, 2.0], "extensions": {"KHR_lights_punctual": {"light": 0}}},
+            {"camera": 0, "translation": [0.0, 1.0, 0.0]}
+        ],
+        "cameras": [{"type": "perspective", "perspective": {"yfov": 1.0, "znear": 0.1}}],
+        "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [1.0, 0.0, 0.0, 1.0], "roughnessFactor": 0.25}}],
+        "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "material": 0}]}],
+        "accessors": [{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+            "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]}],
+        "bufferViews": [{"buffer": 0, "byteLength": 36}],
+        "buffers": [{"byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"}]
+    }"#;
+
+    #[test]
+    fn node_hierarchy_materials_camera_and_light() {
+        let (document, buffers, images) = gltf::import_slice(TRIANGLE.as_bytes()).unwrap();
+        let placement = Placement {
+            r: Vector3::new(0., 0., 0.),
+            s: Vector3::new(1., 1., 1.),
+            t: Vector3::new(0., 0., 0.),
+        };
+        let scene = from_document("triangle.gltf", &document, &buffers, &images, placement, &mut TextureCache::new());
+        assert_eq!(scene.objects.len(), 2);
+        let mesh = &scene.objects[0];
+        assert_eq!(mesh.bounds.min, Vector3::new(0., 0., -5.));
+        assert_eq!(mesh.bounds.max, Vector3::new(2., 2., -5.));
+        assert_eq!(mesh.materials[0].diffuse, Color::new(1., 0., 0.));
+        assert_eq!(mesh.materials[0].roughness, 0.25);
+        // 光源は子ノードの位置の発光球
+        let light = &scene.objects[1];
+        assert!(light.is_ec);
+        assert!((light.origin.z + 5.).abs() < 1e-5);
+        let camera = scene.camera.unwrap();
+        assert_eq!(camera.position, Vector3::new(0., 1., 0.));
+        assert_eq!(camera.forward, Vector3::new(0., 0., -1.));
+        assert!((camera.yfov - 1f32.to_degrees()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn unsupported_primitive_is_skipped() {
+        // 同じ頂点を線分として描くプリミティブを先に置く
+        let lines = TRIANGLE.replace(r#""primitives": ["#, r#""primitives": [{"attributes": {"POSITION": 0}, "mode": 1}, "#);
+        let (document, buffers, images) = gltf::import_slice(lines.as_bytes()).unwrap();
+        let placement = Placement {
+            r: Vector3::new(0., 0., 0.),
+            s: Vector3::new(1., 1., 1.),
+            t: Vector3::new(0., 0., 0.),
+        };
+        let scene = from_document("lines.gltf", &document, &buffers, &images, placement, &mut TextureCache::new());
+        assert_eq!(scene.objects.len(), 2);
+        assert_eq!(scene.objects[0].shapes.len(), 1);
+        assert!(scene.camera.is_some());
+    }
+}
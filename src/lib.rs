@@ -0,0 +1,39 @@
+//! パストレーシングによるレンダラ.
+//!
+//! シーンは`SceneBuilder`で組み立てるか, `config::SceneConfig`で読んだscene.jsonから
+//! `loader::build_scene`で作り, `Scene::render`で画像にする.
+
+pub mod scene;
+pub mod vector;
+pub mod object;
+pub mod ray;
+pub mod color;
+pub mod bounds;
+pub mod shape;
+pub mod sdf;
+pub mod csg;
+pub mod gltf_import;
+pub mod ply;
+pub mod stl;
+pub mod config;
+pub mod loader;
+pub mod error;
+pub mod assets;
+pub mod material;
+pub mod microfacet;
+pub mod bsdf;
+pub mod displacement;
+pub mod texture;
+mod bvh;
+mod sampler;
+
+pub use scene::{Camera, Scene, SceneBuilder, RenderSettings};
+pub use vector::{Vector3, Vector2};
+pub use color::Color;
+pub use object::{Object, Plane, Mesh};
+pub use shape::Shape;
+pub use error::{Error, Result, LoadPolicy};
+pub use assets::{Assets, SearchPaths, TextureCache, TextureId};
+pub use material::Material;
+pub use microfacet::Conductor;
+pub use image::RgbImage;
@@ -0,0 +1,215 @@
+use std::path::Path;
+
+use crate::scene::{Camera, Scene};
+use crate::shape::{Shape, Sphere, Disk, Quad, InfinitePlane, Cylinder, Cone, Torus};
+use crate::sdf::{Sdf, SdfShape};
+use crate::csg::{Csg, CsgOp};
+use crate::object::{self, Object, Mesh};
+use crate::config::{SceneConfig, ShapeConfig, SdfConfig, CsgOpConfig, MaterialConfig};
+use crate::material::Material;
+use crate::{gltf_import, stl};
+use crate::error::{Error, Result, warn};
+use crate::assets::{Assets, SearchPaths};
+
+pub fn load_sdf(v: &SdfConfig) -> Sdf {
+    let children = |children: &[SdfConfig]| children.iter().map(load_sdf).collect::<Vec<_>>();
+    let child = |child: &SdfConfig| Box::new(load_sdf(child));
+    match v {
+        SdfConfig::Sphere { radius } => Sdf::Sphere(*radius),
+        SdfConfig::Box { half_extents } => Sdf::Box(*half_extents),
+        SdfConfig::Torus { major_radius, minor_radius } => Sdf::Torus(*major_radius, *minor_radius),
+        SdfConfig::Cylinder { radius, half_height } => Sdf::Cylinder(*radius, *half_height),
+        SdfConfig::Mandelbulb { power, iterations } => Sdf::Mandelbulb(*power, *iterations),
+        SdfConfig::Union { children: c } => Sdf::Union(children(c)),
+        SdfConfig::Intersection { children: c } => Sdf::Intersection(children(c)),
+        SdfConfig::Difference { children: c } => Sdf::Difference(children(c)),
+        SdfConfig::SmoothUnion { k, children: c } => Sdf::SmoothUnion(*k, children(c)),
+        SdfConfig::SmoothIntersection { k, children: c } => Sdf::SmoothIntersection(*k, children(c)),
+        SdfConfig::SmoothDifference { k, children: c } => Sdf::SmoothDifference(*k, children(c)),
+        SdfConfig::Translate { translate, child: c } => Sdf::Translate(*translate, child(c)),
+        SdfConfig::Rotate { rotate, child: c } => Sdf::rotate(rotate, load_sdf(c)),
+        SdfConfig::Scale { scale, child: c } => Sdf::Scale(*scale, child(c)),
+        SdfConfig::Repeat { period, child: c } => Sdf::Repeat(*period, child(c)),
+        SdfConfig::Round { radius, child: c } => Sdf::Round(*radius, child(c)),
+    }
+}
+
+/// `mtl`と`material`で指定した材質を読み込む. `mtl`がなければ`material`の名前の灰色の拡散面
+/// MTLファイルは探索ディレクトリから, テクスチャはMTLファイルのディレクトリから探す
+pub fn load_material(mtl: &Option<String>, name: &Option<String>, assets: &Assets) -> Result<tobj::Material> {
+    let mtl = match (mtl, name) {
+        (Some(mtl), _) => assets.search_paths.resolve(mtl, None),
+        // scene.jsonの`materials`で名前を指定して上書きできるようにする
+        (None, Some(name)) => return Ok(tobj::Material {
+            name: name.clone(),
+            ..object::default_material()
+        }),
+        (None, None) => return Ok(object::default_material()),
+    };
+    let found = match tobj::load_mtl(&mtl) {
+        Ok((mtl_materials, _)) => mtl_materials.into_iter()
+            .find(|m| name.as_ref().is_none_or(|name| &m.name == name))
+            .ok_or_else(|| Error::Parse {
+                path: mtl.clone(),
+                message: format!("no material named {}", name.as_deref().unwrap_or("")),
+            }),
+        Err(source) => Err(Error::Mtl { path: mtl.clone(), source }),
+    };
+    match found {
+        Err(e) if assets.policy.substitute_materials => {
+            warn(&format!("{}, using the default material", e));
+            Ok(object::default_material())
+        },
+        Ok(mut material) => {
+            assets.search_paths.resolve_textures(&mut material, Path::new(&mtl).parent());
+            Ok(material)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// 形状を読み込み, 使う材質を`materials`に追加する. `path`はscene.jsonの中の場所で, 誤りの表示に使う
+pub fn load_shape(v: &ShapeConfig, path: &str, materials: &mut Vec<tobj::Material>, assets: &Assets) -> Result<Box<dyn Shape>> {
+    let (mtl, material) = match v {
+        ShapeConfig::Csg { op, children } => {
+            let op = match op {
+                CsgOpConfig::Union => CsgOp::Union,
+                CsgOpConfig::Intersection => CsgOp::Intersection,
+                CsgOpConfig::Difference => CsgOp::Difference,
+            };
+            let children = children.iter().enumerate()
+                .map(|(i, c)| load_shape(c, &format!("{}.children[{}]", path, i), materials, assets))
+                .collect::<Result<_>>()?;
+            return Ok(Box::new(Csg::new(op, children)));
+        },
+        ShapeConfig::Mesh { name, rotate, scale, translate } => {
+            let name = assets.search_paths.resolve(name, None);
+            let (mut planes, mesh_materials) = object::load_mesh(&name, *rotate, *scale, *translate, assets)?;
+            // 閉じていないとCSGで内外が決まらない
+            if !Mesh::is_closed(&planes) {
+                return Err(Error::Parse {
+                    path: name,
+                    message: format!("mesh at `{}` is not closed, every edge must be shared by two triangles", path),
+                });
+            }
+            let offset = materials.len();
+            for plane in planes.iter_mut() {
+                plane.material_id = plane.material_id.map(|id| id + offset);
+            }
+            materials.extend(mesh_materials);
+            return Ok(Box::new(Mesh::new(planes)));
+        },
+        ShapeConfig::Sphere { mtl, material, .. }
+        | ShapeConfig::Disk { mtl, material, .. }
+        | ShapeConfig::Quad { mtl, material, .. }
+        | ShapeConfig::Plane { mtl, material, .. }
+        | ShapeConfig::Cylinder { mtl, material, .. }
+        | ShapeConfig::Cone { mtl, material, .. }
+        | ShapeConfig::Torus { mtl, material, .. }
+        | ShapeConfig::Sdf { mtl, material, .. } => (mtl, material),
+    };
+    materials.push(load_material(mtl, material, assets)?);
+    let material_id = Some(materials.len() - 1);
+    Ok(match v {
+        ShapeConfig::Sphere { center, radius, .. } => Box::new(Sphere::new(*center, *radius, material_id)),
+        ShapeConfig::Disk { center, normal, radius, .. } => Box::new(Disk::new(*center, *normal, *radius, material_id)),
+        ShapeConfig::Quad { corner, edge1, edge2, .. } => Box::new(Quad::new(*corner, *edge1, *edge2, material_id)),
+        ShapeConfig::Plane { point, normal, .. } => Box::new(InfinitePlane::new(*point, *normal, material_id)),
+        ShapeConfig::Cylinder { base, axis, radius, caps, .. } => Box::new(Cylinder::new(*base, *axis, *radius, *caps, material_id)),
+        ShapeConfig::Cone { base, axis, radius, caps, .. } => Box::new(Cone::new(*base, *axis, *radius, *caps, material_id)),
+        ShapeConfig::Torus { center, axis, major_radius, minor_radius, .. } => Box::new(Torus::new(*center, *axis, *major_radius, *minor_radius, material_id)),
+        ShapeConfig::Sdf { sdf, .. } => Box::new(SdfShape::new(load_sdf(sdf), material_id)),
+        ShapeConfig::Csg { .. } | ShapeConfig::Mesh { .. } => unreachable!(),
+    })
+}
+
+/// scene.jsonの`materials`の値で材質を上書きする
+pub fn override_material(material: &mut Material, config: &MaterialConfig) {
+    if let Some(conductor) = config.conductor {
+        material.conductor = Some(conductor);
+        material.metallic = 1.;
+        material.metallic_texture = None;
+    }
+    if let Some(metallic) = config.metallic {
+        material.metallic = metallic;
+        material.metallic_texture = None;
+    }
+    if let Some(roughness) = config.roughness {
+        material.roughness = roughness;
+        material.roughness_texture = None;
+    }
+    if let Some(normal_format) = config.normal_format {
+        material.normal_format = normal_format;
+    }
+    if let Some(bump_scale) = config.bump_scale {
+        material.bump_scale = bump_scale;
+    }
+    if let Some(alpha_test) = config.alpha_test {
+        material.alpha_test = alpha_test;
+    }
+}
+
+/// scene.jsonのディレクトリ, 設定の`search_paths`, `extra`の順に探す探索ディレクトリ
+pub fn search_paths(scene_file: &str, config: &SceneConfig, extra: &[String]) -> SearchPaths {
+    let scene_dir = Path::new(scene_file).parent().unwrap_or_else(|| Path::new(""));
+    let mut search_paths = SearchPaths::new();
+    search_paths.push(scene_dir);
+    for dir in config.search_paths.iter() {
+        search_paths.push(scene_dir.join(dir));
+    }
+    for dir in extra.iter() {
+        search_paths.push(dir);
+    }
+    search_paths
+}
+
+/// 設定からシーンを組み立てる. メッシュファイルや材質を読み込み, テクスチャはシーンで共有する
+pub fn build_scene(config: &SceneConfig, mut assets: Assets) -> Result<Scene> {
+    let mut objs = Vec::new();
+    let mut gltf_camera = None;
+    for obj in config.objectlist.iter() {
+        let name = assets.search_paths.resolve(&obj.name, None);
+        let name = name.as_str();
+        let (rotate, scale, translate) = (obj.rotate, obj.scale, obj.translate);
+        let lower = name.to_lowercase();
+        if lower.ends_with(".gltf") || lower.ends_with(".glb") {
+            let gltf_scene = gltf_import::import(name, rotate, scale, translate, &mut assets.textures)?;
+            // "camera": trueならglTFのカメラを使う
+            if obj.camera && gltf_camera.is_none() {
+                gltf_camera = gltf_scene.camera;
+            }
+            objs.extend(gltf_scene.objects);
+        } else if lower.ends_with(".stl") {
+            let default = stl::StlOptions::default();
+            let options = stl::StlOptions {
+                weld: obj.weld.unwrap_or(default.weld),
+                crease_angle: obj.crease_angle.unwrap_or(default.crease_angle),
+            };
+            let planes = stl::load_stl(name, rotate, scale, translate, &options)?;
+            let shapes = planes.into_iter().map(|plane| Box::new(plane) as Box<dyn Shape>).collect();
+            let material = load_material(&obj.mtl, &obj.material, &assets)?;
+            objs.push(Object::load(shapes, vec![material], &mut assets)?);
+        } else {
+            objs.push(Object::import(name, rotate, scale, translate, &mut assets)?);
+        }
+    }
+    for (i, shape) in config.shapes.iter().enumerate() {
+        let mut materials = Vec::new();
+        let shape = load_shape(shape, &format!("shapes[{}]", i), &mut materials, &assets)?;
+        objs.push(Object::load(vec![shape], materials, &mut assets)?);
+    }
+    for material in objs.iter_mut().flat_map(|obj| obj.materials.iter_mut()) {
+        if let Some(material_config) = config.materials.get(&material.name) {
+            override_material(material, material_config);
+        }
+    }
+    let camera = &config.camera;
+    let image_size = (camera.image_size[0], camera.image_size[1]);
+    let camera = match gltf_camera {
+        Some(gltf_camera) => gltf_camera.to_camera(image_size),
+        None => Camera::look_at(camera.position, camera.forward, camera.top, camera.fov, image_size),
+    };
+    let mut scene = Scene::with_textures(camera, objs, assets.textures);
+    scene.set_texture_filter(config.camera.texture_filter);
+    Ok(scene)
+}
@@ -1,64 +1,49 @@
-mod scene;
-mod vector;
-mod object;
-mod ray;
-mod color;
+mod cli;
 
-use serde_json::Value;
-use std::fs;
+use std::process;
 
-use vector::Vector3;
-use scene::{Camera, Scene};
+use render::config::SceneConfig;
+use render::loader;
+use render::{Assets, LoadPolicy};
 
 fn main() {
-    let content = fs::read_to_string("./scene.json").unwrap();
-    let v: Value = serde_json::from_str(&content).unwrap();
-    let mut objs = Vec::new();
-    if let Value::Array(objectlist) = &v["objectlist"] {
-        for obj in objectlist.iter() {
-            let rotate = Vector3::new(
-                obj["rotate"][0].to_string().parse::<f32>().unwrap(),
-                obj["rotate"][1].to_string().parse::<f32>().unwrap(),
-                obj["rotate"][2].to_string().parse::<f32>().unwrap(),
-            );
-            let scale = Vector3::new(
-                obj["scale"][0].to_string().parse::<f32>().unwrap(),
-                obj["scale"][1].to_string().parse::<f32>().unwrap(),
-                obj["scale"][2].to_string().parse::<f32>().unwrap(),
-            );
-            let translate = Vector3::new(
-                obj["translate"][0].to_string().parse::<f32>().unwrap(),
-                obj["translate"][1].to_string().parse::<f32>().unwrap(),
-                obj["translate"][2].to_string().parse::<f32>().unwrap(),
-            );
-            if let Some(name) = obj["name"].as_str() {
-                objs.push(object::Object::import(name, rotate, scale, translate));
-            }
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        },
+    };
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if let Some(threads) = args.threads {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            eprintln!("error: {}", e);
+            process::exit(1);
         }
     }
-    let camera = &v["camera"];
-    let num_of_bounce = camera["num_of_bounce"].to_string().parse::<usize>().unwrap();
-    let num_of_diffuse = camera["num_of_diffuse"].to_string().parse::<usize>().unwrap();
-    let sampling = camera["sampling"].to_string().parse::<usize>().unwrap();
-    let position = Vector3::new(
-        camera["position"][0].to_string().parse::<f32>().unwrap(),
-        camera["position"][1].to_string().parse::<f32>().unwrap(),
-        camera["position"][2].to_string().parse::<f32>().unwrap(),
-    );
-    let top = Vector3::new(
-        camera["top"][0].to_string().parse::<f32>().unwrap(),
-        camera["top"][1].to_string().parse::<f32>().unwrap(),
-        camera["top"][2].to_string().parse::<f32>().unwrap(),
-    );
-    let forward = Vector3::new(
-        camera["forward"][0].to_string().parse::<f32>().unwrap(),
-        camera["forward"][1].to_string().parse::<f32>().unwrap(),
-        camera["forward"][2].to_string().parse::<f32>().unwrap(),
-    );
-    let fov: f32 = camera["fov"].to_string().parse::<f32>().unwrap();
-    let image_size = (camera["image_size"][0].to_string().parse::<u32>().unwrap(), camera["image_size"][1].to_string().parse::<u32>().unwrap());
-    let right = top.cross(&forward);
-    let camera = Camera::new(position, top, forward, right, fov, image_size);
-    let scene = Scene::new(camera, objs);
-    scene.render(num_of_bounce, sampling, num_of_diffuse);
-}
\ No newline at end of file
+    let config = match SceneConfig::load(&args.scene, &args.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        },
+    };
+    let policy = if args.allow_missing { LoadPolicy::lenient() } else { LoadPolicy::default() };
+    let assets = Assets::new(loader::search_paths(&args.scene, &config, &args.search_paths), policy);
+    let scene = match loader::build_scene(&config, assets) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        },
+    };
+    let settings = config.camera.render_settings(args.seed);
+    let img = scene.render(&settings);
+    if let Err(e) = img.save(&args.output) {
+        eprintln!("error: can not save {}: {}", args.output, e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,330 @@
+use serde::Deserialize;
+
+use crate::color::Color;
+use crate::assets::{TextureCache, TextureId, TextureSpec, alpha_key};
+use crate::microfacet::Conductor;
+use crate::error::{Error, Result};
+
+/// 法線マップの緑チャンネルの向き
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalMapFormat {
+    /// 緑が+v (OpenGL, Blender, glTF)
+    #[default]
+    OpenGl,
+    /// 緑が-v (DirectX, Unreal)
+    DirectX,
+}
+
+/// 切り抜きテクスチャのアルファで光線を通すかの決め方
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlphaTest {
+    /// アルファがこれより小さいところを通す
+    Threshold(f32),
+    /// アルファの確率で当たる. 半透明の縁がぼける
+    Stochastic,
+}
+impl Default for AlphaTest {
+    fn default() -> AlphaTest {
+        AlphaTest::Threshold(0.5)
+    }
+}
+
+/// 描画に使う材質. 読み込むときに一度だけ作る
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    pub name: String,
+    /// 拡散色(0-1)
+    pub diffuse: Color,
+    pub diffuse_texture: Option<TextureId>,
+    /// ラフネス. テクスチャがあればそちらを使う
+    pub roughness: f32,
+    pub roughness_texture: Option<TextureId>,
+    /// メタリック. テクスチャがあればそちらを使う
+    pub metallic: f32,
+    pub metallic_texture: Option<TextureId>,
+    /// 金属の複素屈折率. あれば`diffuse`の代わりにこれで金属の色を決める
+    pub conductor: Option<Conductor>,
+    /// 誘電体の鏡面反射の強さ. 0.5で反射率4%
+    pub specular: f32,
+    /// 布のような縁の光沢
+    pub sheen: f32,
+    pub sheen_texture: Option<TextureId>,
+    /// 表面のクリア塗装の層
+    pub clearcoat: f32,
+    pub clearcoat_texture: Option<TextureId>,
+    pub clearcoat_roughness: f32,
+    /// 異方性(0-1)と, その向きの回転(0-1で1周)
+    pub anisotropy: f32,
+    pub anisotropy_rotation: f32,
+    /// 透明度
+    pub transparency: f32,
+    /// 屈折率
+    pub ior: f32,
+    /// 内部を距離1進んだときに残る光の割合 (Tf). 白なら吸収しない
+    pub transmission_filter: Color,
+    /// 発光(0-255のスケール)
+    pub emission: Color,
+    /// 接空間の法線マップ
+    pub normal_texture: Option<TextureId>,
+    pub normal_format: NormalMapFormat,
+    /// 高さマップとその強さ. 隣のテクセルとの高さの差に掛けて法線を傾ける
+    pub bump_texture: Option<TextureId>,
+    pub bump_scale: f32,
+    /// 読み込み時にメッシュを動かす変位マップ. 高さは base + gain × 明るさ
+    pub displacement_texture: Option<TextureId>,
+    pub displacement_base: f32,
+    pub displacement_gain: f32,
+    /// 切り抜きのマスク (map_d). 透明なところは光線が素通りする
+    pub alpha_texture: Option<TextureId>,
+    pub alpha_test: AlphaTest,
+}
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            name: String::from("default"),
+            diffuse: Color::new(0.8, 0.8, 0.8),
+            diffuse_texture: None,
+            roughness: 0.,
+            roughness_texture: None,
+            metallic: 0.,
+            metallic_texture: None,
+            conductor: None,
+            specular: 0.5,
+            sheen: 0.,
+            sheen_texture: None,
+            clearcoat: 0.,
+            clearcoat_texture: None,
+            clearcoat_roughness: 0.03,
+            anisotropy: 0.,
+            anisotropy_rotation: 0.,
+            transparency: 0.,
+            ior: 1.5,
+            transmission_filter: Color::ones(),
+            emission: Color::zeros(),
+            normal_texture: None,
+            normal_format: NormalMapFormat::OpenGl,
+            bump_texture: None,
+            bump_scale: 1.,
+            displacement_texture: None,
+            displacement_base: 0.,
+            displacement_gain: 1.,
+            alpha_texture: None,
+            alpha_test: AlphaTest::default(),
+        }
+    }
+}
+impl Material {
+    /// 拡散面
+    pub fn diffuse(color: Color) -> Material {
+        Material {
+            diffuse: color,
+            ..Default::default()
+        }
+    }
+    /// 発光面. `emission`は0-255のスケール
+    pub fn light(emission: Color) -> Material {
+        Material {
+            name: String::from("light"),
+            diffuse: Color::zeros(),
+            emission,
+            ..Default::default()
+        }
+    }
+    pub fn is_emissive(&self) -> bool {
+        0. < self.emission.r || 0. < self.emission.g || 0. < self.emission.b
+    }
+    /// MTLの材質から作る. テクスチャは`textures`に読み込み済みのものだけ使う
+    pub fn compile(material: &tobj::Material, textures: &TextureCache) -> Result<Material> {
+        let number = |key: &str| -> Result<Option<f32>> {
+            material.unknown_param.get(key).map(|value| value.trim().parse::<f32>().map_err(|_| Error::Material {
+                name: material.name.clone(),
+                message: format!("invalid {} `{}`", key, value),
+            })).transpose()
+        };
+        // テクスチャ名の前にはオプションがつくことがある
+        let texture = |name: Option<&String>| name.and_then(|name| textures.id(&TextureSpec::parse(name).file));
+        let roughness = number("Pr")?;
+        let metallic = number("Pm")?;
+        let sheen = number("Ps")?;
+        let clearcoat = number("Pc")?;
+        // 値が書いてあればテクスチャより優先する
+        let param_texture = |value: Option<f32>, key: &str| if value.is_none() { texture(material.unknown_param.get(key)) } else { None };
+        // tobjはdを読んでdissolveに入れる. 既定の1でなければTrより優先する
+        let transparency = if material.dissolve != 1. {
+            1. - material.dissolve
+        } else {
+            number("Tr")?.unwrap_or(0.)
+        };
+        let color = |key: &str| -> Result<Option<Color>> {
+            material.unknown_param.get(key).map(|value| {
+                let rgb = value.split_whitespace().map(|s| s.parse::<f32>().ok()).collect::<Option<Vec<_>>>();
+                match rgb.as_deref() {
+                    Some(&[r, g, b]) => Ok(Color::new(r, g, b)),
+                    _ => Err(Error::Material {
+                        name: material.name.clone(),
+                        message: format!("invalid {} `{}`, expected 3 numbers", key, value),
+                    }),
+                }
+            }).transpose()
+        };
+        // 金属はプリセット名 (conductor) か複素屈折率 (conductor_eta, conductor_k) で指定する
+        let conductor = match (material.unknown_param.get("conductor"), color("conductor_eta")?, color("conductor_k")?) {
+            (Some(name), _, _) => Some(Conductor::preset(name.trim()).ok_or_else(|| Error::Material {
+                name: material.name.clone(),
+                message: format!("unknown conductor `{}`, expected one of {}", name, Conductor::PRESETS.join(", ")),
+            })?),
+            (None, Some(eta), Some(k)) => Some(Conductor { eta, k }),
+            (None, None, None) => None,
+            _ => return Err(Error::Material {
+                name: material.name.clone(),
+                message: String::from("conductor_eta and conductor_k must be given together"),
+            }),
+        };
+        let bump = TextureSpec::parse(&material.normal_texture);
+        let disp_range = match material.unknown_param.get("disp").and_then(|disp| TextureSpec::parse(disp).numbers("-mm")).as_deref() {
+            Some(&[base]) => (base, 1.),
+            Some(&[base, gain]) => (base, gain),
+            _ => (0., 1.),
+        };
+        let normal_format = match material.unknown_param.get("norm_format").map(|s| s.trim()) {
+            None | Some("opengl") | Some("gl") => NormalMapFormat::OpenGl,
+            Some("directx") | Some("dx") => NormalMapFormat::DirectX,
+            Some(value) => return Err(Error::Material {
+                name: material.name.clone(),
+                message: format!("invalid norm_format `{}`, expected opengl or directx", value),
+            }),
+        };
+        let alpha_test = match material.unknown_param.get("alpha_test").map(|s| s.trim()) {
+            None => AlphaTest::default(),
+            Some("stochastic") => AlphaTest::Stochastic,
+            Some(value) => AlphaTest::Threshold(value.parse().map_err(|_| Error::Material {
+                name: material.name.clone(),
+                message: format!("invalid alpha_test `{}`, expected stochastic or a threshold", value),
+            })?),
+        };
+        Ok(Material {
+            name: material.name.clone(),
+            diffuse: Color::from_list(material.diffuse),
+            diffuse_texture: texture(Some(&material.diffuse_texture)),
+            roughness: roughness.unwrap_or(0.),
+            roughness_texture: param_texture(roughness, "map_Pr"),
+            // 金属を指定したらPmがなくても金属にする
+            metallic: metallic.unwrap_or(if conductor.is_some() { 1. } else { 0. }),
+            metallic_texture: param_texture(metallic, "map_Pm"),
+            conductor,
+            // Ksがなければ既定の0.5
+            specular: if material.specular.iter().any(|&ks| 0. < ks) {
+                material.specular.iter().sum::<f32>() / 3.
+            } else {
+                0.5
+            },
+            sheen: sheen.unwrap_or(0.),
+            sheen_texture: param_texture(sheen, "map_Ps"),
+            clearcoat: clearcoat.unwrap_or(0.),
+            clearcoat_texture: param_texture(clearcoat, "map_Pc"),
+            clearcoat_roughness: number("Pcr")?.unwrap_or(0.03),
+            anisotropy: number("aniso")?.unwrap_or(0.),
+            anisotropy_rotation: number("anisor")?.unwrap_or(0.),
+            transparency,
+            ior: material.optical_density,
+            transmission_filter: color("Tf")?.unwrap_or_else(Color::ones),
+            emission: color("Ec")?.unwrap_or_else(Color::zeros),
+            normal_texture: texture(material.unknown_param.get("norm")),
+            normal_format,
+            // tobjはmap_Bumpとbumpをnormal_textureに入れる
+            bump_texture: texture(Some(&material.normal_texture)),
+            bump_scale: bump.numbers("-bm").and_then(|bm| bm.first().copied()).unwrap_or(1.),
+            displacement_texture: texture(material.unknown_param.get("disp")),
+            displacement_base: disp_range.0,
+            displacement_gain: disp_range.1,
+            // マスクは色のテクスチャとは別の名前で読んである
+            alpha_texture: textures.id(&alpha_key(&TextureSpec::parse(&material.dissolve_texture).file)),
+            alpha_test,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn compile_mtl_parameters() {
+        let mut textures = TextureCache::new();
+        let id = textures.insert(String::from("kd.png"), RgbImage::new(1, 1));
+        let mut mtl = tobj::Material {
+            name: String::from("m"),
+            diffuse: [0.5, 0.25, 1.],
+            diffuse_texture: String::from("kd.png"),
+            optical_density: 1.33,
+            ..Default::default()
+        };
+        mtl.unknown_param.insert(String::from("Pr"), String::from("0.3"));
+        mtl.unknown_param.insert(String::from("map_Pr"), String::from("kd.png"));
+        mtl.unknown_param.insert(String::from("map_Pm"), String::from("kd.png"));
+        mtl.unknown_param.insert(String::from("Tr"), String::from("0.25"));
+        mtl.unknown_param.insert(String::from("Ec"), String::from("10 20 30"));
+        mtl.unknown_param.insert(String::from("Pc"), String::from("1"));
+        mtl.unknown_param.insert(String::from("Pcr"), String::from("0.2"));
+        mtl.unknown_param.insert(String::from("aniso"), String::from("0.5"));
+        mtl.unknown_param.insert(String::from("map_Ps"), String::from("kd.png"));
+        let m = Material::compile(&mtl, &textures).unwrap();
+        assert_eq!(m.diffuse, Color::new(0.5, 0.25, 1.));
+        assert_eq!(m.diffuse_texture, Some(id));
+        assert_eq!((m.roughness, m.roughness_texture), (0.3, None));
+        assert_eq!(m.metallic_texture, Some(id));
+        assert_eq!((m.transparency, m.ior), (0.25, 1.33));
+        assert_eq!(m.transmission_filter, Color::ones());
+        assert!(m.is_emissive());
+        assert_eq!((m.specular, m.sheen_texture), (0.5, Some(id)));
+        assert_eq!((m.clearcoat, m.clearcoat_roughness, m.anisotropy), (1., 0.2, 0.5));
+
+        mtl.unknown_param.insert(String::from("Pm"), String::from("shiny"));
+        let e = Material::compile(&mtl, &textures).unwrap_err();
+        assert!(e.to_string().contains("invalid Pm `shiny`"), "{}", e);
+        mtl.unknown_param.remove("Pm");
+        mtl.unknown_param.insert(String::from("Ec"), String::from("1 2"));
+        assert!(Material::compile(&mtl, &textures).is_err());
+        mtl.unknown_param.remove("Ec");
+        mtl.dissolve = 0.2;
+        assert!((Material::compile(&mtl, &textures).unwrap().transparency - 0.8).abs() < 1e-6);
+        mtl.dissolve = 1.;
+        mtl.unknown_param.insert(String::from("Tf"), String::from("0.5 1 1"));
+        assert_eq!(Material::compile(&mtl, &textures).unwrap().transmission_filter, Color::new(0.5, 1., 1.));
+
+        mtl.unknown_param.insert(String::from("norm"), String::from("kd.png"));
+        mtl.unknown_param.insert(String::from("norm_format"), String::from("dx"));
+        let m = Material::compile(&mtl, &textures).unwrap();
+        assert_eq!((m.normal_texture, m.normal_format), (Some(id), NormalMapFormat::DirectX));
+        mtl.normal_texture = String::from("-bm 0.25 kd.png");
+        mtl.unknown_param.insert(String::from("disp"), String::from("-mm -0.1 0.5 kd.png"));
+        let m = Material::compile(&mtl, &textures).unwrap();
+        assert_eq!((m.bump_texture, m.bump_scale), (Some(id), 0.25));
+        assert_eq!((m.displacement_texture, m.displacement_base, m.displacement_gain), (Some(id), -0.1, 0.5));
+
+        mtl.unknown_param.insert(String::from("conductor"), String::from("copper"));
+        let m = Material::compile(&mtl, &textures).unwrap();
+        assert_eq!((m.conductor, m.metallic), (Conductor::preset("copper"), 1.));
+        mtl.unknown_param.insert(String::from("conductor"), String::from("brass"));
+        let e = Material::compile(&mtl, &textures).unwrap_err();
+        assert!(e.to_string().contains("unknown conductor `brass`"), "{}", e);
+        mtl.unknown_param.remove("conductor");
+        mtl.unknown_param.insert(String::from("conductor_eta"), String::from("1 1 1"));
+        assert!(Material::compile(&mtl, &textures).is_err());
+        mtl.unknown_param.insert(String::from("conductor_k"), String::from("2 2 2"));
+        assert_eq!(Material::compile(&mtl, &textures).unwrap().conductor, Some(Conductor { eta: Color::ones(), k: Color::ones() * 2. }));
+
+        let mask = textures.insert(alpha_key("kd.png"), RgbImage::new(1, 1));
+        mtl.dissolve_texture = String::from("kd.png");
+        mtl.unknown_param.insert(String::from("alpha_test"), String::from("stochastic"));
+        let m = Material::compile(&mtl, &textures).unwrap();
+        assert_eq!((m.alpha_texture, m.alpha_test), (Some(mask), AlphaTest::Stochastic));
+        mtl.unknown_param.insert(String::from("alpha_test"), String::from("0.25"));
+        assert_eq!(Material::compile(&mtl, &textures).unwrap().alpha_test, AlphaTest::Threshold(0.25));
+        mtl.unknown_param.insert(String::from("alpha_test"), String::from("half"));
+        assert!(Material::compile(&mtl, &textures).is_err());
+    }
+}
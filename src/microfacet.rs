@@ -0,0 +1,256 @@
+use std::f32::consts::PI;
+
+use crate::vector::{Vector3, Vector2};
+use crate::color::Color;
+
+/// これより小さいαは数値的に不安定なので丸める
+const MIN_ALPHA: f32 = 1e-3;
+
+/// 法線をz軸とする局所座標
+#[derive(Debug, Copy, Clone)]
+pub struct Frame {
+    pub s: Vector3,
+    pub t: Vector3,
+    pub n: Vector3,
+}
+impl Frame {
+    /// `n`は正規化済み
+    pub fn new(n: Vector3) -> Frame {
+        let (s, t) = n.orthonormal_basis();
+        Frame {
+            s,
+            t,
+            n,
+        }
+    }
+    pub fn to_local(&self, v: &Vector3) -> Vector3 {
+        Vector3::new(v.inner(&self.s), v.inner(&self.t), v.inner(&self.n))
+    }
+    pub fn to_world(&self, v: &Vector3) -> Vector3 {
+        self.s * v.x + self.t * v.y + self.n * v.z
+    }
+}
+
+/// Schlickの近似によるフレネル反射率
+pub fn schlick(f0: Color, cos_theta: f32) -> Color {
+    let m = (1. - cos_theta.clamp(0., 1.)).powi(5);
+    f0 + (Color::ones() - f0) * m
+}
+
+/// 誘電体の境界での偏光を平均したフレネル反射率. `eta`は入射側/透過側の屈折率の比で, 全反射なら1
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i);
+    if 1. <= sin2_t {
+        return 1.;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (eta * cos_t - cos_i) / (eta * cos_t + cos_i);
+    (rs * rs + rp * rp) / 2.
+}
+
+/// 金属の複素屈折率 eta + ik (RGBそれぞれ)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+}
+impl Conductor {
+    pub const PRESETS: [&'static str; 5] = ["gold", "copper", "aluminium", "silver", "chrome"];
+    /// 名前つきの金属. 値は赤650nm, 緑550nm, 青450nmあたりのもの
+    pub fn preset(name: &str) -> Option<Conductor> {
+        let (eta, k) = match name {
+            "gold" => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+            "copper" => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+            "aluminium" | "aluminum" => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+            "silver" => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+            "chrome" => ([3.175, 3.181, 2.322], [3.306, 3.331, 3.125]),
+            _ => return None,
+        };
+        Some(Conductor {
+            eta: Color::from_list(eta),
+            k: Color::from_list(k),
+        })
+    }
+    /// 偏光を平均したフレネル反射率
+    pub fn fresnel(&self, cos_i: f32) -> Color {
+        Color::new(
+            fresnel_conductor(cos_i, self.eta.r, self.k.r),
+            fresnel_conductor(cos_i, self.eta.g, self.k.g),
+            fresnel_conductor(cos_i, self.eta.b, self.k.b),
+        )
+    }
+}
+
+fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i.clamp(0., 1.).powi(2);
+    let sin2 = 1. - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2b2 = (t0 * t0 + 4. * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2b2 + t0)).max(0.).sqrt();
+    let t1 = a2b2 + cos2;
+    let t2 = 2. * cos_i.clamp(0., 1.) * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    (rs + rp) / 2.
+}
+
+/// GGX (Trowbridge-Reitz) 分布とSmithの遮蔽関数. ベクトルは全て局所座標で正規化済み.
+/// `alpha_x`, `alpha_y`は局所座標のx, y方向の粗さ
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ggx {
+    pub alpha_x: f32,
+    pub alpha_y: f32,
+}
+impl Ggx {
+    /// 見た目が線形に変わるようにα = roughness²とする
+    pub fn from_roughness(roughness: f32) -> Ggx {
+        Ggx::anisotropic(roughness, 0.)
+    }
+    /// `anisotropy`が大きいほどx方向に伸びたハイライトになる (Disneyの対応付け)
+    pub fn anisotropic(roughness: f32, anisotropy: f32) -> Ggx {
+        let aspect = (1. - 0.9 * anisotropy.clamp(0., 1.)).sqrt();
+        let alpha = roughness * roughness;
+        Ggx {
+            alpha_x: (alpha / aspect).max(MIN_ALPHA),
+            alpha_y: (alpha * aspect).max(MIN_ALPHA),
+        }
+    }
+    /// 鏡面とみなせるほど滑らか
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x <= MIN_ALPHA && self.alpha_y <= MIN_ALPHA
+    }
+    /// 法線分布 D(h)
+    pub fn d(&self, h: &Vector3) -> f32 {
+        if h.z <= 0. {
+            return 0.;
+        }
+        let (ax, ay) = (self.alpha_x, self.alpha_y);
+        let t = (h.x * h.x) / (ax * ax) + (h.y * h.y) / (ay * ay) + h.z * h.z;
+        1. / (PI * ax * ay * t * t)
+    }
+    fn lambda(&self, w: &Vector3) -> f32 {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0. {
+            return f32::INFINITY;
+        }
+        let (ax, ay) = (self.alpha_x, self.alpha_y);
+        let t = (ax * ax * w.x * w.x + ay * ay * w.y * w.y) / cos2;
+        ((1. + t).sqrt() - 1.) / 2.
+    }
+    /// 片方向の遮蔽 G1(w)
+    pub fn g1(&self, w: &Vector3) -> f32 {
+        1. / (1. + self.lambda(w))
+    }
+    /// 高さ相関のある遮蔽 G2(wo, wi)
+    pub fn g2(&self, wo: &Vector3, wi: &Vector3) -> f32 {
+        1. / (1. + self.lambda(wo) + self.lambda(wi))
+    }
+    /// `wo`から見える法線を分布 D_wo(h) = G1(wo) max(0, wo・h) D(h) / wo.z に従って選ぶ (Heitz 2018)
+    pub fn sample_vndf(&self, wo: &Vector3, u: &Vector2) -> Vector3 {
+        // 半球に引き伸ばした座標で考える
+        let v = Vector3::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalize();
+        let len2 = v.x * v.x + v.y * v.y;
+        let t1 = if 0. < len2 {
+            Vector3::new(-v.y, v.x, 0.) / len2.sqrt()
+        } else {
+            Vector3::new(1., 0., 0.)
+        };
+        let t2 = v.cross(&t1);
+        let r = u.x.sqrt();
+        let phi = 2. * PI * u.y;
+        let p1 = r * phi.cos();
+        let s = (1. + v.z) / 2.;
+        let p2 = (1. - s) * (1. - p1 * p1).max(0.).sqrt() + s * r * phi.sin();
+        let p3 = (1. - p1 * p1 - p2 * p2).max(0.).sqrt();
+        let n = t1 * p1 + t2 * p2 + v * p3;
+        Vector3::new(self.alpha_x * n.x, self.alpha_y * n.y, n.z.max(0.)).normalize()
+    }
+    /// フレネルを除いたBRDF D G2 / (4 cosθo cosθi)
+    pub fn eval(&self, wo: &Vector3, wi: &Vector3) -> f32 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return 0.;
+        }
+        let h = (*wo + *wi).normalize();
+        self.d(&h) * self.g2(wo, wi) / (4. * wo.z * wi.z)
+    }
+    /// `sample`で`wi`を選ぶ確率密度
+    pub fn pdf(&self, wo: &Vector3, wi: &Vector3) -> f32 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return 0.;
+        }
+        let h = (*wo + *wi).normalize();
+        self.g1(wo) * self.d(&h) / (4. * wo.z)
+    }
+    /// 反射方向, 選んだ微小面の法線, 重み BRDF cosθi / pdf = G2 / G1(wo) を返す. 重みにフレネルは含まない
+    pub fn sample(&self, wo: &Vector3, u: &Vector2) -> Option<(Vector3, Vector3, f32)> {
+        if wo.z <= 0. {
+            return None;
+        }
+        let h = self.sample_vndf(wo, u);
+        let wi = h * (2. * wo.inner(&h)) - *wo;
+        if wi.z <= 0. {
+            return None;
+        }
+        Some((wi, h, self.g2(wo, &wi) / self.g1(wo)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler;
+
+    /// 上半球を等間隔に区切って積分する
+    fn integrate<F: Fn(&Vector3) -> f32>(f: F) -> f32 {
+        let (n_theta, n_phi) = (256, 512);
+        let (d_theta, d_phi) = (PI / 2. / n_theta as f32, 2. * PI / n_phi as f32);
+        let mut sum = 0.;
+        for i in 0..n_theta {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..n_phi {
+                let phi = (j as f32 + 0.5) * d_phi;
+                let w = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                sum += f(&w) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn conductor_fresnel() {
+        let gold = Conductor::preset("gold").unwrap();
+        // 垂直入射では ((n-1)²+k²)/((n+1)²+k²)
+        let (n, k) = (gold.eta.r, gold.k.r);
+        let normal = ((n - 1.).powi(2) + k * k) / ((n + 1.).powi(2) + k * k);
+        assert!((gold.fresnel(1.).r - normal).abs() < 1e-4);
+        // 金は赤が青より強く反射し, 浅い角度では白くなる
+        assert!(gold.fresnel(1.).b < gold.fresnel(1.).r);
+        assert!(0.99 < gold.fresnel(0.).b);
+        assert_eq!(Conductor::preset("unobtainium"), None);
+    }
+
+    #[test]
+    fn ggx_is_normalized_and_sampling_matches_pdf() {
+        let wo = Vector3::new(0.6, 0., 0.8);
+        for &(roughness, anisotropy) in [(0.3, 0.), (0.7, 0.), (0.5, 0.8)].iter() {
+            let ggx = Ggx::anisotropic(roughness, anisotropy);
+            // 投影した法線分布の積分は1
+            assert!((integrate(|h| ggx.d(h) * h.z) - 1.).abs() < 1e-2);
+            // pdfの積分は反射が下半球に出る分だけ1より小さい
+            let total = integrate(|wi| ggx.pdf(&wo, wi));
+            assert!(total <= 1.01 && 0.8 < total, "{}", total);
+            // 重みの期待値は方向アルベド ∫ f cosθi dωi に一致する
+            let albedo = integrate(|wi| ggx.eval(&wo, wi) * wi.z);
+            let n = 100000;
+            let estimate = sampler::scoped(Some(3), || (0..n).map(|_| {
+                let u = Vector2::new(sampler::uniform(), sampler::uniform());
+                ggx.sample(&wo, &u).map_or(0., |(_, _, weight)| weight)
+            }).sum::<f32>()) / n as f32;
+            assert!(albedo <= 1. && (estimate - albedo).abs() < 1e-2, "{} {}", estimate, albedo);
+        }
+    }
+}
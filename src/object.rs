@@ -1,12 +1,23 @@
-use tobj;
-use image::RgbImage;
-use image::io::Reader as ImageReader;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 
 use crate::vector::{Vector3, Vector2};
 use crate::ray::Ray;
 use crate::color::Color;
+use crate::bvh::Bvh;
+use crate::bounds::Bounds3;
+use crate::shape::{Shape, Surface, Crossing, Crossings, degenerate_surface};
+use crate::ply;
+use crate::stl;
+use crate::error::{Error, Result, warn};
+use crate::assets::Assets;
+use crate::material::Material;
+use crate::displacement;
 
+pub const MIN_RANGE: f32 = 0.0001;
 pub const MAX_RANGE: f32 = 10000.;
 
 #[derive(Debug)]
@@ -25,6 +36,7 @@ impl Texcoord {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct Normcoord {
     pub v1: Vector3,
@@ -41,6 +53,23 @@ impl Normcoord {
     }
 }
 
+/// 三角形の頂点カラー
+#[derive(Debug)]
+pub struct Colorcoord {
+    pub v1: Color,
+    pub v2: Color,
+    pub v3: Color,
+}
+impl Colorcoord {
+    pub fn new(v1: Color, v2: Color, v3: Color) -> Colorcoord {
+        Colorcoord {
+            v1,
+            v2,
+            v3,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Plane {
     v1: Vector3,
@@ -49,7 +78,7 @@ pub struct Plane {
     pub vn: Option<Normcoord>,
     pub vt: Option<Texcoord>,
     pub material_id: Option<usize>,
-
+    pub vc: Option<Colorcoord>,
 }
 impl Plane {
     pub fn new(v1: Vector3, v2: Vector3, v3: Vector3, vn: Option<Normcoord>, vt: Option<Texcoord>, material_id: Option<usize>) -> Plane {
@@ -60,9 +89,28 @@ impl Plane {
             vn,
             vt,
             material_id,
+            vc: None,
         }
     }
-    pub fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+    pub fn vertices(&self) -> [Vector3; 3] {
+        [self.v1, self.v2, self.v3]
+    }
+    /// UVから求めた位置のu, vについての微分 (dp/du, dp/dv). UVがないか潰れていればNone
+    pub fn tangent(&self) -> Option<(Vector3, Vector3)> {
+        let vt = self.vt.as_ref()?;
+        let (dp1, dp2) = (self.v2 - self.v1, self.v3 - self.v1);
+        let (duv1, duv2) = (vt.v2 - vt.v1, vt.v3 - vt.v1);
+        let det = duv1.x * duv2.y - duv2.x * duv1.y;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let tangent = (dp1 * duv2.y - dp2 * duv1.y) / det;
+        let bitangent = (dp2 * duv1.x - dp1 * duv2.x) / det;
+        Some((tangent, bitangent))
+    }
+}
+impl Shape for Plane {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
         let e1 = self.v2 - self.v1;
         let e2 = self.v3 - self.v1;
         let r = ray.origin - self.v1;
@@ -72,214 +120,507 @@ impl Plane {
             r.cross(&e1).inner(&ray.direction),
         );
         let t = vec/ray.direction.cross(&e2).inner(&e1);
-        if 0.0001 < t.x && 0. <= t.y && 0. <= t.z && t.y + t.z <= 1. && t.x < MAX_RANGE {
+        if MIN_RANGE < t.x && 0. <= t.y && 0. <= t.z && t.y + t.z <= 1. && t.x < MAX_RANGE {
             Some(t)
         } else {
             None
         }
     }
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::from_points(&[self.v1, self.v2, self.v3])
+    }
+    fn area(&self) -> f32 {
+        let n = (self.v2 - self.v1).cross(&(self.v3 - self.v1));
+        n.inner(&n).sqrt() / 2.
+    }
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let s = u.x.sqrt();
+        self.v1 * (1. - s) + self.v2 * (s * (1. - u.y)) + self.v3 * (s * u.y)
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let (b1, b2) = (hit.y, hit.z);
+        let b0 = 1. - b1 - b2;
+        let geometric_normal = (self.v2 - self.v1).cross(&(self.v3 - self.v1)).normalize();
+        // 頂点法線を重心座標で補間する. 頂点の並びが法線と逆でも面の法線を同じ側に向ける
+        let (normal, geometric_normal) = match &self.vn {
+            Some(vn) => {
+                let normal = (vn.v1 * b0 + vn.v2 * b1 + vn.v3 * b2).normalize();
+                if normal.inner(&geometric_normal) < 0. {
+                    (normal, geometric_normal * -1.)
+                } else {
+                    (normal, geometric_normal)
+                }
+            },
+            None => (geometric_normal, geometric_normal),
+        };
+        let uv = self.vt.as_ref().map(|vt| vt.v1 * b0 + vt.v2 * b1 + vt.v3 * b2);
+        let color = self.vc.as_ref().map(|vc| vc.v1 * b0 + vc.v2 * b1 + vc.v3 * b2);
+        Surface {
+            point: ray.origin + ray.direction * hit.x,
+            normal,
+            geometric_normal,
+            uv,
+            material_id: self.material_id,
+            color,
+            tangent: self.tangent(),
+            footprint: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Object {
-    pub planes: Vec<Plane>,
+    pub shapes: Vec<Box<dyn Shape>>,
     pub origin: Vector3,
-    pub radius: f32,
-    pub materials: Vec<tobj::Material>,
-    pub image: HashMap<String, RgbImage>,
+    pub bounds: Bounds3,
+    pub materials: Vec<Material>,
     pub is_ec: bool,
+    /// 切り抜きのマスクを持つ材質がある
+    pub is_cutout: bool,
+    /// 全ての形状から表面の点を一様に選べる
+    pub is_samplable: bool,
+    bvh: Bvh,
+    area_cdf: Vec<f32>,
 }
-impl Object {
-    pub fn import(obj_file: &str, r: Vector3, s: Vector3, t: Vector3) -> Object {
-        let cornell_box = tobj::load_obj(
-            obj_file,
-            &tobj::LoadOptions {
-                single_index: false,
-                triangulate: true,
-                ..Default::default()
-            },
-        );
-        let (models, materials) = cornell_box.expect("CAN NOT OPEN OBJ FILE");
-        let materials = materials.expect("CAN NOT OPEN MTL FILE");
-        let mut max_radius: f32 = 0.;
-        let mut planes = Vec::new();
-        let mut image = HashMap::new();
-        let mut is_ec = false;
-        for model in models.iter() {
-            for i in 0..model.mesh.indices.len() / 3 {
-                let j = model.mesh.indices[3*i] as usize;
-                let x = model.mesh.positions[3*j];
-                let y = model.mesh.positions[3*j+1];
-                let z = model.mesh.positions[3*j+2];
-                let mut v1 = Vector3::new(x,y,z);
-                v1 = v1.rotate(&r);
-                v1 = v1.scale(&s);
-                v1 = v1.translate(&t);
-                let j = model.mesh.indices[3*i+1] as usize;
-                let x = model.mesh.positions[3*j];
-                let y = model.mesh.positions[3*j+1];
-                let z = model.mesh.positions[3*j+2];
-                let mut v2 = Vector3::new(x,y,z);
-                v2 = v2.rotate(&r);
-                v2 = v2.scale(&s);
-                v2 = v2.translate(&t);
-                let j = model.mesh.indices[3*i+2] as usize;
-                let x = model.mesh.positions[3*j];
-                let y = model.mesh.positions[3*j+1];
-                let z = model.mesh.positions[3*j+2];
-                let mut v3 = Vector3::new(x,y,z);
-                v3 = v3.rotate(&r);
-                v3 = v3.scale(&s);
-                v3 = v3.translate(&t);
-                if max_radius < (v1.x*v1.x + v1.y*v1.y + v1.z*v1.z).sqrt() {
-                    max_radius = (v1.x*v1.x + v1.y*v1.y + v1.z*v1.z).sqrt();
-                }
-                if max_radius < (v2.x*v2.x + v2.y*v2.y + v2.z*v2.z).sqrt() {
-                    max_radius = (v2.x*v2.x + v2.y*v2.y + v2.z*v2.z).sqrt();
-                }
-                if max_radius < (v3.x*v3.x + v3.y*v3.y + v3.z*v3.z).sqrt() {
-                    max_radius = (v3.x*v3.x + v3.y*v3.y + v3.z*v3.z).sqrt();
-                }
-                let mut vn: Option<Normcoord> = None;
-                if !model.mesh.normals.is_empty() {
-                    let j = model.mesh.normal_indices[3*i] as usize;
-                    let x = model.mesh.normals[3*j];
-                    let y = model.mesh.normals[3*j+1];
-                    let z = model.mesh.normals[3*j+2];
-                    let mut vn1 = Vector3::new(x, y, z);
-                    vn1 = vn1.rotate(&r);
-                    let j = model.mesh.normal_indices[3*i+1] as usize;
-                    let x = model.mesh.normals[3*j];
-                    let y = model.mesh.normals[3*j+1];
-                    let z = model.mesh.normals[3*j+2];
-                    let mut vn2 = Vector3::new(x, y, z);
-                    vn2 = vn2.rotate(&r);
-                    let j = model.mesh.normal_indices[3*i+2] as usize;
-                    let x = model.mesh.normals[3*j];
-                    let y = model.mesh.normals[3*j+1];
-                    let z = model.mesh.normals[3*j+2];
-                    let mut vn3 = Vector3::new(x, y, z);
-                    vn3 = vn3.rotate(&r);
-                    vn = Some(Normcoord::new(vn1, vn2, vn3));
-                }
-                let mut vt: Option<Texcoord> = None;
-                if !model.mesh.texcoords.is_empty() {
-                    let j = model.mesh.texcoord_indices[3*i] as usize;
-                    let x = model.mesh.texcoords[2*j];
-                    let y = model.mesh.texcoords[2*j+1];
-                    let vt1 = Vector2::new(x, y);
-                    let j = model.mesh.texcoord_indices[3*i+1] as usize;
-                    let x = model.mesh.texcoords[2*j];
-                    let y = model.mesh.texcoords[2*j+1];
-                    let vt2 = Vector2::new(x, y);
-                    let j = model.mesh.texcoord_indices[3*i+2] as usize;
-                    let x = model.mesh.texcoords[2*j];
-                    let y = model.mesh.texcoords[2*j+1];
-                    let vt3 = Vector2::new(x, y);
-                    vt = Some(Texcoord::new(vt1, vt2, vt3));
-                }
-                planes.push(Plane::new(v1,v2,v3,vn,vt,model.mesh.material_id));
+/// 拡張子に応じてメッシュファイルを読み込む
+pub fn load_mesh(mesh_file: &str, r: Vector3, s: Vector3, t: Vector3, assets: &Assets) -> Result<(Vec<Plane>, Vec<tobj::Material>)> {
+    let lower = mesh_file.to_lowercase();
+    if lower.ends_with(".ply") {
+        ply::load_ply(mesh_file, r, s, t)
+    } else if lower.ends_with(".stl") {
+        let material = tobj::Material {
+            name: String::from("stl"),
+            diffuse: [0.8, 0.8, 0.8],
+            ..Default::default()
+        };
+        Ok((stl::load_stl(mesh_file, r, s, t, &stl::StlOptions::default())?, vec![material]))
+    } else {
+        load_obj(mesh_file, r, s, t, assets)
+    }
+}
+
+/// 既定の材質. 灰色の拡散面
+pub fn default_material() -> tobj::Material {
+    tobj::Material {
+        name: String::from("default"),
+        diffuse: [0.8, 0.8, 0.8],
+        ..Default::default()
+    }
+}
+
+/// OBJファイルを読み込み, 回転・拡大・平行移動した三角形と材質を返す.
+/// MTLファイルはOBJファイルのディレクトリ, テクスチャはMTLファイルのディレクトリから探す
+pub fn load_obj(obj_file: &str, r: Vector3, s: Vector3, t: Vector3, assets: &Assets) -> Result<(Vec<Plane>, Vec<tobj::Material>)> {
+    let file = File::open(obj_file).map_err(|source| Error::Io { path: obj_file.to_string(), source })?;
+    let obj_dir = Path::new(obj_file).parent();
+    let mtl_file = RefCell::new(PathBuf::new());
+    let cornell_box = tobj::load_obj_buf(
+        &mut BufReader::new(file),
+        &tobj::LoadOptions {
+            single_index: false,
+            triangulate: true,
+            ..Default::default()
+        },
+        |mtl_path| {
+            let mtl_path = assets.search_paths.resolve(&mtl_path.to_string_lossy(), obj_dir);
+            mtl_file.replace(PathBuf::from(&mtl_path));
+            tobj::load_mtl(mtl_path)
+        },
+    );
+    let (models, materials) = cornell_box.map_err(|source| Error::Obj { path: obj_file.to_string(), source })?;
+    let mtl_file = mtl_file.into_inner();
+    // MTLが読めなければ全ての面を既定の材質にする
+    let (mut materials, substituted) = match materials {
+        Ok(materials) => (materials, false),
+        Err(source) if assets.policy.substitute_materials => {
+            warn(&format!("can not open MTL file {}: {}, using the default material", mtl_file.display(), source));
+            (vec![default_material()], true)
+        },
+        Err(source) => return Err(Error::Mtl { path: mtl_file.to_string_lossy().into_owned(), source }),
+    };
+    for material in materials.iter_mut() {
+        assets.search_paths.resolve_textures(material, mtl_file.parent());
+    }
+    let mut planes = Vec::new();
+    for model in models.iter() {
+        for i in 0..model.mesh.indices.len() / 3 {
+            let j = model.mesh.indices[3*i] as usize;
+            let x = model.mesh.positions[3*j];
+            let y = model.mesh.positions[3*j+1];
+            let z = model.mesh.positions[3*j+2];
+            let mut v1 = Vector3::new(x,y,z);
+            v1 = v1.rotate(&r);
+            v1 = v1.scale(&s);
+            v1 = v1.translate(&t);
+            let j = model.mesh.indices[3*i+1] as usize;
+            let x = model.mesh.positions[3*j];
+            let y = model.mesh.positions[3*j+1];
+            let z = model.mesh.positions[3*j+2];
+            let mut v2 = Vector3::new(x,y,z);
+            v2 = v2.rotate(&r);
+            v2 = v2.scale(&s);
+            v2 = v2.translate(&t);
+            let j = model.mesh.indices[3*i+2] as usize;
+            let x = model.mesh.positions[3*j];
+            let y = model.mesh.positions[3*j+1];
+            let z = model.mesh.positions[3*j+2];
+            let mut v3 = Vector3::new(x,y,z);
+            v3 = v3.rotate(&r);
+            v3 = v3.scale(&s);
+            v3 = v3.translate(&t);
+            let mut vn: Option<Normcoord> = None;
+            if !model.mesh.normals.is_empty() {
+                let j = model.mesh.normal_indices[3*i] as usize;
+                let x = model.mesh.normals[3*j];
+                let y = model.mesh.normals[3*j+1];
+                let z = model.mesh.normals[3*j+2];
+                let mut vn1 = Vector3::new(x, y, z);
+                vn1 = vn1.rotate(&r);
+                let j = model.mesh.normal_indices[3*i+1] as usize;
+                let x = model.mesh.normals[3*j];
+                let y = model.mesh.normals[3*j+1];
+                let z = model.mesh.normals[3*j+2];
+                let mut vn2 = Vector3::new(x, y, z);
+                vn2 = vn2.rotate(&r);
+                let j = model.mesh.normal_indices[3*i+2] as usize;
+                let x = model.mesh.normals[3*j];
+                let y = model.mesh.normals[3*j+1];
+                let z = model.mesh.normals[3*j+2];
+                let mut vn3 = Vector3::new(x, y, z);
+                vn3 = vn3.rotate(&r);
+                vn = Some(Normcoord::new(vn1, vn2, vn3));
             }
-            for material in materials.iter() {
-                println!("{:?}", material);
-                if material.ambient_texture != "" {
-                    let t_image = ImageReader::open(&material.ambient_texture).unwrap().decode().unwrap();
-                    image.insert(String::from(&material.ambient_texture), t_image.to_rgb8());
-                }
-                if material.diffuse_texture != "" {
-                    let t_image = ImageReader::open(&material.diffuse_texture).unwrap().decode().unwrap();
-                    image.insert(String::from(&material.diffuse_texture), t_image.to_rgb8());
-                }
-                if material.specular_texture != "" {
-                    let t_image = ImageReader::open(&material.specular_texture).unwrap().decode().unwrap();
-                    image.insert(String::from(&material.specular_texture), t_image.to_rgb8());
-                }
-                if material.normal_texture != "" {
-                    let t_image = ImageReader::open(&material.normal_texture).unwrap().decode().unwrap();
-                    image.insert(String::from(&material.normal_texture), t_image.to_rgb8());
-                }
-                if material.shininess_texture != "" {
-                    let t_image = ImageReader::open(&material.shininess_texture).unwrap().decode().unwrap();
-                    image.insert(String::from(&material.shininess_texture), t_image.to_rgb8());
-                }
-                if material.dissolve_texture != "" {
-                    let t_image = ImageReader::open(&material.dissolve_texture).unwrap().decode().unwrap();
-                    image.insert(String::from(&material.dissolve_texture), t_image.to_rgb8());
-                }
-                if let Some(map_pr) = material.unknown_param.get("map_Pr") {
-                    let t_image = ImageReader::open(map_pr).unwrap().decode().unwrap();
-                    image.insert(String::from(map_pr), t_image.to_rgb8());
-                }
-                if let Some(map_pm) = material.unknown_param.get("map_Pm") {
-                    let t_image = ImageReader::open(map_pm).unwrap().decode().unwrap();
-                    image.insert(String::from(map_pm), t_image.to_rgb8());
-                }
-                if let Some(ec_string) = material.unknown_param.get("Ec") {
-                    let ec = Color::from_vector(&ec_string.split(" ").filter_map(|s| s.parse::<f32>().ok()).collect::<Vec<_>>());
-                    if (Color{r: 0., g:0., b:0.}) < ec {
-                        is_ec = true;
-                    }
-                }
+            let mut vt: Option<Texcoord> = None;
+            if !model.mesh.texcoords.is_empty() {
+                let j = model.mesh.texcoord_indices[3*i] as usize;
+                let x = model.mesh.texcoords[2*j];
+                let y = model.mesh.texcoords[2*j+1];
+                let vt1 = Vector2::new(x, y);
+                let j = model.mesh.texcoord_indices[3*i+1] as usize;
+                let x = model.mesh.texcoords[2*j];
+                let y = model.mesh.texcoords[2*j+1];
+                let vt2 = Vector2::new(x, y);
+                let j = model.mesh.texcoord_indices[3*i+2] as usize;
+                let x = model.mesh.texcoords[2*j];
+                let y = model.mesh.texcoords[2*j+1];
+                let vt3 = Vector2::new(x, y);
+                vt = Some(Texcoord::new(vt1, vt2, vt3));
             }
+            let material_id = if substituted { Some(0) } else { model.mesh.material_id };
+            planes.push(Plane::new(v1,v2,v3,vn,vt,material_id));
         }
-        Object {
+    }
+    Ok((planes, materials))
+}
+
+/// 面積の累積和
+fn area_cdf<I: Iterator<Item = f32>>(areas: I) -> Vec<f32> {
+    let mut total = 0.;
+    areas.map(|area| {
+        total += area;
+        total
+    }).collect()
+}
+
+/// 累積和から面積に比例して番号を選び, 選んだ区間の中で引き伸ばした乱数と一緒に返す
+fn pick_by_area(cdf: &[f32], u: &Vector2) -> Option<(usize, Vector2)> {
+    let total = match cdf.last() {
+        Some(&total) if total.is_finite() && 0. < total => total,
+        _ => return None,
+    };
+    let target = u.x * total;
+    let i = cdf.partition_point(|&c| c <= target).min(cdf.len() - 1);
+    let start = if i == 0 { 0. } else { cdf[i - 1] };
+    let ux = ((target - start) / (cdf[i] - start)).clamp(0., 1.);
+    Some((i, Vector2::new(ux, u.y)))
+}
+
+#[derive(Debug)]
+pub struct Mesh {
+    planes: Vec<Plane>,
+    bvh: Bvh,
+    area_cdf: Vec<f32>,
+}
+impl Mesh {
+    pub fn new(planes: Vec<Plane>) -> Mesh {
+        let bvh = Bvh::build(&planes.iter().map(|plane| plane.bounds()).collect::<Vec<_>>());
+        let area_cdf = area_cdf(planes.iter().map(|plane| plane.area()));
+        Mesh {
             planes,
-            origin: t,
-            radius: max_radius,
-            materials,
-            image,
-            is_ec,
+            bvh,
+            area_cdf,
         }
     }
-    fn intersection(&self, ray: &Ray) -> Option<(Vector3, usize)> {
-        let b = ray.direction.inner(&(ray.origin- self.origin));
-        let a = ray.direction.inner(&(ray.direction));
-        let c = (ray.origin-self.origin).inner(&(ray.origin-self.origin)) - self.radius*self.radius;
+    /// 全ての辺がちょうど2つの三角形で共有されている. 頂点は座標が完全に一致するものを同じとみなす
+    pub fn is_closed(planes: &[Plane]) -> bool {
+        let key = |v: &Vector3| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()];
+        let mut edges = HashMap::new();
+        for plane in planes.iter() {
+            let [a, b, c] = plane.vertices();
+            for (p, q) in [(a, b), (b, c), (c, a)].iter() {
+                let (p, q) = (key(p), key(q));
+                *edges.entry(if p < q { (p, q) } else { (q, p) }).or_insert(0) += 1;
+            }
+        }
+        !planes.is_empty() && edges.values().all(|&count| count == 2)
+    }
+    fn nearest(&self, ray: &Ray) -> Option<(Vector3, usize)> {
         let mut min_d = Vector3::new(MAX_RANGE,0.,0.);
-        let mut is_hit = false;
-        let mut plane_num: usize = 0;
-        if b*b-a*c < 0. {
-            None
-        } else {
-            self.planes.iter().enumerate().for_each(|(i, plane)| {
-                if let Some(v) = plane.intersection(ray) {
-                    if v.x < min_d.x {
-                        is_hit = true;
-                        min_d = v;
-                        plane_num = i;
-                    }
-                }
-            });
-            if is_hit {
-                Some((min_d, plane_num))
+        let mut plane_num = None;
+        self.bvh.traverse(ray, MAX_RANGE, |i, _| {
+            let v = self.planes[i].intersection(ray)?;
+            if v.x < min_d.x {
+                min_d = v;
+                plane_num = Some(i);
+                Some(v.x)
             } else {
                 None
             }
+        });
+        plane_num.map(|i| (min_d, i))
+    }
+}
+impl Shape for Mesh {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        self.nearest(ray).map(|(v, _)| v)
+    }
+    fn bounds(&self) -> Bounds3 {
+        self.bvh.bounds()
+    }
+    fn area(&self) -> f32 {
+        self.area_cdf.last().copied().unwrap_or(0.)
+    }
+    /// 面積に比例して三角形を選ぶ
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        match pick_by_area(&self.area_cdf, u) {
+            Some((i, u)) => self.planes[i].sample(&u),
+            None => self.bounds().centroid(),
         }
     }
-    pub fn crossjudge<'a>(objs: &'a Vec<Object>, ray: &'a Ray) -> Option<(Vector3, &'a Plane, &'a Object)>{
-        let mut is_hit = false;
-        let mut min_d = Vector3::new(MAX_RANGE,0.,0.);
-        let mut plane_num: usize = 0;
-        let mut obj_num: usize = 0;
-        objs.iter().enumerate().for_each(|(i, obj)| {
-            if let Some((v, t_plane_num)) = obj.intersection(ray) {
-                if v.x < min_d.x {
-                    min_d = v;
-                    is_hit = true;
-                    plane_num = t_plane_num;
-                    obj_num = i;
-                }
+    /// 交点の距離より先のノードは辿らずに, 当たった三角形を探し直す
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let mut best = None;
+        self.bvh.traverse(ray, hit.x * (1. + 1e-4) + MIN_RANGE, |i, _| {
+            let v = self.planes[i].intersection(ray)?;
+            // 辺を共有する三角形も同じ距離で当たるので重心座標まで比べる
+            let d = (v.x - hit.x).abs() + (v.y - hit.y).abs() + (v.z - hit.z).abs();
+            if best.is_none_or(|(best_d, _)| d < best_d) {
+                best = Some((d, i));
             }
+            None
         });
-        if is_hit {
-            Some((
-                min_d,
-                &(objs[obj_num].planes[plane_num]),
-                &objs[obj_num],
-            ))
-        } else {
+        match best {
+            Some((_, i)) => self.planes[i].surface(ray, hit),
+            None => degenerate_surface(ray, hit),
+        }
+    }
+    /// 閉じたメッシュを仮定し, 交差した三角形の数の偶奇で内外を決める
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        let mut hits = Vec::new();
+        self.bvh.traverse(ray, MAX_RANGE, |i, _| {
+            if let Some(v) = self.planes[i].intersection(ray) {
+                hits.push((v, i));
+            }
             None
+        });
+        hits.sort_by(|a, b| a.0.x.partial_cmp(&b.0.x).unwrap_or(std::cmp::Ordering::Equal));
+        // 辺や頂点で隣り合う三角形に重複して当たったものを除く
+        hits.dedup_by(|a, b| (a.0.x - b.0.x).abs() < 1e-5);
+        let inside = hits.len() % 2 == 1;
+        Some(Crossings {
+            inside,
+            hits: hits.into_iter().map(|(hit, i)| Crossing {
+                hit,
+                shape: &self.planes[i] as &dyn Shape,
+                flip: false,
+            }).collect(),
+        })
+    }
+}
+
+impl Object {
+    /// メッシュファイルを読み込み, テクスチャを`assets`に読む
+    pub fn import(obj_file: &str, r: Vector3, s: Vector3, t: Vector3, assets: &mut Assets) -> Result<Object> {
+        let (planes, materials) = load_mesh(obj_file, r, s, t, assets)?;
+        assets.textures.load_materials(&materials, &assets.policy)?;
+        let materials = materials.iter().map(|m| Material::compile(m, &assets.textures)).collect::<Result<Vec<_>>>()?;
+        let planes = displacement::displace(planes, &materials, &assets.textures);
+        let shapes = planes.into_iter().map(|plane| Box::new(plane) as Box<dyn Shape>).collect();
+        Ok(Object::new(shapes, materials, t))
+    }
+    /// 形状とMTLの材質から作り, 材質のテクスチャを`assets`に読む
+    pub fn load(shapes: Vec<Box<dyn Shape>>, materials: Vec<tobj::Material>, assets: &mut Assets) -> Result<Object> {
+        assets.textures.load_materials(&materials, &assets.policy)?;
+        let materials = materials.iter().map(|m| Material::compile(m, &assets.textures)).collect::<Result<_>>()?;
+        Ok(Object::from_shapes(shapes, materials))
+    }
+    pub fn from_shapes(shapes: Vec<Box<dyn Shape>>, materials: Vec<Material>) -> Object {
+        let origin = shapes.iter().fold(Bounds3::empty(), |acc, shape| acc.union(&shape.bounds())).centroid();
+        Object::new(shapes, materials, origin)
+    }
+    fn new(shapes: Vec<Box<dyn Shape>>, materials: Vec<Material>, origin: Vector3) -> Object {
+        let is_ec = materials.iter().any(|material| material.is_emissive());
+        let is_cutout = materials.iter().any(|material| material.alpha_texture.is_some());
+        let bvh = Bvh::build(&shapes.iter().map(|shape| shape.bounds()).collect::<Vec<_>>());
+        let bounds = bvh.bounds();
+        let is_samplable = shapes.iter().all(|shape| shape.samplable());
+        let area_cdf = area_cdf(shapes.iter().map(|shape| shape.area()));
+        Object {
+            shapes,
+            origin,
+            bounds,
+            materials,
+            is_ec,
+            is_cutout,
+            is_samplable,
+            bvh,
+            area_cdf,
         }
     }
-}
\ No newline at end of file
+    /// 表面積の合計
+    pub fn area(&self) -> f32 {
+        self.area_cdf.last().copied().filter(|total| total.is_finite()).unwrap_or(0.)
+    }
+    /// 面積に比例して形状を選び, その表面上の点を返す
+    pub fn sample(&self, u: &Vector2) -> Vector3 {
+        match pick_by_area(&self.area_cdf, u) {
+            Some((i, u)) => self.shapes[i].sample(&u),
+            None => self.origin,
+        }
+    }
+    pub fn intersection(&self, ray: &Ray, t_max: f32) -> Option<(Vector3, usize)> {
+        self.intersection_where(ray, t_max, |_, _| true)
+    }
+    /// `accept(形状の番号, 交点)`がfalseの交点は素通りする. 素通りした形状の奥の交点は探さない
+    pub fn intersection_where<F: FnMut(usize, &Vector3) -> bool>(&self, ray: &Ray, t_max: f32, mut accept: F) -> Option<(Vector3, usize)> {
+        self.bounds.intersection(ray, t_max)?;
+        let mut min_d = Vector3::new(t_max,0.,0.);
+        let mut shape_num = None;
+        self.bvh.traverse(ray, t_max, |i, _| {
+            let v = self.shapes[i].intersection(ray)?;
+            if v.x < min_d.x && accept(i, &v) {
+                min_d = v;
+                shape_num = Some(i);
+                Some(v.x)
+            } else {
+                None
+            }
+        });
+        shape_num.map(|i| (min_d, i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LoadPolicy;
+    use crate::assets::{SearchPaths, missing_texture};
+    use image::{RgbImage, Rgb};
+
+    #[test]
+    fn tangents_follow_uv_and_normals_are_interpolated() {
+        let vn = Normcoord::new(Vector3::new(0., 0., 1.), Vector3::new(1., 0., 0.), Vector3::new(0., 0., 1.));
+        // uが-x向き, vが+y向きのUV
+        let vt = Texcoord::new(Vector2::new(1., 0.), Vector2::new(0., 0.), Vector2::new(1., 1.));
+        let plane = Plane::new(Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.), Some(vn), Some(vt), None);
+        let (tangent, bitangent) = plane.tangent().unwrap();
+        assert_eq!((tangent, bitangent), (Vector3::new(-1., 0., 0.), Vector3::new(0., 1., 0.)));
+        let ray = Ray::new(Vector3::new(0.5, 0.25, 1.), Vector3::new(0., 0., -1.));
+        let surface = plane.surface(&ray, &plane.intersection(&ray).unwrap());
+        let expected = Vector3::new(0.5, 0., 0.5).normalize();
+        let d = surface.normal - expected;
+        assert!(d.inner(&d) < 1e-10, "{:?}", surface.normal);
+    }
+
+    #[test]
+    fn geometric_normal_follows_edges_and_vertex_normals() {
+        let ray = Ray::new(Vector3::new(0.25, 0.25, 1.), Vector3::new(0., 0., -1.));
+        // 法線がなければ辺から求める
+        let plane = Plane::new(Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.), None, None, None);
+        let surface = plane.surface(&ray, &plane.intersection(&ray).unwrap());
+        assert_eq!((surface.normal, surface.geometric_normal), (Vector3::new(0., 0., 1.), Vector3::new(0., 0., 1.)));
+        // 頂点の並びが逆でも頂点法線の側に向ける
+        let up = Vector3::new(0., 0., 1.);
+        let plane = Plane::new(Vector3::new(0., 0., 0.), Vector3::new(0., 1., 0.), Vector3::new(1., 0., 0.), Some(Normcoord::new(up, up, up)), None, None);
+        let surface = plane.surface(&ray, &plane.intersection(&ray).unwrap());
+        assert_eq!(surface.geometric_normal, up);
+    }
+
+    #[test]
+    fn mesh_samples_triangles_by_area() {
+        let big = Plane::new(Vector3::new(0., 0., 0.), Vector3::new(10., 0., 0.), Vector3::new(0., 10., 0.), None, None, None);
+        let small = Plane::new(Vector3::new(0., 0., 1.), Vector3::new(1., 0., 1.), Vector3::new(0., 1., 1.), None, None, None);
+        let mesh = Mesh::new(vec![small, big]);
+        let on_small = (0..1000).filter(|&i| {
+            let u = Vector2::new((i as f32 + 0.5) / 1000., 0.5);
+            mesh.sample(&u).z == 1.
+        }).count();
+        assert!((5..=15).contains(&on_small), "{}", on_small);
+    }
+
+    #[test]
+    fn mesh_surface_finds_the_hit_triangle() {
+        let v = [Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.), Vector3::new(1., 1., 0.)];
+        let mesh = Mesh::new(vec![
+            Plane::new(v[0], v[1], v[2], None, None, Some(0)),
+            Plane::new(v[1], v[3], v[2], None, None, Some(1)),
+        ]);
+        let ray = Ray::new(Vector3::new(0.8, 0.8, 1.), Vector3::new(0., 0., -1.));
+        let hit = mesh.intersection(&ray).unwrap();
+        assert_eq!(mesh.surface(&ray, &hit).material_id, Some(1));
+        // 空のメッシュはどこにも当たらず, 面を聞かれても落ちない
+        let empty = Mesh::new(Vec::new());
+        assert!(empty.intersection(&ray).is_none());
+        assert_eq!(empty.surface(&ray, &hit).material_id, None);
+        assert_eq!(empty.area(), 0.);
+    }
+
+    #[test]
+    fn only_watertight_meshes_are_closed() {
+        let v = [Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.), Vector3::new(0., 0., 1.)];
+        let face = |a: usize, b: usize, c: usize| Plane::new(v[a], v[b], v[c], None, None, None);
+        let mut tetrahedron = vec![face(0, 2, 1), face(0, 1, 3), face(1, 2, 3), face(0, 3, 2)];
+        assert!(Mesh::is_closed(&tetrahedron));
+        tetrahedron.pop();
+        assert!(!Mesh::is_closed(&tetrahedron));
+        assert!(!Mesh::is_closed(&[]));
+    }
+
+    #[test]
+    fn paths_are_resolved_and_missing_files_substituted() {
+        let dir = std::env::temp_dir().join(format!("render-assets-{}", std::process::id()));
+        let shared = dir.join("shared");
+        std::fs::create_dir_all(dir.join("models")).unwrap();
+        std::fs::create_dir_all(&shared).unwrap();
+        let obj = dir.join("models").join("tri.obj");
+        std::fs::write(&obj, "mtllib tri.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nusemtl red\nf 1/1 2/1 3/1\n").unwrap();
+        let obj = obj.to_str().unwrap();
+        let zero = Vector3::new(0., 0., 0.);
+        let one = Vector3::new(1., 1., 1.);
+        let mut search_paths = SearchPaths::new();
+        search_paths.push(&shared);
+        let strict = Assets::new(search_paths.clone(), LoadPolicy::default());
+        let mut lenient = Assets::new(search_paths, LoadPolicy::lenient());
+
+        // MTLがない
+        assert!(matches!(load_obj(obj, zero, one, zero, &strict), Err(Error::Mtl { .. })));
+        let (planes, materials) = load_obj(obj, zero, one, zero, &lenient).unwrap();
+        assert_eq!(materials.len(), 1);
+        assert_eq!(planes[0].material_id, Some(0));
+        assert!(matches!(load_obj("nothing.obj", zero, one, zero, &lenient), Err(Error::Io { .. })));
+
+        // MTLは探索ディレクトリ, テクスチャはMTLのディレクトリから見つかる
+        std::fs::write(shared.join("tri.mtl"), "newmtl red\nKd 1 0 0\nmap_Kd red.png\nmap_Ks gone.png\n").unwrap();
+        RgbImage::from_pixel(2, 2, Rgb([255, 0, 0])).save(shared.join("red.png")).unwrap();
+        let (_, materials) = load_obj(obj, zero, one, zero, &strict).unwrap();
+        let red = shared.join("red.png").canonicalize().unwrap();
+        assert_eq!(Path::new(&materials[0].diffuse_texture), red);
+        assert!(Object::import(obj, zero, one, zero, &mut Assets::new(strict.search_paths.clone(), LoadPolicy::default())).is_err());
+        Object::import(obj, zero, one, zero, &mut lenient).unwrap();
+        Object::import(obj, zero, one, zero, &mut lenient).unwrap();
+        assert_eq!(lenient.textures.len(), 2);
+        let specular = lenient.textures.id(&materials[0].specular_texture).unwrap();
+        assert_eq!(lenient.textures.get(specular), Some(&missing_texture()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
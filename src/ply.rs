@@ -0,0 +1,340 @@
+use std::fs;
+
+use crate::vector::{Vector3, Vector2};
+use crate::color::Color;
+use crate::object::{Plane, Normcoord, Texcoord, Colorcoord};
+use crate::error::{self, Error};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Type {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+impl Type {
+    fn parse(s: &str) -> Result<Type, String> {
+        Ok(match s {
+            "char" | "int8" => Type::Int8,
+            "uchar" | "uint8" => Type::UInt8,
+            "short" | "int16" => Type::Int16,
+            "ushort" | "uint16" => Type::UInt16,
+            "int" | "int32" => Type::Int32,
+            "uint" | "uint32" => Type::UInt32,
+            "float" | "float32" => Type::Float32,
+            "double" | "float64" => Type::Float64,
+            t => return Err(format!("unknown type {}", t)),
+        })
+    }
+    fn size(&self) -> usize {
+        match self {
+            Type::Int8 | Type::UInt8 => 1,
+            Type::Int16 | Type::UInt16 => 2,
+            Type::Int32 | Type::UInt32 | Type::Float32 => 4,
+            Type::Float64 => 8,
+        }
+    }
+    /// 色を0-1にするときの最大値
+    fn max(&self) -> f32 {
+        match self {
+            Type::UInt8 => 255.,
+            Type::UInt16 => 65535.,
+            _ => 1.,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Property {
+    Scalar(String, Type),
+    /// 名前, 要素数の型, 要素の型
+    List(String, Type, Type),
+}
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar(name, _) | Property::List(name, _, _) => name,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+impl Element {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&p.name()))
+    }
+}
+
+/// ヘッダ以降のデータを順に読む
+struct Body<'a> {
+    format: Format,
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> Body<'a> {
+    fn read(&mut self, ty: Type) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+            if start == self.pos {
+                return Err(String::from("unexpected end of file"));
+            }
+            let token = String::from_utf8_lossy(&self.data[start..self.pos]);
+            return token.parse::<f64>().map_err(|_| format!("invalid number `{}`", token));
+        }
+        let size = ty.size();
+        let bytes = self.data.get(self.pos..self.pos + size).ok_or("unexpected end of file")?;
+        self.pos += size;
+        let mut b = [0u8; 8];
+        b[..size].copy_from_slice(bytes);
+        if self.format == Format::BinaryBigEndian {
+            b[..size].reverse();
+        }
+        Ok(match ty {
+            Type::Int8 => b[0] as i8 as f64,
+            Type::UInt8 => b[0] as f64,
+            Type::Int16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Type::UInt16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Type::Int32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Type::UInt32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Type::Float32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Type::Float64 => f64::from_le_bytes(b),
+        })
+    }
+}
+
+/// PLYから読んだ頂点と面
+#[derive(Debug, Default)]
+struct PlyMesh {
+    positions: Vec<Vector3>,
+    normals: Vec<Vector3>,
+    colors: Vec<Color>,
+    texcoords: Vec<Vector2>,
+    faces: Vec<Vec<usize>>,
+    /// 面ごとのUV (`texcoord`リスト)
+    face_texcoords: Vec<Vec<Vector2>>,
+}
+
+fn parse_header(data: &[u8]) -> Result<(Format, Vec<Element>, usize), String> {
+    const END: &[u8] = b"end_header";
+    let end = data.windows(END.len()).position(|w| w == END).ok_or("missing end_header")?;
+    let body = match data[end + END.len()..].iter().position(|&b| b == b'\n') {
+        Some(i) => end + END.len() + i + 1,
+        None => data.len(),
+    };
+    let header = String::from_utf8_lossy(&data[..end]);
+    let mut lines = header.lines().map(|line| line.split_whitespace().collect::<Vec<_>>());
+    if lines.next().and_then(|line| line.first().copied()) != Some("ply") {
+        return Err(String::from("missing ply magic"));
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        match line.as_slice() {
+            ["format", f, ..] => format = Some(match *f {
+                "ascii" => Format::Ascii,
+                "binary_little_endian" => Format::BinaryLittleEndian,
+                "binary_big_endian" => Format::BinaryBigEndian,
+                f => return Err(format!("unknown format {}", f)),
+            }),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("invalid element count {}", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements.last_mut().ok_or("property before element")?
+                .properties.push(Property::List(name.to_string(), Type::parse(count)?, Type::parse(item)?)),
+            ["property", ty, name] => elements.last_mut().ok_or("property before element")?
+                .properties.push(Property::Scalar(name.to_string(), Type::parse(ty)?)),
+            _ => (),
+        }
+    }
+    Ok((format.ok_or("missing format")?, elements, body))
+}
+
+fn parse_ply(data: &[u8]) -> Result<PlyMesh, String> {
+    let (format, elements, pos) = parse_header(data)?;
+    let mut body = Body {
+        format,
+        data,
+        pos,
+    };
+    let mut mesh = PlyMesh::default();
+    for element in elements.iter() {
+        let xyz = [element.find(&["x"]), element.find(&["y"]), element.find(&["z"])];
+        let nxyz = [element.find(&["nx"]), element.find(&["ny"]), element.find(&["nz"])];
+        let rgb = [
+            element.find(&["red", "r", "diffuse_red"]),
+            element.find(&["green", "g", "diffuse_green"]),
+            element.find(&["blue", "b", "diffuse_blue"]),
+        ];
+        let uv = [
+            element.find(&["u", "s", "texture_u", "texture_s"]),
+            element.find(&["v", "t", "texture_v", "texture_t"]),
+        ];
+        let indices = element.find(&["vertex_indices", "vertex_index"]);
+        let texcoord = element.find(&["texcoord"]);
+        let color_max = rgb[0].map_or(1., |i| match &element.properties[i] {
+            Property::Scalar(_, ty) => ty.max(),
+            Property::List(..) => 1.,
+        });
+        let mut scalars = vec![0.; element.properties.len()];
+        let mut lists = vec![Vec::new(); element.properties.len()];
+        for _ in 0..element.count {
+            for (i, property) in element.properties.iter().enumerate() {
+                match property {
+                    Property::Scalar(_, ty) => scalars[i] = body.read(*ty)?,
+                    Property::List(_, count, item) => {
+                        let n = body.read(*count)? as usize;
+                        lists[i] = (0..n).map(|_| body.read(*item)).collect::<Result<_, _>>()?;
+                    },
+                }
+            }
+            let get = |i: Option<usize>| i.map(|i| scalars[i] as f32);
+            if element.name == "vertex" {
+                if let [Some(x), Some(y), Some(z)] = xyz.map(get) {
+                    mesh.positions.push(Vector3::new(x, y, z));
+                }
+                if let [Some(x), Some(y), Some(z)] = nxyz.map(get) {
+                    mesh.normals.push(Vector3::new(x, y, z));
+                }
+                if let [Some(r), Some(g), Some(b)] = rgb.map(get) {
+                    mesh.colors.push(Color::new(r / color_max, g / color_max, b / color_max));
+                }
+                if let [Some(u), Some(v)] = uv.map(get) {
+                    mesh.texcoords.push(Vector2::new(u, v));
+                }
+            } else if element.name == "face" {
+                if let Some(i) = indices {
+                    mesh.faces.push(lists[i].iter().map(|&j| j as usize).collect());
+                }
+                if let Some(i) = texcoord {
+                    mesh.face_texcoords.push(lists[i].chunks_exact(2).map(|c| Vector2::new(c[0] as f32, c[1] as f32)).collect());
+                }
+            }
+        }
+    }
+    Ok(mesh)
+}
+
+/// PLYファイルを読み込み, 回転・拡大・平行移動した三角形と材質を返す. 頂点カラーがあれば拡散色に使う
+pub fn load_ply(ply_file: &str, r: Vector3, s: Vector3, t: Vector3) -> error::Result<(Vec<Plane>, Vec<tobj::Material>)> {
+    let data = fs::read(ply_file).map_err(|source| Error::Io { path: ply_file.to_string(), source })?;
+    let mesh = parse_ply(&data).map_err(|message| Error::Parse { path: ply_file.to_string(), message })?;
+    let positions = mesh.positions.iter().map(|v| v.rotate(&r).scale(&s).translate(&t)).collect::<Vec<_>>();
+    let normals = mesh.normals.iter().map(|v| v.rotate(&r)).collect::<Vec<_>>();
+    let n = positions.len();
+    let mut planes = Vec::new();
+    for (f, face) in mesh.faces.iter().enumerate() {
+        if let Some(&i) = face.iter().find(|&&i| n <= i) {
+            let message = format!("face {} refers to vertex {} but there are only {} vertices", f, i, n);
+            return Err(Error::Parse { path: ply_file.to_string(), message });
+        }
+        let face_texcoords = mesh.face_texcoords.get(f).filter(|uv| uv.len() == face.len());
+        // 多角形は扇状に三角形分割する
+        for k in 2..face.len() {
+            let (a, b, c) = (face[0], face[k-1], face[k]);
+            let vn = if normals.len() == n {
+                Some(Normcoord::new(normals[a], normals[b], normals[c]))
+            } else {
+                None
+            };
+            let vt = match face_texcoords {
+                Some(uv) => Some(Texcoord::new(uv[0], uv[k-1], uv[k])),
+                None if mesh.texcoords.len() == n => Some(Texcoord::new(mesh.texcoords[a], mesh.texcoords[b], mesh.texcoords[c])),
+                None => None,
+            };
+            let mut plane = Plane::new(positions[a], positions[b], positions[c], vn, vt, Some(0));
+            if mesh.colors.len() == n {
+                plane.vc = Some(Colorcoord::new(mesh.colors[a], mesh.colors[b], mesh.colors[c]));
+            }
+            planes.push(plane);
+        }
+    }
+    let material = tobj::Material {
+        name: String::from("ply"),
+        diffuse: [0.8, 0.8, 0.8],
+        ..Default::default()
+    };
+    Ok((planes, vec![material]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 4\n\
+        property float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\n\
+        element face 1\nproperty list uchar int vertex_indices\nend_header\n";
+
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut data = format!("ply\nformat {} 1.0\ncomment test\n{}", format, HEADER).into_bytes();
+        for (i, p) in [[0., 0., 0.], [1., 0., 0.], [1., 1., 0.], [0., 1., 0.]].iter().enumerate() {
+            for &x in p.iter() {
+                let x: f32 = x;
+                data.extend(if big_endian { x.to_be_bytes() } else { x.to_le_bytes() });
+            }
+            data.extend([255, 0, 51 * i as u8]);
+        }
+        data.push(4);
+        for i in 0..4i32 {
+            data.extend(if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        data
+    }
+
+    #[test]
+    fn ascii_and_binary_agree() {
+        let ascii = format!("ply\nformat ascii 1.0\n{}0 0 0 255 0 0\n1 0 0 255 0 51\n1 1 0 255 0 102\n0 1 0 255 0 153\n4 0 1 2 3\n", HEADER);
+        for data in [ascii.into_bytes(), binary(false), binary(true)].iter() {
+            let mesh = parse_ply(data).unwrap();
+            assert_eq!(mesh.positions.len(), 4);
+            assert_eq!(mesh.positions[2], Vector3::new(1., 1., 0.));
+            assert_eq!(mesh.colors[3], Color::new(1., 0., 0.6));
+            assert_eq!(mesh.faces, vec![vec![0, 1, 2, 3]]);
+        }
+    }
+
+    #[test]
+    fn bad_tokens_and_face_indices_are_reported() {
+        let ascii = format!("ply\nformat ascii 1.0\n{}0 0 0 255 0 0\n1 0 0 255 0 51\n1 1 0 255 0 102\n0 1 O 255 0 153\n4 0 1 2 3\n", HEADER);
+        let message = parse_ply(ascii.as_bytes()).unwrap_err();
+        assert!(message.contains("`O`"), "{}", message);
+
+        let dir = std::env::temp_dir().join(format!("render-ply-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("bad_face.ply");
+        let ascii = format!("ply\nformat ascii 1.0\n{}0 0 0 255 0 0\n1 0 0 255 0 51\n1 1 0 255 0 102\n0 1 0 255 0 153\n4 0 1 2 7\n", HEADER);
+        fs::write(&file, ascii).unwrap();
+        let zero = Vector3::new(0., 0., 0.);
+        let result = load_ply(file.to_str().unwrap(), zero, Vector3::new(1., 1., 1.), zero);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(Error::Parse { message, .. }) => assert!(message.contains("vertex 7"), "{}", message),
+            _ => panic!("out of range face index was accepted"),
+        }
+    }
+}
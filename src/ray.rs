@@ -1,5 +1,5 @@
 use crate::vector::Vector3;
-use rand::Rng;
+use crate::sampler;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Ray {
@@ -18,8 +18,8 @@ impl Ray {
         let norm = norm.normalize();
         let dir = dir.normalize();
         for _ in 0..num {
-            let s: f32 = rand::thread_rng().gen_range(0.,std::f32::consts::PI);
-            let p: f32 = rand::thread_rng().gen_range(0.,std::f32::consts::PI);
+            let s: f32 = sampler::gen_range(0.,std::f32::consts::PI);
+            let p: f32 = sampler::gen_range(0.,std::f32::consts::PI);
             let x = s.sin()*p.cos();
             let y = s.sin()*p.sin();
             let z = s.cos();
@@ -37,3 +37,24 @@ impl Ray {
         rays
     }
 }
+
+/// 隣の画素(x, y方向)を通る光線. テクスチャを引く範囲を見積もるのに使う
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RayDifferential {
+    pub rx: Ray,
+    pub ry: Ray,
+}
+impl RayDifferential {
+    /// 点`point`を通り法線が`normal`の平面上で, 隣の光線が当たる点との差 (dp/dx, dp/dy)
+    pub fn offsets(&self, point: &Vector3, normal: &Vector3) -> Option<(Vector3, Vector3)> {
+        let offset = |ray: &Ray| {
+            let denom = normal.inner(&ray.direction);
+            if denom.abs() < 1e-8 {
+                return None;
+            }
+            let t = normal.inner(&(*point - ray.origin)) / denom;
+            Some(ray.origin + ray.direction * t - *point)
+        };
+        Some((offset(&self.rx)?, offset(&self.ry)?))
+    }
+}
@@ -0,0 +1,62 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::cell::RefCell;
+
+thread_local! {
+    /// シードを指定したときのスレッドごとの乱数. Noneならthread_rngを使う
+    static SEEDED: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// [low, high)の一様乱数
+pub fn gen_range(low: f32, high: f32) -> f32 {
+    SEEDED.with(|seeded| match seeded.borrow_mut().as_mut() {
+        Some(rng) => rng.gen_range(low, high),
+        None => rand::thread_rng().gen_range(low, high),
+    })
+}
+
+/// [0, 1)の一様乱数
+pub fn uniform() -> f32 {
+    gen_range(0., 1.)
+}
+
+/// 並列に処理する子の仕事に渡すシードを今の乱数から作る
+pub fn fork() -> Option<u64> {
+    SEEDED.with(|seeded| seeded.borrow_mut().as_mut().map(|rng| rng.gen()))
+}
+
+/// `seed`で初期化した乱数で`f`を実行し, 元の乱数に戻す.
+/// rayonがどのスレッドで仕事を実行しても同じ乱数列になる
+pub fn scoped<T, F: FnOnce() -> T>(seed: Option<u64>, f: F) -> T {
+    let seed = match seed {
+        Some(seed) => seed,
+        None => return f(),
+    };
+    let previous = SEEDED.with(|seeded| seeded.replace(Some(StdRng::seed_from_u64(seed))));
+    let result = f();
+    SEEDED.with(|seeded| seeded.replace(previous));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_sequence_is_reproducible() {
+        let a = scoped(Some(7), || (0..4).map(|_| uniform()).collect::<Vec<_>>());
+        let b = scoped(Some(7), || (0..4).map(|_| uniform()).collect::<Vec<_>>());
+        assert_eq!(a, b);
+        // 子の仕事でいくつ乱数を使っても外側の乱数列は変わらない
+        let c = scoped(Some(7), || {
+            fork();
+            uniform()
+        });
+        let d = scoped(Some(7), || {
+            scoped(fork(), || (uniform(), uniform()));
+            uniform()
+        });
+        assert_eq!(c, d);
+        assert!(fork().is_none());
+    }
+}
@@ -1,10 +1,18 @@
 use image::{ImageBuffer, RgbImage, Rgb};
 use rayon::prelude::*;
 
-use crate::vector::Vector3;
+use crate::vector::{Vector3, Vector2};
 use crate::color::Color;
-use crate::object::{Object, Plane};
-use crate::ray::Ray;
+use crate::object::{Object, Plane, MAX_RANGE};
+use crate::shape::{Shape, Surface};
+use crate::bvh::Bvh;
+use crate::ray::{Ray, RayDifferential};
+use crate::sampler;
+use crate::assets::{TextureCache, TextureId};
+use crate::material::{Material, NormalMapFormat, AlphaTest};
+use crate::microfacet::Frame;
+use crate::bsdf::Principled;
+use crate::texture::{Footprint, TextureFilter};
 
 #[derive(Debug)]
 pub struct Camera {
@@ -27,170 +35,362 @@ impl Camera {
             image_size,
         }
     }
+    /// `forward`の方を向き`top`を上にしたカメラ. `fov`は横の画角(度)
+    pub fn look_at(position: Vector3, forward: Vector3, top: Vector3, fov: f32, image_size: (u32, u32)) -> Camera {
+        let right = top.cross(&forward);
+        Camera::new(position, top, forward, right, fov, image_size)
+    }
+}
+
+/// 描画の設定
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RenderSettings {
+    pub num_of_bounce: usize,
+    pub sampling: usize,
+    pub num_of_diffuse: usize,
+    /// 指定すると画素ごとに決まった乱数列を使い, スレッド数によらず同じ画像になる
+    pub seed: Option<u64>,
+}
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            num_of_bounce: 4,
+            sampling: 50,
+            num_of_diffuse: 1,
+            seed: None,
+        }
+    }
+}
+
+/// プログラムからシーンを組み立てる
+#[derive(Debug, Default)]
+pub struct SceneBuilder {
+    objs: Vec<Object>,
+    textures: TextureCache,
+}
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder::default()
+    }
+    pub fn add_object(&mut self, obj: Object) -> &mut SceneBuilder {
+        self.objs.push(obj);
+        self
+    }
+    /// 三角形メッシュを追加する. 三角形の`material_id`は`materials`の番号
+    pub fn add_mesh(&mut self, planes: Vec<Plane>, materials: Vec<Material>) -> &mut SceneBuilder {
+        let shapes = planes.into_iter().map(|plane| Box::new(plane) as Box<dyn Shape>).collect();
+        self.add_object(Object::from_shapes(shapes, materials))
+    }
+    /// 形状を1つの材質で追加する. 形状の`material_id`は`Some(0)`にしておく
+    pub fn add_shape(&mut self, shape: Box<dyn Shape>, material: Material) -> &mut SceneBuilder {
+        self.add_object(Object::from_shapes(vec![shape], vec![material]))
+    }
+    /// 形状を発光体として追加する. `emission`は0-255のスケール
+    pub fn add_light(&mut self, shape: Box<dyn Shape>, emission: Color) -> &mut SceneBuilder {
+        self.add_shape(shape, Material::light(emission))
+    }
+    /// 画像を追加し, 材質から参照する番号を返す
+    pub fn add_texture(&mut self, name: &str, image: RgbImage) -> TextureId {
+        self.textures.insert(name.to_string(), image)
+    }
+    pub fn build(self, camera: Camera) -> Scene {
+        Scene::with_textures(camera, self.objs, self.textures)
+    }
 }
 
 #[derive(Debug)]
 pub struct Scene {
     camera: Camera,
-    objs: Vec<Object>
+    objs: Vec<Object>,
+    textures: TextureCache,
+    bvh: Bvh,
+    bvh_objs: Vec<usize>,
+    unbounded_objs: Vec<usize>,
+    texture_filter: TextureFilter,
 }
 impl Scene {
     pub fn new(camera: Camera, objs: Vec<Object>) -> Scene {
+        Scene::with_textures(camera, objs, TextureCache::new())
+    }
+    /// 物体の材質が名前で参照するテクスチャを共有して持つ
+    pub fn with_textures(camera: Camera, objs: Vec<Object>, textures: TextureCache) -> Scene {
+        // 無限平面などの境界が有限でない物体はBVHに入れず個別に判定する
+        let (bvh_objs, unbounded_objs): (Vec<usize>, Vec<usize>) = (0..objs.len()).partition(|&i| objs[i].bounds.is_finite());
+        let bvh = Bvh::build(&bvh_objs.iter().map(|&i| objs[i].bounds).collect::<Vec<_>>());
         Scene{
             camera,
             objs,
+            textures,
+            bvh,
+            bvh_objs,
+            unbounded_objs,
+            texture_filter: TextureFilter::default(),
+        }
+    }
+    /// テクスチャの引き方を変える. 既定はトライリニア
+    pub fn set_texture_filter(&mut self, filter: TextureFilter) {
+        self.texture_filter = filter;
+    }
+    fn crossjudge(&self, ray: &Ray) -> Option<(Vector3, &dyn Shape, &Object)> {
+        let mut min_d = Vector3::new(MAX_RANGE,0.,0.);
+        let mut hit = None;
+        for &i in self.unbounded_objs.iter() {
+            if let Some((v, shape_num)) = self.intersect_object(&self.objs[i], ray, min_d.x) {
+                min_d = v;
+                hit = Some((i, shape_num));
+            }
         }
+        self.bvh.traverse(ray, min_d.x, |i, t_max| {
+            let obj_num = self.bvh_objs[i];
+            let (v, shape_num) = self.intersect_object(&self.objs[obj_num], ray, t_max)?;
+            if v.x < min_d.x {
+                min_d = v;
+                hit = Some((obj_num, shape_num));
+                Some(v.x)
+            } else {
+                None
+            }
+        });
+        let (obj_num, shape_num) = hit?;
+        Some((min_d, self.objs[obj_num].shapes[shape_num].as_ref(), &self.objs[obj_num]))
     }
-    fn tex_calc(&self, vec: &Vector3, plane: &Plane, obj: &Object, tex_name: &str) -> Option<Color> {
-        if tex_name.is_empty() {
-            return None;
+    /// 切り抜かれたところを素通りして物体と交差させる. カメラ, 反射, 影の光線で共通
+    fn intersect_object(&self, obj: &Object, ray: &Ray, t_max: f32) -> Option<(Vector3, usize)> {
+        if !obj.is_cutout {
+            return obj.intersection(ray, t_max);
         }
-        let image = &obj.image[tex_name];
-        let width = image.width() as f32;
-        let height = image.height() as f32;
-        let vt = match plane.vt.as_ref() {
-            Some(vt) => vt,
-            None => return None,
+        obj.intersection_where(ray, t_max, |i, hit| self.is_opaque(obj, obj.shapes[i].as_ref(), ray, hit))
+    }
+    /// 交点のマスクのアルファで光線を止めるか決める
+    fn is_opaque(&self, obj: &Object, shape: &dyn Shape, ray: &Ray, hit: &Vector3) -> bool {
+        let surface = shape.surface(ray, hit);
+        let material = match surface.material_id.and_then(|id| obj.materials.get(id)) {
+            Some(material) => material,
+            None => return true,
         };
-        let v1 = vt.v2 - vt.v1;
-        let v2 = vt.v3 - vt.v1;
-        let v = v1 * vec.y + v2 * vec.z;
-        let mut x = if 0. <= v.x {
-            (v.x * height) as u32
-        } else {
-            ((1.+v.x) * height) as u32
+        let alpha = match material.alpha_texture.and_then(|texture| self.tex_calc(&surface, texture)) {
+            Some(mask) => mask.r / 255.,
+            None => return true,
         };
-        let mut y =  if 0. <= v.y {
-            (v.y * width) as u32
-        } else {
-            ((1.+v.y) * width) as u32
+        match material.alpha_test {
+            AlphaTest::Threshold(threshold) => threshold <= alpha,
+            AlphaTest::Stochastic => sampler::uniform() < alpha,
+        }
+    }
+    /// テクスチャの色(0-255). 交点に範囲があれば設定したフィルタで, なければ元の画像を線形補間する
+    fn tex_calc(&self, surface: &Surface, texture: TextureId) -> Option<Color> {
+        let mipmap = self.textures.mipmap(texture)?;
+        let uv = surface.uv?;
+        Some(mipmap.lookup(&uv, surface.footprint.as_ref(), self.texture_filter))
+    }
+    /// カメラの光線の微分から交点でテクスチャを引く範囲を求める
+    fn footprint(differential: &RayDifferential, surface: &Surface) -> Option<Footprint> {
+        let (dpdu, dpdv) = surface.tangent?;
+        let (dpdx, dpdy) = differential.offsets(&surface.point, &surface.geometric_normal)?;
+        Footprint::from_offsets(&dpdx, &dpdy, &dpdu, &dpdv)
+    }
+    /// 材質とテクスチャから交点でのBSDFを作る
+    fn principled(&self, material: &Material, surface: &Surface) -> Principled {
+        // テクスチャは0-255なので0-1にそろえる
+        let base_color = match material.diffuse_texture {
+            Some(texture) => self.tex_calc(surface, texture).map_or(Color::ones(), |map_kd| map_kd/255.),
+            None => surface.color.unwrap_or(material.diffuse),
+        };
+        let param = |texture: Option<TextureId>, value: f32| match texture {
+            Some(texture) => self.tex_calc(surface, texture).map_or(0., |color| color.r/255.),
+            None => value,
         };
-        if width as u32 <= x {
-            x = width as u32 - 1;
+        Principled {
+            base_color,
+            metallic: param(material.metallic_texture, material.metallic),
+            roughness: param(material.roughness_texture, material.roughness),
+            specular: material.specular,
+            sheen: param(material.sheen_texture, material.sheen),
+            clearcoat: param(material.clearcoat_texture, material.clearcoat),
+            clearcoat_roughness: material.clearcoat_roughness,
+            anisotropy: material.anisotropy,
+            anisotropy_rotation: material.anisotropy_rotation,
+            transmission: material.transparency,
+            ior: material.ior,
+            conductor: material.conductor,
         }
-        if height as u32 <= y {
-            y = height as u32 - 1;
+    }
+    /// 高さマップの値(0-1)
+    fn height(&self, surface: &Surface, texture: TextureId) -> Option<f32> {
+        self.tex_calc(surface, texture).map(|c| (c.r + c.g + c.b) / (3. * 255.))
+    }
+    /// 高さマップの1テクセルあたりのu, v方向の高さの差. 差を取るので範囲でぼかさない
+    fn height_gradient(&self, surface: &Surface, texture: TextureId) -> Option<(f32, f32)> {
+        let image = self.textures.get(texture)?;
+        let uv = surface.uv?;
+        let shifted = |du: f32, dv: f32| Surface {
+            uv: Some(Vector2::new(uv.x + du, uv.y + dv)),
+            footprint: None,
+            ..*surface
+        };
+        let h = self.height(&shifted(0., 0.), texture)?;
+        let hu = self.height(&shifted(1. / image.width() as f32, 0.), texture)?;
+        let hv = self.height(&shifted(0., 1. / image.height() as f32), texture)?;
+        Some((hu - h, hv - h))
+    }
+    /// 法線マップとバンプマップで曲げたシェーディング法線. 接線がなければ法線に直交する適当な向きを使う
+    fn shading_normal(&self, material: &Material, surface: &Surface) -> Vector3 {
+        let normal = surface.normal.normalize();
+        if material.normal_texture.is_none() && material.bump_texture.is_none() {
+            return normal;
+        }
+        let (tangent, bitangent) = surface.tangent.unwrap_or_else(|| normal.orthonormal_basis());
+        // 接線を法線に直交させ, UVの向きが反転していれば従接線も反転する
+        let tangent = (tangent - normal * normal.inner(&tangent)).normalize();
+        let mut side = normal.cross(&tangent);
+        if side.inner(&bitangent) < 0. {
+            side = side * -1.;
+        }
+        let mut shading = normal;
+        if let Some(texel) = material.normal_texture.and_then(|texture| self.tex_calc(surface, texture)) {
+            let texel = texel / 255.;
+            let y = match material.normal_format {
+                NormalMapFormat::OpenGl => texel.g * 2. - 1.,
+                NormalMapFormat::DirectX => 1. - texel.g * 2.,
+            };
+            shading = tangent * (texel.r * 2. - 1.) + side * y + normal * (texel.b * 2. - 1.);
         }
-        let pixel = image.get_pixel(x, y);
-        Some(Color::from_pixel(pixel))
+        if let Some((du, dv)) = material.bump_texture.and_then(|texture| self.height_gradient(surface, texture)) {
+            // 高くなる向きと反対に傾ける
+            shading = shading - (tangent * du + side * dv) * material.bump_scale;
+        }
+        if shading.inner(&shading) <= 0. || shading.x.is_nan() {
+            return normal;
+        }
+        shading.normalize()
     }
-    fn calc(&self, ray: &Ray, num_of_bounce: usize, sampling: usize, num_of_diffuse: usize) -> (Color, f32) {
+    /// `ray`の方向から来る放射輝度と交点までの距離.
+    /// `emit`がfalseなら光源サンプリングで数えた発光物体の発光は足さない.
+    /// `differential`はカメラの光線だけが持ち, 反射した先では元の画像のテクスチャを引く
+    fn calc(&self, ray: &Ray, differential: Option<&RayDifferential>, num_of_bounce: usize, sampling: usize, num_of_diffuse: usize, emit: bool) -> (Color, f32) {
         let num_of_bounce = if 0 < num_of_bounce {
             num_of_bounce - 1
         } else {
             return (Color::zeros(), 1.);
         };
-        let (intersect, plane, obj) = match Object::crossjudge(&self.objs, ray) {
-            Some((intersect, plane, obj)) => (intersect, plane, obj),
+        let (intersect, shape, obj) = match self.crossjudge(ray) {
+            Some((intersect, shape, obj)) => (intersect, shape, obj),
             None => return (Color::zeros(), 1.),
         };
-        let material_id = match plane.material_id {
+        let mut surface = shape.surface(ray, &intersect);
+        surface.footprint = differential.and_then(|differential| Scene::footprint(differential, &surface));
+        let material_id = match surface.material_id {
             Some(material_id) => material_id,
             None => return (Color::zeros(), 1.),
         };
         let material = &obj.materials[material_id];
-        let mut diffuse = Color::ones();
-        if !material.diffuse_texture.is_empty() {
-            if let Some(map_kd) = self.tex_calc(&intersect, &plane, &obj, &material.diffuse_texture) {
-                diffuse = map_kd;
-            }
+        let bsdf = self.principled(material, &surface);
+        let mut color = if emit || !Scene::is_light(obj) {
+            material.emission
         } else {
-            diffuse = Color::from_list(material.diffuse);
-        }
-        let mut pr = 0.; //ラフネス
-        if let Some(map_pr) = material.unknown_param.get("map_Pr") {
-            if let Some(pr_color) = self.tex_calc(&intersect, &plane, &obj, map_pr) {
-                pr = pr_color.r/255.;
-            }
-        }
-        if let Some(pr_string) = material.unknown_param.get("Pr") {
-            pr = pr_string.parse::<f32>().unwrap();
-        }
-        let mut pm = 0.; //メタリック
-        if let Some(map_pm) = material.unknown_param.get("map_Pm") {
-            if let Some(pm_color) = self.tex_calc(&intersect, &plane, &obj, map_pm) {
-                pm = pm_color.r/255.;
+            Color::zeros()
+        };
+
+        let new_origin = surface.point;
+        let to_eye = ray.direction * -1.;
+        let geometric = surface.geometric_normal.normalize();
+        // 曲げた法線では視線が裏側に回るなら面の法線を使う
+        let shading = self.shading_normal(material, &surface);
+        let shading = if shading.inner(&to_eye) * geometric.inner(&to_eye) <= 0. { geometric } else { shading };
+        let frame = Frame::new(shading);
+        let wo = frame.to_local(&to_eye);
+        if 0 < num_of_diffuse {
+            // 直接光も方向と同じ数だけ選んで平均する
+            let direct = (0..sampling).fold(Color::zeros(), |acc, _| acc + self.direct_light(&new_origin, &frame, &geometric, &wo, &bsdf));
+            color = color + direct / sampling.max(1) as f32;
+            let filter = material.transmission_filter;
+            let samples = (0..sampling).filter_map(|_| {
+                let u = Vector2::new(sampler::uniform(), sampler::uniform());
+                let sample = bsdf.sample(&wo, sampler::uniform(), &u).filter(|s| consistent(&frame, &geometric, &wo, &s.wi))?;
+                Some((Ray::new(new_origin, frame.to_world(&sample.wi)), sample))
+            }).collect::<Vec<_>>();
+            let seeds = samples.iter().map(|_| sampler::fork()).collect::<Vec<_>>();
+            let mut colors = vec![Color::zeros(); samples.len()];
+            samples.par_iter().zip(seeds.par_iter()).zip(colors.par_iter_mut()).for_each(|(((ray, sample), seed), color)| {
+                // 光源サンプリングできない鏡面の先では発光も数える
+                let (sampling, num_of_diffuse) = Scene::continuation(sample.delta, sampling, num_of_diffuse);
+                let (t_color, distance) = sampler::scoped(*seed, || self.calc(ray, None, num_of_bounce, sampling, num_of_diffuse, sample.delta));
+                *color = t_color * sample.weight * Scene::absorption(&bsdf, &sample.wi, filter, distance);
+            });
+            // 選べなかった方向は寄与0として数える
+            color = color + colors.iter().fold(Color::zeros(), |acc, x| acc+*x)/sampling as f32;
+        } else {
+            // 拡散の回数を使い切っても誘電体の反射と屈折は追う
+            let u = Vector2::new(sampler::uniform(), sampler::uniform());
+            if let Some(sample) = bsdf.sample_dielectric(&wo, sampler::uniform(), &u).filter(|s| consistent(&frame, &geometric, &wo, &s.wi)) {
+                let (t_color, distance) = self.calc(&Ray::new(new_origin, frame.to_world(&sample.wi)), None, num_of_bounce, sampling, 0, true);
+                color = color + t_color * sample.weight * Scene::absorption(&bsdf, &sample.wi, material.transmission_filter, distance);
             }
         }
-        if let Some(pm_string) = material.unknown_param.get("Pm") {
-            pm = pm_string.parse::<f32>().unwrap();
+        (color, intersect.x)
+    }
+    /// 選んだ方向の先で使う (方向の数, 拡散の回数).
+    /// 鏡面の反射と屈折は拡散の回数を使わず, その先は1本の光線だけで追う
+    fn continuation(delta: bool, sampling: usize, num_of_diffuse: usize) -> (usize, usize) {
+        if delta {
+            (1, num_of_diffuse)
+        } else {
+            (sampling, num_of_diffuse - 1)
         }
-        let mut tr: f32 = 0.; //透明度
-        if let Some(tr_string) = material.unknown_param.get("Tr") {
-            tr = match tr_string.parse::<f32>() {
-                Ok(tr) => tr,
-                Err(_) => 0.,
-            };
+    }
+    /// 透過する物体の裏側へ進んだ光が内部を`distance`だけ通る間の吸収 (Beer-Lambert)
+    fn absorption(bsdf: &Principled, wi: &Vector3, filter: Color, distance: f32) -> Color {
+        if 0. <= wi.z || bsdf.transmission <= 0. {
+            return Color::ones();
         }
-        if let Some(d_string) = material.unknown_param.get("d") {
-            tr = match d_string.parse::<f32>() {
-                Ok(d) => 1.-d,
-                Err(_) => 0.,
+        Color::new(filter.r.powf(distance), filter.g.powf(distance), filter.b.powf(distance))
+    }
+    /// 光源サンプリングで直接光を数える発光物体. 表面を選べないCSGを含むものは当たったときに数える
+    fn is_light(obj: &Object) -> bool {
+        obj.is_ec && obj.is_samplable && obj.bounds.is_finite()
+    }
+    /// 発光物体ごとに表面の点を1つ選び, 遮られていなければその直接光を返す
+    fn direct_light(&self, origin: &Vector3, frame: &Frame, geometric: &Vector3, wo: &Vector3, bsdf: &Principled) -> Color {
+        let mut total = Color::zeros();
+        for light in self.objs.iter().filter(|obj| Scene::is_light(obj)) {
+            let area = light.area();
+            if area <= 0. {
+                continue;
             }
-        }
-        let ni = material.optical_density; //屈折率
-        
-        let mut ec = Color::zeros(); //発光
-        if let Some(ec_string) = material.unknown_param.get("Ec") {
-            ec = Color::from_vector(&ec_string.split(" ").filter_map(|s| s.parse::<f32>().ok()).collect::<Vec<_>>());
-        }
-
-        let new_origin = ray.direction*intersect.x+ray.origin;
-        let norm = match &plane.vn {
-            Some(vn) => vn.v1,
-            None => Vector3::new(1.,0.,0.),
-        };
-        let mut tr_color = Color::zeros();
-        if 0. < tr {
-            let tr_ray = Ray::new(new_origin, ray.direction.refraction(&norm, ni));
-            let (ttr_color, d) = self.calc(&tr_ray, num_of_bounce, sampling, num_of_diffuse);
-            tr_color=ttr_color/d;
-        }
-        let mut pm_color = Color::zeros();
-        let mut diffuse_color = Color::zeros();
-        if 0 < num_of_diffuse {
-            if 0. < pm {
-                let rays = Ray::rnd_dirgen(&new_origin, &norm, &ray.direction.reflection(&norm), sampling, pr);
-                let mut colors = vec![Color::zeros(); sampling as usize];
-                rays.par_iter().zip(colors.par_iter_mut()).for_each(|(ray, color)| {
-                    let (t_color, d) = self.calc(ray, num_of_bounce, sampling, num_of_diffuse-1);
-                    *color = t_color/d;
-                });
-                pm_color = colors.iter().fold(Color{r:0.,g:0.,b:0.}, |acc, x| acc+*x)/sampling as f32;
+            let target = light.sample(&Vector2::new(sampler::uniform(), sampler::uniform()));
+            let to_light = target - *origin;
+            let dist2 = to_light.inner(&to_light);
+            let shadow = Ray::new(*origin, to_light);
+            let wi = frame.to_local(&shadow.direction);
+            if !consistent(frame, geometric, wo, &wi) {
+                continue;
             }
-
-            let nee_rays = self.next_event_estimation(&new_origin, &norm);
-            let mut colors = vec![Color::zeros(); nee_rays.len()];
-            nee_rays.par_iter().zip(colors.par_iter_mut()).for_each(|(ray, color)| {
-                let (t_color, d) = self.calc(ray, num_of_bounce, sampling, 0);
-                *color = t_color/d;
-            });
-            let nee_color = colors.iter().fold(Color{r:0.,g:0.,b:0.}, |acc, x| acc+*x);
-
-            let rays = Ray::rnd_dirgen(&new_origin, &norm, &norm, sampling-nee_rays.len(), 1.);
-            let mut colors = vec![Color::zeros(); sampling-nee_rays.len() as usize];
-            rays.par_iter().zip(colors.par_iter_mut()).for_each(|(ray, color)| {
-                let (t_color, d) = self.calc(ray, num_of_bounce, sampling, num_of_diffuse-1);
-                *color = t_color/d;
-            });
-            diffuse_color = (colors.iter().fold(Color{r:0.,g:0.,b:0.}, |acc, x| acc+*x)+nee_color)/sampling as f32;
-        }
-
-        (diffuse*diffuse_color*(1.-pm)*(1.-tr)/2. + tr_color*tr*(1.-pm) + pm_color*pm + ec, intersect.x)
-    }
-    fn next_event_estimation(&self, origin: &Vector3, norm: &Vector3) -> Vec<Ray> {
-        let mut rays = Vec::new();
-        self.objs.iter().for_each(|obj| {
-            if !obj.is_ec {
-                return;
+            let f = bsdf.eval(wo, &wi);
+            if f == Color::zeros() {
+                continue;
             }
-            if 0. < norm.inner(&(obj.origin-*origin)) {
-                rays.push(Ray::new(*origin,obj.origin-*origin));
+            // 選んだ点より手前で当たったら遮られている
+            let (intersect, shape, hit) = match self.crossjudge(&shadow) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            if !std::ptr::eq(hit, light) || intersect.x < dist2.sqrt() * (1. - 1e-3) {
+                continue;
             }
-        });
-        rays
+            let surface = shape.surface(&shadow, &intersect);
+            let emission = match surface.material_id {
+                Some(material_id) => light.materials[material_id].emission,
+                None => continue,
+            };
+            // 面積の確率密度を立体角に直す
+            let cos_light = surface.normal.inner(&shadow.direction).abs();
+            total = total + emission * f * (wi.z.abs() * cos_light * area / dist2);
+        }
+        total
     }
-    pub fn render(&self, num_of_bounce: usize, sampling: usize, num_of_diffuse: usize) {
+    /// 画像をメモリ上に描画する
+    pub fn render(&self, settings: &RenderSettings) -> RgbImage {
+        let RenderSettings { num_of_bounce, sampling, num_of_diffuse, seed } = *settings;
         let (width, height) = self.camera.image_size;
         let mut img: RgbImage = ImageBuffer::new(width, height);
         let fov = self.camera.fov/180. * std::f32::consts::PI;
@@ -202,6 +402,7 @@ impl Scene {
             .collect::<Vec<(u32, u32, &mut Rgb<u8>)>>()
             .par_iter_mut()
             .for_each(|(w, h, pixel)|{
+                let pixel_seed = seed.map(|seed| seed ^ ((*h as u64) << 32 | *w as u64));
                 let mut w = (*w) as f32;
                 let mut h = (*h) as f32;
                 let height = height as f32;
@@ -210,40 +411,218 @@ impl Scene {
                 h = (h - height/2.)/(width/2.);
                 let direction = forward/(fov/2.).tan()+right*w-top*h;
                 let ray = Ray::new(position,direction);
-                let (color, _) = self.calc(&ray, num_of_bounce, sampling, num_of_diffuse);
+                // 隣の画素への光線
+                let step = 2. / width;
+                let differential = RayDifferential {
+                    rx: Ray::new(position, direction + right*step),
+                    ry: Ray::new(position, direction - top*step),
+                };
+                let (color, _) = sampler::scoped(pixel_seed, || self.calc(&ray, Some(&differential), num_of_bounce, sampling, num_of_diffuse, true));
                 let color = color.reform();
                 pixel[0] = color.r as u8;
                 pixel[1] = color.g as u8;
                 pixel[2] = color.b as u8;
             });
-        img.save("save.png").unwrap();
+        img
     }
 }
+/// シェーディング法線で見た反射か透過かが, 面の法線で見たものと同じ. 違えば光が面を漏れる
+fn consistent(frame: &Frame, geometric: &Vector3, wo: &Vector3, wi: &Vector3) -> bool {
+    let side = |w: &Vector3| frame.to_world(w).inner(geometric);
+    (0. < wo.z * wi.z) == (0. < side(wo) * side(wi))
+}
 #[cfg(test)]
 mod tests {
     use crate::vector::Vector3;
-    use crate::scene::Scene;
+    use crate::ray::Ray;
     #[test]
     fn it_works() {
         let origin = Vector3::new(0.,0.,0.);
         let norm = Vector3::new(1.,0.,0.);
-        let rays = Ray::rnd_dirgen(&origin, &norm, 100, false);
+        let rays = Ray::rnd_dirgen(&origin, &norm, &norm, 100, 0.);
         for ray in rays {
             if norm.inner(&ray.direction) < 0. {
                 panic!("STOP");
             }
         }
     }
+    // 値を表示して確かめるだけなので普段は走らせない
     #[test]
+    #[ignore]
     fn refract() {
         let norm = Vector3::new(1.,0.,0.);
         let vec = Vector3::new(1.,1.,1.);
         panic!("{:?}",vec.refraction(&norm, 1.2));
     }
     #[test]
+    #[ignore]
     fn reflect() {
         let norm = Vector3::new(1.,1.,1.);
         let vec = Vector3::new(1.,1.,1.);
         panic!("{:?}", vec.reflection(&norm));
     }
-}
\ No newline at end of file
+    #[test]
+    fn refract_follows_snell() {
+        let norm = Vector3::new(1.,0.,0.);
+        // 空気からガラスへ: sinθi = 1.5 sinθt
+        let vec = Vector3::new(-0.6, 0.8, 0.);
+        let t = vec.refraction(&norm, 1.5);
+        assert!((t.y - 0.8 / 1.5).abs() < 1e-6 && t.x < 0.);
+        // ガラスの中から浅い角度では全反射する
+        assert_eq!(Vector3::new(0.6, 0.8, 0.).refraction(&norm, 1.5), Vector3::new(-0.6, 0.8, 0.));
+    }
+    #[test]
+    fn render_built_scene() {
+        use super::*;
+        use crate::shape::Sphere;
+        let mut builder = SceneBuilder::new();
+        builder.add_light(Box::new(Sphere::new(Vector3::new(0., 0., 0.), 1., Some(0))), Color::new(255., 255., 255.));
+        let camera = Camera::look_at(Vector3::new(0., 0., 5.), Vector3::new(0., 0., -1.), Vector3::new(0., 1., 0.), 60., (8, 6));
+        let scene = builder.build(camera);
+        let settings = RenderSettings {
+            sampling: 2,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let img = scene.render(&settings);
+        assert_eq!(img.dimensions(), (8, 6));
+        // 中央は光源, 隅は何もない
+        assert_eq!(img.get_pixel(4, 3)[0], 255);
+        assert_eq!(img.get_pixel(0, 0)[0], 0);
+    }
+    #[test]
+    fn crossjudge_matches_brute_force() {
+        use super::*;
+        use crate::shape::{Sphere, Quad, InfinitePlane};
+        let mut builder = SceneBuilder::new();
+        // 境界箱が重なり合う球と板, BVHに入らない無限平面
+        for i in 0..12 {
+            let f = i as f32;
+            let center = Vector3::new((f * 1.7).sin() * 2., (f * 2.3).cos() * 2., (f * 0.9).sin() - 1.);
+            builder.add_shape(Box::new(Sphere::new(center, 0.5 + 0.1 * (i % 4) as f32, Some(0))), Material::default());
+        }
+        builder.add_shape(Box::new(Quad::new(Vector3::new(-3., -3., -0.5), Vector3::new(6., 0., 0.), Vector3::new(0., 6., 0.), Some(0))), Material::default());
+        builder.add_shape(Box::new(InfinitePlane::new(Vector3::new(0., 0., -2.), Vector3::new(0.3, 0., 1.), Some(0))), Material::default());
+        let camera = Camera::look_at(Vector3::new(0., 0., 5.), Vector3::new(0., 0., -1.), Vector3::new(0., 1., 0.), 60., (8, 6));
+        let scene = builder.build(camera);
+        let (mut hits, mut plane_hits) = (0, 0);
+        for i in 0..400 {
+            let (x, y) = ((i % 20) as f32 / 10. - 1., (i / 20) as f32 / 10. - 1.);
+            let ray = Ray::new(Vector3::new(x, y, 5.), Vector3::new(x * 0.7, y * 0.5, -1.));
+            let brute = scene.objs.iter()
+                .filter_map(|obj| obj.intersection(&ray, MAX_RANGE).map(|(v, _)| (v.x, obj)))
+                .fold(None, |acc: Option<(f32, &Object)>, hit| match acc {
+                    Some(best) if best.0 <= hit.0 => acc,
+                    _ => Some(hit),
+                });
+            match (scene.crossjudge(&ray), brute) {
+                (Some((v, _, obj)), Some((t, expected))) => {
+                    assert!((v.x - t).abs() < 1e-5, "{} {}", v.x, t);
+                    assert!(std::ptr::eq(obj, expected));
+                    hits += 1;
+                    if std::ptr::eq(obj, scene.objs.last().unwrap()) {
+                        plane_hits += 1;
+                    }
+                },
+                (None, None) => {},
+                (found, brute) => panic!("{:?} {:?}", found.map(|h| h.0), brute.map(|h| h.0)),
+            }
+        }
+        // 球や板にも無限平面にも当たっている
+        assert!(0 < plane_hits && plane_hits < hits, "{} {}", plane_hits, hits);
+    }
+    #[test]
+    fn glass_does_not_fan_out() {
+        use super::*;
+        use crate::shape::Sphere;
+        use std::time::{Duration, Instant};
+        // 鏡面の先は方向を増やさない
+        assert_eq!(Scene::continuation(true, 8, 2), (1, 2));
+        assert_eq!(Scene::continuation(false, 8, 2), (8, 1));
+        let mut builder = SceneBuilder::new();
+        let glass = Material {
+            roughness: 0.,
+            transparency: 1.,
+            ior: 1.5,
+            ..Default::default()
+        };
+        builder.add_shape(Box::new(Sphere::new(Vector3::new(0., 0., 0.), 1., Some(0))), glass);
+        builder.add_light(Box::new(Sphere::new(Vector3::new(0., 0., -5.), 2., Some(0))), Color::new(255., 255., 255.));
+        let camera = Camera::look_at(Vector3::new(0., 0., 5.), Vector3::new(0., 0., -1.), Vector3::new(0., 1., 0.), 20., (4, 3));
+        let scene = builder.build(camera);
+        let settings = RenderSettings {
+            num_of_bounce: 8,
+            sampling: 8,
+            num_of_diffuse: 2,
+            seed: Some(1),
+        };
+        // 屈折のたびに8方向へ分かれると8^7倍の光線を追うことになり, 終わらない
+        let start = Instant::now();
+        scene.render(&settings);
+        assert!(start.elapsed() < Duration::from_secs(30), "{:?}", start.elapsed());
+    }
+    #[test]
+    fn normal_map_tilts_shading_normal() {
+        use super::*;
+        use crate::shape::{Quad, Shape};
+        use image::{RgbImage, Rgb};
+        let mut builder = SceneBuilder::new();
+        let texture = builder.add_texture("normal.png", RgbImage::from_pixel(1, 1, Rgb([128, 218, 218])));
+        let camera = Camera::look_at(Vector3::new(0., 0., 5.), Vector3::new(0., 0., -1.), Vector3::new(0., 1., 0.), 60., (8, 6));
+        let scene = builder.build(camera);
+        let quad = Quad::new(Vector3::new(-1., -1., 0.), Vector3::new(2., 0., 0.), Vector3::new(0., 2., 0.), Some(0));
+        let ray = Ray::new(Vector3::new(0., 0., 1.), Vector3::new(0., 0., -1.));
+        let surface = quad.surface(&ray, &quad.intersection(&ray).unwrap());
+        let mut material = Material {
+            normal_texture: Some(texture),
+            ..Default::default()
+        };
+        // 緑はOpenGLではvの向き, DirectXでは逆向き
+        let n = scene.shading_normal(&material, &surface);
+        assert!(0.6 < n.y && 0.6 < n.z && n.x.abs() < 0.01, "{:?}", n);
+        material.normal_format = NormalMapFormat::DirectX;
+        let n = scene.shading_normal(&material, &surface);
+        assert!(n.y < -0.6 && 0.6 < n.z, "{:?}", n);
+    }
+    #[test]
+    fn bump_map_tilts_away_from_slope() {
+        use super::*;
+        use crate::shape::{Quad, Shape};
+        use image::{RgbImage, Rgb};
+        let mut builder = SceneBuilder::new();
+        // uが増えると高くなる
+        let texture = builder.add_texture("height.png", RgbImage::from_fn(4, 4, |x, _| Rgb([x as u8 * 50; 3])));
+        let camera = Camera::look_at(Vector3::new(0., 0., 5.), Vector3::new(0., 0., -1.), Vector3::new(0., 1., 0.), 60., (8, 6));
+        let scene = builder.build(camera);
+        let quad = Quad::new(Vector3::new(-1., -1., 0.), Vector3::new(2., 0., 0.), Vector3::new(0., 2., 0.), Some(0));
+        let ray = Ray::new(Vector3::new(-0.6, 0., 1.), Vector3::new(0., 0., -1.));
+        let surface = quad.surface(&ray, &quad.intersection(&ray).unwrap());
+        let material = Material {
+            bump_texture: Some(texture),
+            bump_scale: 5.,
+            ..Default::default()
+        };
+        let n = scene.shading_normal(&material, &surface);
+        assert!(n.x < -0.5 && n.y.abs() < 1e-3 && 0. < n.z, "{:?}", n);
+    }
+    #[test]
+    fn rays_pass_through_cutout() {
+        use super::*;
+        use crate::shape::{Quad, Sphere};
+        use image::{RgbImage, Rgb};
+        let mut builder = SceneBuilder::new();
+        // 左半分が透明な葉
+        let mask = builder.add_texture("leaf.png#alpha", RgbImage::from_fn(2, 2, |x, _| Rgb([x as u8 * 255; 3])));
+        let leaf = Material {
+            alpha_texture: Some(mask),
+            ..Default::default()
+        };
+        builder.add_shape(Box::new(Quad::new(Vector3::new(-1., -1., 0.), Vector3::new(2., 0., 0.), Vector3::new(0., 2., 0.), Some(0))), leaf);
+        builder.add_light(Box::new(Sphere::new(Vector3::new(0., 0., -3.), 2., Some(0))), Color::new(255., 255., 255.));
+        let camera = Camera::look_at(Vector3::new(0., 0., 5.), Vector3::new(0., 0., -1.), Vector3::new(0., 1., 0.), 60., (8, 6));
+        let scene = builder.build(camera);
+        let hit = |x: f32| scene.crossjudge(&Ray::new(Vector3::new(x, 0., 1.), Vector3::new(0., 0., -1.))).map(|(_, _, obj)| obj.is_ec);
+        assert_eq!(hit(-0.5), Some(true));
+        assert_eq!(hit(0.5), Some(false));
+    }
+}
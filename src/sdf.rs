@@ -0,0 +1,363 @@
+use std::f32::consts::PI;
+
+use crate::vector::{Vector3, Vector2};
+use crate::ray::Ray;
+use crate::bounds::Bounds3;
+use crate::object::{MIN_RANGE, MAX_RANGE};
+use crate::shape::{Shape, Surface, Crossings};
+
+const MAX_STEPS: usize = 512;
+const HIT_EPSILON: f32 = 0.0001;
+
+fn abs(v: &Vector3) -> Vector3 {
+    Vector3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn max(v: &Vector3, m: f32) -> Vector3 {
+    Vector3::new(v.x.max(m), v.y.max(m), v.z.max(m))
+}
+
+fn length(v: &Vector3) -> f32 {
+    v.inner(v).sqrt()
+}
+
+fn infinite_bounds() -> Bounds3 {
+    Bounds3::new(
+        Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+    )
+}
+
+/// 符号付き距離関数の式. 変換は子の座標系への変換として評価する
+#[derive(Debug)]
+pub enum Sdf {
+    Sphere(f32),
+    /// 各軸方向の半分の長さ
+    Box(Vector3),
+    /// xz平面上のトーラス (中心から管の中心までの距離, 管の半径)
+    Torus(f32, f32),
+    /// y軸方向の円柱 (半径, 高さの半分)
+    Cylinder(f32, f32),
+    /// (次数, 反復回数)
+    Mandelbulb(f32, usize),
+    Union(Vec<Sdf>),
+    Intersection(Vec<Sdf>),
+    /// 最初の子から残りの子を引く
+    Difference(Vec<Sdf>),
+    SmoothUnion(f32, Vec<Sdf>),
+    SmoothIntersection(f32, Vec<Sdf>),
+    SmoothDifference(f32, Vec<Sdf>),
+    Translate(Vector3, Box<Sdf>),
+    /// 回転後の各軸 (x, y, z)
+    Rotate([Vector3; 3], Box<Sdf>),
+    Scale(f32, Box<Sdf>),
+    /// 各軸の周期 (0なら繰り返さない)
+    Repeat(Vector3, Box<Sdf>),
+    /// 表面を外側に膨らませて角を丸める
+    Round(f32, Box<Sdf>),
+}
+
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0. {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0., 1.);
+    b * (1. - h) + a * h - k * h * (1. - h)
+}
+
+impl Sdf {
+    /// `Rotate`用に, `Vector3::rotate`と同じ角度 (度) から回転後の軸を求める
+    pub fn rotate(r: &Vector3, child: Sdf) -> Sdf {
+        let axes = [
+            Vector3::new(1., 0., 0.).rotate(r),
+            Vector3::new(0., 1., 0.).rotate(r),
+            Vector3::new(0., 0., 1.).rotate(r),
+        ];
+        Sdf::Rotate(axes, Box::new(child))
+    }
+    pub fn distance(&self, p: &Vector3) -> f32 {
+        match self {
+            Sdf::Sphere(r) => length(p) - r,
+            Sdf::Box(b) => {
+                let q = abs(p) - *b;
+                length(&max(&q, 0.)) + q.x.max(q.y.max(q.z)).min(0.)
+            },
+            Sdf::Torus(major, minor) => {
+                let q = Vector2::new((p.x*p.x + p.z*p.z).sqrt() - major, p.y);
+                (q.x*q.x + q.y*q.y).sqrt() - minor
+            },
+            Sdf::Cylinder(r, h) => {
+                let d = Vector2::new((p.x*p.x + p.z*p.z).sqrt() - r, p.y.abs() - h);
+                let outside = Vector2::new(d.x.max(0.), d.y.max(0.));
+                d.x.max(d.y).min(0.) + (outside.x*outside.x + outside.y*outside.y).sqrt()
+            },
+            Sdf::Mandelbulb(power, iterations) => {
+                let mut z = *p;
+                let mut dr = 1.;
+                let mut r = length(&z);
+                for _ in 0..*iterations {
+                    if 2. < r {
+                        break;
+                    }
+                    let theta = (z.z / r).clamp(-1., 1.).acos() * power;
+                    let phi = z.y.atan2(z.x) * power;
+                    dr = r.powf(power - 1.) * power * dr + 1.;
+                    let zr = r.powf(*power);
+                    z = Vector3::new(theta.sin() * phi.cos(), phi.sin() * theta.sin(), theta.cos()) * zr + *p;
+                    r = length(&z);
+                }
+                0.5 * r.ln() * r / dr
+            },
+            Sdf::Union(children) => children.iter().map(|c| c.distance(p)).fold(f32::INFINITY, f32::min),
+            Sdf::Intersection(children) => children.iter().map(|c| c.distance(p)).fold(f32::NEG_INFINITY, f32::max),
+            Sdf::Difference(children) => {
+                let mut iter = children.iter();
+                let first = iter.next().map_or(f32::INFINITY, |c| c.distance(p));
+                iter.fold(first, |acc, c| acc.max(-c.distance(p)))
+            },
+            Sdf::SmoothUnion(k, children) => {
+                let mut iter = children.iter().map(|c| c.distance(p));
+                let first = iter.next().unwrap_or(f32::INFINITY);
+                iter.fold(first, |acc, d| smooth_min(acc, d, *k))
+            },
+            Sdf::SmoothIntersection(k, children) => {
+                let mut iter = children.iter().map(|c| c.distance(p));
+                let first = iter.next().unwrap_or(f32::NEG_INFINITY);
+                iter.fold(first, |acc, d| -smooth_min(-acc, -d, *k))
+            },
+            Sdf::SmoothDifference(k, children) => {
+                let mut iter = children.iter().map(|c| c.distance(p));
+                let first = iter.next().unwrap_or(f32::INFINITY);
+                iter.fold(first, |acc, d| -smooth_min(-acc, d, *k))
+            },
+            Sdf::Translate(t, child) => child.distance(&(*p - *t)),
+            Sdf::Rotate(axes, child) => child.distance(&Vector3::new(p.inner(&axes[0]), p.inner(&axes[1]), p.inner(&axes[2]))),
+            Sdf::Scale(s, child) => child.distance(&(*p / *s)) * s,
+            Sdf::Repeat(period, child) => {
+                let wrap = |x: f32, c: f32| if 0. < c { x - c * (x / c).round() } else { x };
+                child.distance(&Vector3::new(wrap(p.x, period.x), wrap(p.y, period.y), wrap(p.z, period.z)))
+            },
+            Sdf::Round(r, child) => child.distance(p) - r,
+        }
+    }
+    pub fn bounds(&self) -> Bounds3 {
+        match self {
+            Sdf::Sphere(r) => Bounds3::new(Vector3::new(-r, -r, -r), Vector3::new(*r, *r, *r)),
+            Sdf::Box(b) => Bounds3::new(*b * -1., *b),
+            Sdf::Torus(major, minor) => {
+                let e = Vector3::new(major + minor, *minor, major + minor);
+                Bounds3::new(e * -1., e)
+            },
+            Sdf::Cylinder(r, h) => Bounds3::new(Vector3::new(-r, -h, -r), Vector3::new(*r, *h, *r)),
+            Sdf::Mandelbulb(_, _) => Bounds3::new(Vector3::new(-1.5, -1.5, -1.5), Vector3::new(1.5, 1.5, 1.5)),
+            Sdf::Union(children) => children.iter().fold(Bounds3::empty(), |acc, c| acc.union(&c.bounds())),
+            Sdf::SmoothUnion(k, children) => {
+                let b = children.iter().fold(Bounds3::empty(), |acc, c| acc.union(&c.bounds()));
+                Bounds3::new(b.min - *k, b.max + *k)
+            },
+            Sdf::Intersection(children) | Sdf::SmoothIntersection(_, children) => {
+                children.iter().fold(infinite_bounds(), |acc, c| {
+                    let b = c.bounds();
+                    Bounds3::new(
+                        Vector3::new(acc.min.x.max(b.min.x), acc.min.y.max(b.min.y), acc.min.z.max(b.min.z)),
+                        Vector3::new(acc.max.x.min(b.max.x), acc.max.y.min(b.max.y), acc.max.z.min(b.max.z)),
+                    )
+                })
+            },
+            Sdf::Difference(children) | Sdf::SmoothDifference(_, children) => match children.first() {
+                Some(c) => c.bounds(),
+                None => Bounds3::empty(),
+            },
+            Sdf::Translate(t, child) => {
+                let b = child.bounds();
+                Bounds3::new(b.min + *t, b.max + *t)
+            },
+            Sdf::Rotate(axes, child) => {
+                let b = child.bounds();
+                if !b.is_finite() {
+                    return infinite_bounds();
+                }
+                let mut out = Bounds3::empty();
+                for i in 0..8 {
+                    let c = Vector3::new(
+                        if i & 1 == 0 { b.min.x } else { b.max.x },
+                        if i & 2 == 0 { b.min.y } else { b.max.y },
+                        if i & 4 == 0 { b.min.z } else { b.max.z },
+                    );
+                    out = out.union_point(&(axes[0] * c.x + axes[1] * c.y + axes[2] * c.z));
+                }
+                out
+            },
+            Sdf::Scale(s, child) => {
+                let b = child.bounds();
+                Bounds3::new(b.min * *s, b.max * *s)
+            },
+            Sdf::Repeat(_, _) => infinite_bounds(),
+            Sdf::Round(r, child) => {
+                let b = child.bounds();
+                Bounds3::new(b.min - *r, b.max + *r)
+            },
+        }
+    }
+}
+
+/// 符号付き距離関数をsphere tracingで描画する形状
+#[derive(Debug)]
+pub struct SdfShape {
+    sdf: Sdf,
+    bounds: Bounds3,
+    material_id: Option<usize>,
+}
+impl SdfShape {
+    pub fn new(sdf: Sdf, material_id: Option<usize>) -> SdfShape {
+        let bounds = sdf.bounds();
+        SdfShape {
+            sdf,
+            bounds,
+            material_id,
+        }
+    }
+    fn trace(&self, origin: &Vector3, direction: &Vector3, t_min: f32, t_max: f32) -> Option<f32> {
+        let mut t = t_min;
+        for _ in 0..MAX_STEPS {
+            let d = self.sdf.distance(&(*origin + *direction * t)).abs();
+            if d < HIT_EPSILON {
+                return Some(t);
+            }
+            t += d;
+            if t_max < t {
+                return None;
+            }
+        }
+        None
+    }
+    /// 光線を進める範囲. 表面上から出る光線がすぐに同じ点で止まらないように少し進めてから探す
+    fn trace_range(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let (t_enter, t_exit) = if self.bounds.is_finite() {
+            self.bounds.intersection(ray, MAX_RANGE)?
+        } else {
+            (0., MAX_RANGE)
+        };
+        let mut t_min = t_enter.max(MIN_RANGE);
+        if self.sdf.distance(&ray.origin).abs() < HIT_EPSILON {
+            t_min = t_min.max(10. * HIT_EPSILON);
+        }
+        Some((t_min, t_exit))
+    }
+    fn normal(&self, p: &Vector3) -> Vector3 {
+        // 正四面体の4点での差分から勾配を求める
+        let h = HIT_EPSILON;
+        let k = [
+            Vector3::new(1., -1., -1.),
+            Vector3::new(-1., -1., 1.),
+            Vector3::new(-1., 1., -1.),
+            Vector3::new(1., 1., 1.),
+        ];
+        k.iter()
+            .fold(Vector3::new(0., 0., 0.), |acc, k| acc + *k * self.sdf.distance(&(*p + *k * h)))
+            .normalize()
+    }
+}
+impl Shape for SdfShape {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        let (t_min, t_max) = self.trace_range(ray)?;
+        self.trace(&ray.origin, &ray.direction, t_min, t_max)
+            .map(|t| Vector3::new(t, 0., 0.))
+    }
+    fn bounds(&self) -> Bounds3 {
+        self.bounds
+    }
+    /// 境界箱の表面積で近似する
+    fn area(&self) -> f32 {
+        self.bounds.surface_area()
+    }
+    /// 境界球上の点から中心に向かって表面を探す近似的なサンプリング
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let center = self.bounds.centroid();
+        if !self.bounds.is_finite() {
+            return center;
+        }
+        let radius = length(&self.bounds.diagonal()) / 2.;
+        let z = 1. - 2. * u.x;
+        let r = (1. - z*z).max(0.).sqrt();
+        let phi = 2. * PI * u.y;
+        let dir = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+        let origin = center + dir * radius;
+        let direction = dir * -1.;
+        match self.trace(&origin, &direction, 0., radius) {
+            Some(t) => origin + direction * t,
+            None => center,
+        }
+    }
+    /// 面積も点の選び方も一様でないので光源サンプリングには使わない
+    fn samplable(&self) -> bool {
+        false
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let point = ray.origin + ray.direction * hit.x;
+        let normal = self.normal(&point);
+        Surface {
+            point,
+            normal,
+            geometric_normal: normal,
+            uv: None,
+            material_id: self.material_id,
+            color: None,
+            tangent: None,
+            footprint: None,
+        }
+    }
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        let inside = self.sdf.distance(&ray.origin) < 0.;
+        let mut hits = Vec::new();
+        if let Some((mut t_min, t_max)) = self.trace_range(ray) {
+            while let Some(t) = self.trace(&ray.origin, &ray.direction, t_min, t_max) {
+                hits.push(Vector3::new(t, 0., 0.));
+                // 表面を抜けるまで少しずつ進める
+                t_min = t + 2. * HIT_EPSILON;
+                for _ in 0..MAX_STEPS {
+                    if 2. * HIT_EPSILON <= self.sdf.distance(&(ray.origin + ray.direction * t_min)).abs() {
+                        break;
+                    }
+                    t_min += 2. * HIT_EPSILON;
+                }
+            }
+        }
+        Some(Crossings::new(self, inside, hits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_translated_round_box() {
+        let sdf = Sdf::Translate(
+            Vector3::new(0., 0., -5.),
+            Box::new(Sdf::Round(0.1, Box::new(Sdf::Box(Vector3::new(1., 1., 1.))))),
+        );
+        let shape = SdfShape::new(sdf, None);
+        let ray = Ray::new(Vector3::new(0., 0., 0.), Vector3::new(0., 0., -1.));
+        let hit = shape.intersection(&ray).unwrap();
+        assert!((hit.x - 3.9).abs() < 1e-3);
+        let normal = shape.surface(&ray, &hit).normal;
+        assert!((normal.z - 1.).abs() < 1e-3);
+        assert!(!shape.samplable());
+        let ray = Ray::new(Vector3::new(0., 3., 0.), Vector3::new(0., 0., -1.));
+        assert!(shape.intersection(&ray).is_none());
+    }
+
+    #[test]
+    fn smooth_union_blends() {
+        let a = Sdf::Translate(Vector3::new(-1., 0., 0.), Box::new(Sdf::Sphere(1.)));
+        let b = Sdf::Translate(Vector3::new(1., 0., 0.), Box::new(Sdf::Sphere(1.)));
+        let hard = Sdf::Union(vec![a, b]);
+        let p = Vector3::new(0., 0.5, 0.);
+        let d = hard.distance(&p);
+        if let Sdf::Union(children) = hard {
+            let smooth = Sdf::SmoothUnion(0.5, children);
+            assert!(smooth.distance(&p) < d);
+        }
+    }
+}
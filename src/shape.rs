@@ -0,0 +1,912 @@
+use std::fmt::Debug;
+use std::f32::consts::PI;
+
+use crate::vector::{Vector3, Vector2};
+use crate::ray::Ray;
+use crate::bounds::Bounds3;
+use crate::color::Color;
+use crate::texture::Footprint;
+use crate::object::{MIN_RANGE, MAX_RANGE};
+
+/// 交点における面の情報
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Surface {
+    pub point: Vector3,
+    /// シェーディングに使う法線
+    pub normal: Vector3,
+    /// 面そのものの法線. `normal`と同じ側を向く
+    pub geometric_normal: Vector3,
+    pub uv: Option<Vector2>,
+    pub material_id: Option<usize>,
+    /// 頂点カラー. あれば材質の拡散色の代わりに使う
+    pub color: Option<Color>,
+    /// 位置のu, vについての微分 (dp/du, dp/dv). 法線マップの接空間とテクスチャを引く範囲に使う
+    pub tangent: Option<(Vector3, Vector3)>,
+    /// テクスチャを引く範囲. 形状は作らず, シーンが光線の微分から埋める
+    pub footprint: Option<Footprint>,
+}
+
+/// 光線が形状の表面を横切る点. `flip`が真なら法線を裏返して使う
+#[derive(Debug, Copy, Clone)]
+pub struct Crossing<'a> {
+    pub hit: Vector3,
+    pub shape: &'a dyn Shape,
+    pub flip: bool,
+}
+
+/// 光線の始点が内部にあるかと, そこから先で表面を横切る点を近い順に並べたもの
+#[derive(Debug, Clone)]
+pub struct Crossings<'a> {
+    pub inside: bool,
+    pub hits: Vec<Crossing<'a>>,
+}
+impl<'a> Crossings<'a> {
+    pub fn new(shape: &'a dyn Shape, inside: bool, mut hits: Vec<Vector3>) -> Crossings<'a> {
+        hits.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap_or(std::cmp::Ordering::Equal));
+        Crossings {
+            inside,
+            hits: hits.into_iter().map(|hit| Crossing { hit, shape, flip: false }).collect(),
+        }
+    }
+}
+
+/// 光線と交差できる形状.
+/// `intersection`は(距離, 形状ごとの媒介変数2つ)を返し, `surface`はそれを受け取って法線やUVを求める
+pub trait Shape: Debug + Send + Sync {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3>;
+    fn bounds(&self) -> Bounds3;
+    fn area(&self) -> f32;
+    /// [0,1)^2の乱数から表面上の点を一様に選ぶ
+    fn sample(&self, u: &Vector2) -> Vector3;
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface;
+    /// `sample`が見える表面の点を一様に返す. falseなら光源サンプリングに使わない
+    fn samplable(&self) -> bool {
+        true
+    }
+    /// CSGで使う内外の情報. 閉じていない形状はNoneを返す
+    fn crossings(&self, _ray: &Ray) -> Option<Crossings<'_>> {
+        None
+    }
+}
+
+/// 面の情報が決まらないときの, 材質のない光線に向いた面
+pub fn degenerate_surface(ray: &Ray, hit: &Vector3) -> Surface {
+    let normal = ray.direction * -1.;
+    Surface {
+        point: ray.origin + ray.direction * hit.x,
+        normal,
+        geometric_normal: normal,
+        uv: None,
+        material_id: None,
+        color: None,
+        tangent: None,
+        footprint: None,
+    }
+}
+
+fn in_range(t: f32) -> bool {
+    MIN_RANGE < t && t < MAX_RANGE
+}
+
+fn nearest(hits: Vec<Vector3>) -> Option<Vector3> {
+    hits.into_iter().fold(None, |acc: Option<Vector3>, hit| match acc {
+        Some(best) if best.x <= hit.x => acc,
+        _ => Some(hit),
+    })
+}
+
+#[derive(Debug)]
+pub struct Sphere {
+    center: Vector3,
+    radius: f32,
+    material_id: Option<usize>,
+}
+impl Sphere {
+    pub fn new(center: Vector3, radius: f32, material_id: Option<usize>) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material_id,
+        }
+    }
+}
+impl Sphere {
+    fn hits(&self, ray: &Ray) -> Vec<Vector3> {
+        let oc = ray.origin - self.center;
+        let b = ray.direction.inner(&oc);
+        let c = oc.inner(&oc) - self.radius*self.radius;
+        let d = b*b - c;
+        if d < 0. {
+            return Vec::new();
+        }
+        let d = d.sqrt();
+        [-b - d, -b + d].iter()
+            .filter(|t| in_range(**t))
+            .map(|t| Vector3::new(*t, 0., 0.))
+            .collect()
+    }
+}
+impl Shape for Sphere {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        nearest(self.hits(ray))
+    }
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::new(self.center - self.radius, self.center + self.radius)
+    }
+    fn area(&self) -> f32 {
+        4. * PI * self.radius * self.radius
+    }
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let z = 1. - 2. * u.x;
+        let r = (1. - z*z).max(0.).sqrt();
+        let phi = 2. * PI * u.y;
+        self.center + Vector3::new(r * phi.cos(), r * phi.sin(), z) * self.radius
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let point = ray.origin + ray.direction * hit.x;
+        let normal = (point - self.center) / self.radius;
+        // vは南極で0, 北極で1
+        let u = normal.z.atan2(normal.x) / (2. * PI) + 0.5;
+        let v = 1. - normal.y.clamp(-1., 1.).acos() / PI;
+        // 極では向きが決まらない
+        let sin_theta = (normal.x*normal.x + normal.z*normal.z).sqrt();
+        let tangent = if 1e-6 < sin_theta {
+            let bitangent = Vector3::new(-normal.x*normal.y, sin_theta*sin_theta, -normal.z*normal.y) / sin_theta;
+            Some((Vector3::new(-normal.z, 0., normal.x) * (2. * PI * self.radius), bitangent * (PI * self.radius)))
+        } else {
+            None
+        };
+        Surface {
+            point,
+            normal,
+            geometric_normal: normal,
+            uv: Some(Vector2::new(u, v)),
+            material_id: self.material_id,
+            color: None,
+            tangent,
+            footprint: None,
+        }
+    }
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        let oc = ray.origin - self.center;
+        Some(Crossings::new(self, oc.inner(&oc) < self.radius*self.radius, self.hits(ray)))
+    }
+}
+
+#[derive(Debug)]
+pub struct Disk {
+    center: Vector3,
+    normal: Vector3,
+    radius: f32,
+    material_id: Option<usize>,
+}
+impl Disk {
+    pub fn new(center: Vector3, normal: Vector3, radius: f32, material_id: Option<usize>) -> Disk {
+        Disk {
+            center,
+            normal: normal.normalize(),
+            radius,
+            material_id,
+        }
+    }
+}
+impl Shape for Disk {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        let denom = ray.direction.inner(&self.normal);
+        if denom.abs() < f32::EPSILON {
+            return None;
+        }
+        let t = (self.center - ray.origin).inner(&self.normal) / denom;
+        if !in_range(t) {
+            return None;
+        }
+        let p = ray.origin + ray.direction * t - self.center;
+        let (s, r) = self.normal.orthonormal_basis();
+        let x = p.inner(&s);
+        let y = p.inner(&r);
+        if self.radius*self.radius < x*x + y*y {
+            return None;
+        }
+        Some(Vector3::new(t, x, y))
+    }
+    fn bounds(&self) -> Bounds3 {
+        disk_bounds(&self.center, &self.normal, self.radius)
+    }
+    fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let r = self.radius * u.x.sqrt();
+        let theta = 2. * PI * u.y;
+        let (s, t) = self.normal.orthonormal_basis();
+        self.center + s * (r * theta.cos()) + t * (r * theta.sin())
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let r = (hit.y*hit.y + hit.z*hit.z).sqrt();
+        let phi = hit.z.atan2(hit.y) / (2. * PI) + 0.5;
+        Surface {
+            point: ray.origin + ray.direction * hit.x,
+            normal: self.normal,
+            geometric_normal: self.normal,
+            uv: Some(Vector2::new(r / self.radius, phi)),
+            material_id: self.material_id,
+            color: None,
+            tangent: None,
+            footprint: None,
+        }
+    }
+}
+
+/// `corner`から`edge1`, `edge2`に張られた平行四辺形
+#[derive(Debug)]
+pub struct Quad {
+    corner: Vector3,
+    edge1: Vector3,
+    edge2: Vector3,
+    material_id: Option<usize>,
+}
+impl Quad {
+    pub fn new(corner: Vector3, edge1: Vector3, edge2: Vector3, material_id: Option<usize>) -> Quad {
+        Quad {
+            corner,
+            edge1,
+            edge2,
+            material_id,
+        }
+    }
+}
+impl Shape for Quad {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        let n = self.edge1.cross(&self.edge2);
+        let denom = ray.direction.inner(&n);
+        if denom.abs() < f32::EPSILON {
+            return None;
+        }
+        let t = (self.corner - ray.origin).inner(&n) / denom;
+        if !in_range(t) {
+            return None;
+        }
+        let w = ray.origin + ray.direction * t - self.corner;
+        let nn = n.inner(&n);
+        let a = w.cross(&self.edge2).inner(&n) / nn;
+        let b = self.edge1.cross(&w).inner(&n) / nn;
+        if (0. ..=1.).contains(&a) && (0. ..=1.).contains(&b) {
+            Some(Vector3::new(t, a, b))
+        } else {
+            None
+        }
+    }
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::from_points(&[
+            self.corner,
+            self.corner + self.edge1,
+            self.corner + self.edge2,
+            self.corner + self.edge1 + self.edge2,
+        ])
+    }
+    fn area(&self) -> f32 {
+        let n = self.edge1.cross(&self.edge2);
+        n.inner(&n).sqrt()
+    }
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        self.corner + self.edge1 * u.x + self.edge2 * u.y
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let normal = self.edge1.cross(&self.edge2).normalize();
+        Surface {
+            point: ray.origin + ray.direction * hit.x,
+            normal,
+            geometric_normal: normal,
+            uv: Some(Vector2::new(hit.y, hit.z)),
+            material_id: self.material_id,
+            color: None,
+            tangent: Some((self.edge1, self.edge2)),
+            footprint: None,
+        }
+    }
+}
+
+/// `point`を通り`normal`に垂直な無限平面. UVはワールド座標の長さをそのまま使う
+#[derive(Debug)]
+pub struct InfinitePlane {
+    point: Vector3,
+    normal: Vector3,
+    material_id: Option<usize>,
+}
+impl InfinitePlane {
+    pub fn new(point: Vector3, normal: Vector3, material_id: Option<usize>) -> InfinitePlane {
+        InfinitePlane {
+            point,
+            normal: normal.normalize(),
+            material_id,
+        }
+    }
+}
+impl Shape for InfinitePlane {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        let denom = ray.direction.inner(&self.normal);
+        if denom.abs() < f32::EPSILON {
+            return None;
+        }
+        let t = (self.point - ray.origin).inner(&self.normal) / denom;
+        if !in_range(t) {
+            return None;
+        }
+        let p = ray.origin + ray.direction * t - self.point;
+        let (s, r) = self.normal.orthonormal_basis();
+        Some(Vector3::new(t, p.inner(&s), p.inner(&r)))
+    }
+    fn bounds(&self) -> Bounds3 {
+        Bounds3::new(Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY), Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY))
+    }
+    fn area(&self) -> f32 {
+        f32::INFINITY
+    }
+    fn sample(&self, _u: &Vector2) -> Vector3 {
+        self.point
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        Surface {
+            point: ray.origin + ray.direction * hit.x,
+            normal: self.normal,
+            geometric_normal: self.normal,
+            uv: Some(Vector2::new(hit.y, hit.z)),
+            material_id: self.material_id,
+            color: None,
+            tangent: None,
+            footprint: None,
+        }
+    }
+    /// 法線の裏側を内部とする半空間として扱う
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        let inside = (ray.origin - self.point).inner(&self.normal) < 0.;
+        Some(Crossings::new(self, inside, self.intersection(ray).into_iter().collect()))
+    }
+}
+
+/// 形状の局所座標系. `n`が局所z軸になる
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    origin: Vector3,
+    s: Vector3,
+    t: Vector3,
+    n: Vector3,
+}
+impl Frame {
+    fn new(origin: Vector3, n: Vector3) -> Frame {
+        let n = n.normalize();
+        let (s, t) = n.orthonormal_basis();
+        Frame {
+            origin,
+            s,
+            t,
+            n,
+        }
+    }
+    fn local(&self, v: &Vector3) -> Vector3 {
+        Vector3::new(v.inner(&self.s), v.inner(&self.t), v.inner(&self.n))
+    }
+    fn world(&self, v: &Vector3) -> Vector3 {
+        self.s * v.x + self.t * v.y + self.n * v.z
+    }
+    fn ray_to_local(&self, ray: &Ray) -> (Vector3, Vector3) {
+        (self.local(&(ray.origin - self.origin)), self.local(&ray.direction))
+    }
+}
+
+fn disk_bounds(center: &Vector3, normal: &Vector3, radius: f32) -> Bounds3 {
+    let n = normal.normalize();
+    let e = Vector3::new(
+        (1. - n.x*n.x).max(0.).sqrt(),
+        (1. - n.y*n.y).max(0.).sqrt(),
+        (1. - n.z*n.z).max(0.).sqrt(),
+    ) * radius;
+    Bounds3::new(*center - e, *center + e)
+}
+
+/// ax^2+bx+c=0の実数解を小さい順に返す
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return None;
+        }
+        return Some((-c / b, -c / b));
+    }
+    let d = b*b - 4.*a*c;
+    if d < 0. {
+        return None;
+    }
+    // 桁落ちを避ける形で解く
+    let q = -0.5 * (b + b.signum() * d.sqrt());
+    let (x0, x1) = if q == 0. { (0., 0.) } else { (q / a, c / q) };
+    Some((x0.min(x1), x0.max(x1)))
+}
+
+/// x^3+ax^2+bx+c=0の最大の実数解
+fn solve_cubic(a: f64, b: f64, c: f64) -> f64 {
+    let q = (a*a - 3.*b) / 9.;
+    let r = (2.*a*a*a - 9.*a*b + 27.*c) / 54.;
+    let mut x = if r*r < q*q*q {
+        let theta = (r / (q*q*q).sqrt()).clamp(-1., 1.).acos();
+        -2. * q.sqrt() * (theta / 3.).cos() - a / 3.
+    } else {
+        let s = -r.signum() * (r.abs() + (r*r - q*q*q).sqrt()).cbrt();
+        let t = if s == 0. { 0. } else { q / s };
+        s + t - a / 3.
+    };
+    for _ in 0..2 {
+        let f = ((x + a) * x + b) * x + c;
+        let df = (3. * x + 2. * a) * x + b;
+        if df.abs() < 1e-12 {
+            break;
+        }
+        x -= f / df;
+    }
+    x
+}
+
+/// x^4+ax^3+bx^2+cx+d=0の実数解 (Ferrariの方法)
+fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    let a2 = a*a;
+    let p = b - 3.*a2/8.;
+    let q = c - a*b/2. + a2*a/8.;
+    let r = d - a*c/4. + a2*b/16. - 3.*a2*a2/256.;
+    let mut roots = Vec::with_capacity(4);
+    if q.abs() < 1e-10 {
+        if let Some((z0, z1)) = solve_quadratic(1., p, r) {
+            for z in [z0, z1].iter() {
+                if 0. <= *z {
+                    roots.push(z.sqrt());
+                    roots.push(-z.sqrt());
+                }
+            }
+        }
+    } else {
+        let m = solve_cubic(p, p*p/4. - r, -q*q/8.);
+        if m <= 0. {
+            return roots;
+        }
+        let s = (2.*m).sqrt();
+        if let Some((y0, y1)) = solve_quadratic(1., -s, p/2. + m + q/(2.*s)) {
+            roots.push(y0);
+            roots.push(y1);
+        }
+        if let Some((y0, y1)) = solve_quadratic(1., s, p/2. + m - q/(2.*s)) {
+            roots.push(y0);
+            roots.push(y1);
+        }
+    }
+    roots.iter().map(|y| {
+        // 元の多項式でNewton法をかけて精度を上げる
+        let mut x = y - a/4.;
+        for _ in 0..2 {
+            let f = (((x + a) * x + b) * x + c) * x + d;
+            let df = ((4. * x + 3. * a) * x + 2. * b) * x + c;
+            if df.abs() < 1e-12 {
+                break;
+            }
+            x -= f / df;
+        }
+        x
+    }).collect()
+}
+
+/// `base`から`axis`方向に伸びる円柱. `caps`が真なら両端を円板で閉じる
+#[derive(Debug)]
+pub struct Cylinder {
+    frame: Frame,
+    height: f32,
+    radius: f32,
+    caps: bool,
+    material_id: Option<usize>,
+}
+impl Cylinder {
+    pub fn new(base: Vector3, axis: Vector3, radius: f32, caps: bool, material_id: Option<usize>) -> Cylinder {
+        Cylinder {
+            frame: Frame::new(base, axis),
+            height: axis.inner(&axis).sqrt(),
+            radius,
+            caps,
+            material_id,
+        }
+    }
+}
+impl Cylinder {
+    fn hits(&self, ray: &Ray) -> Vec<Vector3> {
+        let (o, d) = self.frame.ray_to_local(ray);
+        let mut hits = Vec::with_capacity(2);
+        let mut update = |t: f32, part: f32| {
+            if in_range(t) {
+                hits.push(Vector3::new(t, part, 0.));
+            }
+        };
+        let a = (d.x*d.x + d.y*d.y) as f64;
+        let b = 2. * (o.x*d.x + o.y*d.y) as f64;
+        let c = (o.x*o.x + o.y*o.y - self.radius*self.radius) as f64;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for t in [t0 as f32, t1 as f32].iter() {
+                let z = o.z + d.z * t;
+                if 0. <= z && z <= self.height {
+                    update(*t, 0.);
+                }
+            }
+        }
+        if self.caps && f32::EPSILON < d.z.abs() {
+            for (part, h) in [(1., 0.), (2., self.height)].iter() {
+                let t = (h - o.z) / d.z;
+                let x = o.x + d.x * t;
+                let y = o.y + d.y * t;
+                if x*x + y*y <= self.radius*self.radius {
+                    update(t, *part);
+                }
+            }
+        }
+        hits
+    }
+}
+impl Shape for Cylinder {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        nearest(self.hits(ray))
+    }
+    fn bounds(&self) -> Bounds3 {
+        let top = self.frame.origin + self.frame.n * self.height;
+        disk_bounds(&self.frame.origin, &self.frame.n, self.radius).union(&disk_bounds(&top, &self.frame.n, self.radius))
+    }
+    fn area(&self) -> f32 {
+        let side = 2. * PI * self.radius * self.height;
+        if self.caps {
+            side + 2. * PI * self.radius * self.radius
+        } else {
+            side
+        }
+    }
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let side = 2. * PI * self.radius * self.height / self.area();
+        let local = if u.x < side {
+            let phi = 2. * PI * u.y;
+            Vector3::new(self.radius * phi.cos(), self.radius * phi.sin(), self.height * u.x / side)
+        } else {
+            let ux = (u.x - side) / (1. - side);
+            let (ux, z) = if ux < 0.5 { (ux * 2., 0.) } else { (ux * 2. - 1., self.height) };
+            let r = self.radius * ux.sqrt();
+            let phi = 2. * PI * u.y;
+            Vector3::new(r * phi.cos(), r * phi.sin(), z)
+        };
+        self.frame.origin + self.frame.world(&local)
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let point = ray.origin + ray.direction * hit.x;
+        let p = self.frame.local(&(point - self.frame.origin));
+        let phi = p.y.atan2(p.x) / (2. * PI) + 0.5;
+        let (normal, uv) = if hit.y == 0. {
+            (Vector3::new(p.x, p.y, 0.).normalize(), Vector2::new(phi, p.z / self.height))
+        } else {
+            let r = (p.x*p.x + p.y*p.y).sqrt() / self.radius;
+            let nz = if hit.y == 1. { -1. } else { 1. };
+            (Vector3::new(0., 0., nz), Vector2::new(r, phi))
+        };
+        let normal = self.frame.world(&normal);
+        Surface {
+            point,
+            normal,
+            geometric_normal: normal,
+            uv: Some(uv),
+            material_id: self.material_id,
+            color: None,
+            tangent: None,
+            footprint: None,
+        }
+    }
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        if !self.caps {
+            return None;
+        }
+        let p = self.frame.local(&(ray.origin - self.frame.origin));
+        let inside = p.x*p.x + p.y*p.y < self.radius*self.radius && 0. < p.z && p.z < self.height;
+        Some(Crossings::new(self, inside, self.hits(ray)))
+    }
+}
+
+/// 底面の中心`base`から頂点`base + axis`へ細くなる円錐. `caps`が真なら底面を閉じる
+#[derive(Debug)]
+pub struct Cone {
+    frame: Frame,
+    height: f32,
+    radius: f32,
+    caps: bool,
+    material_id: Option<usize>,
+}
+impl Cone {
+    pub fn new(base: Vector3, axis: Vector3, radius: f32, caps: bool, material_id: Option<usize>) -> Cone {
+        Cone {
+            frame: Frame::new(base, axis),
+            height: axis.inner(&axis).sqrt(),
+            radius,
+            caps,
+            material_id,
+        }
+    }
+}
+impl Cone {
+    fn hits(&self, ray: &Ray) -> Vec<Vector3> {
+        let (o, d) = self.frame.ray_to_local(ray);
+        let mut hits = Vec::with_capacity(3);
+        let mut update = |t: f32, part: f32| {
+            if in_range(t) {
+                hits.push(Vector3::new(t, part, 0.));
+            }
+        };
+        let k = (self.radius / self.height) as f64;
+        let k2 = k * k;
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, (self.height - o.z) as f64);
+        let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+        let a = dx*dx + dy*dy - k2*dz*dz;
+        let b = 2. * (ox*dx + oy*dy + k2*oz*dz);
+        let c = ox*ox + oy*oy - k2*oz*oz;
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for t in [t0 as f32, t1 as f32].iter() {
+                let z = o.z + d.z * t;
+                if 0. <= z && z <= self.height {
+                    update(*t, 0.);
+                }
+            }
+        }
+        if self.caps && f32::EPSILON < d.z.abs() {
+            let t = -o.z / d.z;
+            let x = o.x + d.x * t;
+            let y = o.y + d.y * t;
+            if x*x + y*y <= self.radius*self.radius {
+                update(t, 1.);
+            }
+        }
+        hits
+    }
+}
+impl Shape for Cone {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        nearest(self.hits(ray))
+    }
+    fn bounds(&self) -> Bounds3 {
+        let apex = self.frame.origin + self.frame.n * self.height;
+        disk_bounds(&self.frame.origin, &self.frame.n, self.radius).union_point(&apex)
+    }
+    fn area(&self) -> f32 {
+        let side = PI * self.radius * (self.radius*self.radius + self.height*self.height).sqrt();
+        if self.caps {
+            side + PI * self.radius * self.radius
+        } else {
+            side
+        }
+    }
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let side = PI * self.radius * (self.radius*self.radius + self.height*self.height).sqrt() / self.area();
+        let phi = 2. * PI * u.y;
+        let local = if u.x < side {
+            // 頂点からの距離の2乗が一様になるように選ぶ
+            let f = (u.x / side).sqrt();
+            Vector3::new(self.radius * f * phi.cos(), self.radius * f * phi.sin(), self.height * (1. - f))
+        } else {
+            let r = self.radius * ((u.x - side) / (1. - side)).sqrt();
+            Vector3::new(r * phi.cos(), r * phi.sin(), 0.)
+        };
+        self.frame.origin + self.frame.world(&local)
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let point = ray.origin + ray.direction * hit.x;
+        let p = self.frame.local(&(point - self.frame.origin));
+        let phi = p.y.atan2(p.x) / (2. * PI) + 0.5;
+        let (normal, uv) = if hit.y == 0. {
+            let k = self.radius / self.height;
+            let normal = Vector3::new(p.x, p.y, k * k * (self.height - p.z)).normalize();
+            (normal, Vector2::new(phi, p.z / self.height))
+        } else {
+            let r = (p.x*p.x + p.y*p.y).sqrt() / self.radius;
+            (Vector3::new(0., 0., -1.), Vector2::new(r, phi))
+        };
+        let normal = self.frame.world(&normal);
+        Surface {
+            point,
+            normal,
+            geometric_normal: normal,
+            uv: Some(uv),
+            material_id: self.material_id,
+            color: None,
+            tangent: None,
+            footprint: None,
+        }
+    }
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        if !self.caps {
+            return None;
+        }
+        let p = self.frame.local(&(ray.origin - self.frame.origin));
+        let r = self.radius * (1. - p.z / self.height);
+        let inside = p.x*p.x + p.y*p.y < r*r && 0. < p.z && p.z < self.height;
+        Some(Crossings::new(self, inside, self.hits(ray)))
+    }
+}
+
+/// 中心`center`, 回転軸`axis`, 中心から管の中心までが`major_radius`, 管の半径が`minor_radius`のトーラス
+#[derive(Debug)]
+pub struct Torus {
+    frame: Frame,
+    major_radius: f32,
+    minor_radius: f32,
+    material_id: Option<usize>,
+}
+impl Torus {
+    pub fn new(center: Vector3, axis: Vector3, major_radius: f32, minor_radius: f32, material_id: Option<usize>) -> Torus {
+        Torus {
+            frame: Frame::new(center, axis),
+            major_radius,
+            minor_radius,
+            material_id,
+        }
+    }
+}
+impl Torus {
+    fn hits(&self, ray: &Ray) -> Vec<Vector3> {
+        // 係数が大きくなりすぎないように, 境界箱に入る点まで光線の始点を進めてから解く
+        let t_enter = match self.bounds().intersection(ray, MAX_RANGE) {
+            Some((t_enter, _)) => t_enter,
+            None => return Vec::new(),
+        };
+        let (o, d) = self.frame.ray_to_local(ray);
+        let o = o + d * t_enter;
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+        let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+        let r2 = (self.major_radius * self.major_radius) as f64;
+        let m2 = (self.minor_radius * self.minor_radius) as f64;
+        let e = ox*ox + oy*oy + oz*oz - r2 - m2;
+        let f = ox*dx + oy*dy + oz*dz;
+        let roots = solve_quartic(
+            4. * f,
+            4. * f * f + 2. * e + 4. * r2 * dz * dz,
+            4. * e * f + 8. * r2 * oz * dz,
+            e * e - 4. * r2 * (m2 - oz * oz),
+        );
+        roots.iter()
+            .map(|t| *t as f32 + t_enter)
+            .filter(|t| in_range(*t))
+            .map(|t| Vector3::new(t, 0., 0.))
+            .collect()
+    }
+}
+impl Shape for Torus {
+    fn intersection(&self, ray: &Ray) -> Option<Vector3> {
+        nearest(self.hits(ray))
+    }
+    fn bounds(&self) -> Bounds3 {
+        let b = disk_bounds(&self.frame.origin, &self.frame.n, self.major_radius + self.minor_radius);
+        Bounds3::new(b.min - self.minor_radius, b.max + self.minor_radius)
+    }
+    fn area(&self) -> f32 {
+        4. * PI * PI * self.major_radius * self.minor_radius
+    }
+    /// 管の周りの角度θの密度は(R + r cosθ)に比例する. 累積分布(Rθ + r sinθ)/2πRを二分法で逆に解く
+    fn sample(&self, u: &Vector2) -> Vector3 {
+        let phi = 2. * PI * u.x;
+        let target = 2. * PI * self.major_radius * u.y;
+        let (mut lo, mut hi) = (0., 2. * PI);
+        for _ in 0..24 {
+            let mid = (lo + hi) / 2.;
+            if self.major_radius * mid + self.minor_radius * mid.sin() < target {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let theta = (lo + hi) / 2.;
+        let r = self.major_radius + self.minor_radius * theta.cos();
+        let local = Vector3::new(r * phi.cos(), r * phi.sin(), self.minor_radius * theta.sin());
+        self.frame.origin + self.frame.world(&local)
+    }
+    fn surface(&self, ray: &Ray, hit: &Vector3) -> Surface {
+        let point = ray.origin + ray.direction * hit.x;
+        let p = self.frame.local(&(point - self.frame.origin));
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+        let scale = if rho > 0. { 1. - self.major_radius / rho } else { 0. };
+        let normal = Vector3::new(p.x * scale, p.y * scale, p.z).normalize();
+        let u = p.y.atan2(p.x) / (2. * PI) + 0.5;
+        let v = p.z.atan2(rho - self.major_radius) / (2. * PI) + 0.5;
+        let normal = self.frame.world(&normal);
+        Surface {
+            point,
+            normal,
+            geometric_normal: normal,
+            uv: Some(Vector2::new(u, v)),
+            material_id: self.material_id,
+            color: None,
+            tangent: None,
+            footprint: None,
+        }
+    }
+    fn crossings(&self, ray: &Ray) -> Option<Crossings<'_>> {
+        let p = self.frame.local(&(ray.origin - self.frame.origin));
+        let rho = (p.x*p.x + p.y*p.y).sqrt() - self.major_radius;
+        let inside = rho*rho + p.z*p.z < self.minor_radius*self.minor_radius;
+        Some(Crossings::new(self, inside, self.hits(ray)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sphere_hit_from_outside_and_inside() {
+        let sphere = Sphere::new(Vector3::new(0., 0., -5.), 1., None);
+        let ray = Ray::new(Vector3::new(0., 0., 0.), Vector3::new(0., 0., -1.));
+        let hit = sphere.intersection(&ray).unwrap();
+        assert!((hit.x - 4.).abs() < 1e-5);
+        let surface = sphere.surface(&ray, &hit);
+        assert!((surface.normal - Vector3::new(0., 0., 1.)).inner(&Vector3::new(1., 1., 1.)).abs() < 1e-5);
+        let ray = Ray::new(Vector3::new(0., 0., -5.), Vector3::new(1., 0., 0.));
+        assert!((sphere.intersection(&ray).unwrap().x - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn quad_and_disk_bounds_contain_samples() {
+        let quad = Quad::new(Vector3::new(0., 0., 0.), Vector3::new(2., 0., 0.), Vector3::new(0., 1., 1.), None);
+        let disk = Disk::new(Vector3::new(1., 1., 1.), Vector3::new(1., 1., 0.), 2., None);
+        let shapes: [&dyn Shape; 2] = [&quad, &disk];
+        for shape in shapes.iter() {
+            let b = shape.bounds();
+            for i in 0..10 {
+                for j in 0..10 {
+                    let p = shape.sample(&Vector2::new(i as f32 / 10., j as f32 / 10.));
+                    assert!(b.min.x - 1e-4 <= p.x && p.x <= b.max.x + 1e-4);
+                    assert!(b.min.y - 1e-4 <= p.y && p.y <= b.max.y + 1e-4);
+                    assert!(b.min.z - 1e-4 <= p.z && p.z <= b.max.z + 1e-4);
+                }
+            }
+        }
+        let ray = Ray::new(Vector3::new(1., 5., 0.5), Vector3::new(0., -1., 0.));
+        let hit = quad.intersection(&ray).unwrap();
+        assert!((hit.x - 4.5).abs() < 1e-5);
+        assert!((hit.y - 0.5).abs() < 1e-5 && (hit.z - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn quartic_roots() {
+        // (x-1)(x-2)(x+3)(x-0.5)
+        let mut roots = solve_quartic(-0.5, -7., 9.5, -3.);
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected = [-3., 0.5, 1., 2.];
+        assert_eq!(roots.len(), 4);
+        for (r, e) in roots.iter().zip(expected.iter()) {
+            assert!((r - e).abs() < 1e-6, "{:?}", roots);
+        }
+    }
+
+    #[test]
+    fn torus_cylinder_cone_hits() {
+        let torus = Torus::new(Vector3::new(0., 0., 0.), Vector3::new(0., 1., 0.), 2., 0.5, None);
+        let ray = Ray::new(Vector3::new(-10., 0., 0.), Vector3::new(1., 0., 0.));
+        assert!((torus.intersection(&ray).unwrap().x - 7.5).abs() < 1e-3);
+        let ray = Ray::new(Vector3::new(0., 10., 0.), Vector3::new(0., -1., 0.));
+        assert!(torus.intersection(&ray).is_none());
+
+        // 外側半分(cosθ > 0)の面積の割合は1/2 + r/πR
+        let n = 100;
+        let outer = (0..n * n).filter(|&i| {
+            let u = Vector2::new(((i / n) as f32 + 0.5) / n as f32, ((i % n) as f32 + 0.5) / n as f32);
+            let p = torus.sample(&u);
+            2. < (p.x * p.x + p.z * p.z).sqrt()
+        }).count();
+        let expected = 0.5 + 0.5 / (PI * 2.);
+        assert!((outer as f32 / (n * n) as f32 - expected).abs() < 0.01, "{}", outer);
+
+        let cylinder = Cylinder::new(Vector3::new(0., 0., 0.), Vector3::new(0., 2., 0.), 1., true, None);
+        let ray = Ray::new(Vector3::new(0., 10., 0.), Vector3::new(0., -1., 0.));
+        let hit = cylinder.intersection(&ray).unwrap();
+        assert!((hit.x - 8.).abs() < 1e-4);
+        assert_eq!(cylinder.surface(&ray, &hit).normal, Vector3::new(0., 1., 0.));
+        let ray = Ray::new(Vector3::new(-5., 1., 0.), Vector3::new(1., 0., 0.));
+        assert!((cylinder.intersection(&ray).unwrap().x - 4.).abs() < 1e-4);
+
+        let cone = Cone::new(Vector3::new(0., 0., 0.), Vector3::new(0., 2., 0.), 1., false, None);
+        let ray = Ray::new(Vector3::new(-5., 1., 0.), Vector3::new(1., 0., 0.));
+        assert!((cone.intersection(&ray).unwrap().x - 4.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fs;
+use std::collections::HashMap;
+
+use crate::vector::Vector3;
+use crate::object::{Plane, Normcoord};
+use crate::error::{self, Error};
+
+/// STLの読み込み設定
+#[derive(Debug, Copy, Clone)]
+pub struct StlOptions {
+    /// 同じ座標の頂点をまとめて滑らかな法線を作る
+    pub weld: bool,
+    /// これより大きい角度(度)で接する面とは法線を平均しない
+    pub crease_angle: f32,
+}
+impl Default for StlOptions {
+    fn default() -> StlOptions {
+        StlOptions {
+            weld: true,
+            crease_angle: 30.,
+        }
+    }
+}
+
+fn read_vector(words: &mut std::str::SplitWhitespace) -> Option<Vector3> {
+    let mut f = || words.next().and_then(|s| s.parse::<f32>().ok());
+    Some(Vector3::new(f()?, f()?, f()?))
+}
+
+/// 面の法線と3頂点の組を返す
+fn parse_stl(data: &[u8]) -> Result<Vec<(Vector3, [Vector3; 3])>, String> {
+    // バイナリでも"solid"で始まるものがあるので大きさで先に判定する
+    if 84 <= data.len() {
+        let n = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        if data.len() == 84 + 50 * n {
+            let f = |i: usize| f32::from_le_bytes([data[i], data[i+1], data[i+2], data[i+3]]);
+            let v = |i: usize| Vector3::new(f(i), f(i+4), f(i+8));
+            return Ok((0..n).map(|k| {
+                let i = 84 + 50 * k;
+                (v(i), [v(i+12), v(i+24), v(i+36)])
+            }).collect());
+        }
+    }
+    let text = String::from_utf8_lossy(data);
+    let mut words = text.split_whitespace();
+    let mut facets = Vec::new();
+    let mut normal = Vector3::new(0., 0., 0.);
+    let mut vertices = Vec::new();
+    while let Some(word) = words.next() {
+        match word {
+            "normal" => normal = read_vector(&mut words).ok_or("invalid facet normal")?,
+            "vertex" => vertices.push(read_vector(&mut words).ok_or("invalid vertex")?),
+            "endfacet" => {
+                if vertices.len() == 3 {
+                    facets.push((normal, [vertices[0], vertices[1], vertices[2]]));
+                }
+                vertices.clear();
+            },
+            _ => (),
+        }
+    }
+    Ok(facets)
+}
+
+/// 座標が完全に一致する頂点に同じ番号を振る
+fn weld(facets: &[[Vector3; 3]]) -> Vec<[usize; 3]> {
+    let mut ids = HashMap::new();
+    // -0.と0.を同じ頂点として扱う
+    let key = |v: &Vector3| [v.x + 0., v.y + 0., v.z + 0.].map(f32::to_bits);
+    facets.iter().map(|facet| facet.map(|v| {
+        let n = ids.len();
+        *ids.entry(key(&v)).or_insert(n)
+    })).collect()
+}
+
+fn angle(a: Vector3, b: Vector3) -> f32 {
+    let (a, b) = (a.normalize(), b.normalize());
+    a.inner(&b).clamp(-1., 1.).acos()
+}
+
+/// 角度で重み付けした頂点法線を, 折り目の角度より急な面を除いて面の角ごとに求める
+fn smooth_normals(facets: &[[Vector3; 3]], ids: &[[usize; 3]], face_normals: &[Vector3], crease_angle: f32) -> Vec<[Vector3; 3]> {
+    let crease = crease_angle.to_radians();
+    let mut incident: HashMap<usize, Vec<(usize, f32)>> = HashMap::new();
+    for (f, facet) in facets.iter().enumerate() {
+        for k in 0..3 {
+            let p = facet[k];
+            let weight = angle(facet[(k+1)%3] - p, facet[(k+2)%3] - p);
+            incident.entry(ids[f][k]).or_default().push((f, weight));
+        }
+    }
+    face_normals.iter().enumerate().map(|(f, &n)| {
+        [0, 1, 2].map(|k| {
+            let sum = incident[&ids[f][k]].iter()
+                .filter(|&&(g, _)| angle(n, face_normals[g]) <= crease)
+                .fold(Vector3::new(0., 0., 0.), |acc, &(g, weight)| acc + face_normals[g] * weight);
+            if 0. < sum.inner(&sum) { sum.normalize() } else { n }
+        })
+    }).collect()
+}
+
+/// STLファイル(バイナリ/ASCII)を読み込み, 回転・拡大・平行移動した三角形を返す. 材質は0番を使う
+pub fn load_stl(stl_file: &str, r: Vector3, s: Vector3, t: Vector3, options: &StlOptions) -> error::Result<Vec<Plane>> {
+    let data = fs::read(stl_file).map_err(|source| Error::Io { path: stl_file.to_string(), source })?;
+    let parsed = parse_stl(&data).map_err(|message| Error::Parse { path: stl_file.to_string(), message })?;
+    let facets = parsed.iter()
+        .map(|(_, facet)| facet.map(|v| v.rotate(&r).scale(&s).translate(&t)))
+        .collect::<Vec<_>>();
+    // 面の法線は変換後の頂点から求め, 潰れた面だけファイルの法線を使う
+    let face_normals = parsed.iter().zip(facets.iter()).map(|((normal, _), facet)| {
+        let n = (facet[1] - facet[0]).cross(&(facet[2] - facet[0]));
+        if 0. < n.inner(&n) { n.normalize() } else { normal.rotate(&r) }
+    }).collect::<Vec<_>>();
+    let normals = if options.weld {
+        smooth_normals(&facets, &weld(&facets), &face_normals, options.crease_angle)
+    } else {
+        face_normals.iter().map(|&n| [n; 3]).collect()
+    };
+    Ok(facets.iter().zip(normals.iter()).map(|(facet, vn)| {
+        Plane::new(facet[0], facet[1], facet[2], Some(Normcoord::new(vn[0], vn[1], vn[2])), None, Some(0))
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x軸で90度に折れた2枚の三角形
+    const FOLD: &str = "solid fold
+        facet normal 0 0 1
+          outer loop
+            vertex 0 0 0
+            vertex 1 0 0
+            vertex 0 1 0
+          endloop
+        endfacet
+        facet normal 0 1 0
+          outer loop
+            vertex 0 0 0
+            vertex 0 0 1
+            vertex 1 0 0
+          endloop
+        endfacet
+    endsolid fold";
+
+    fn facets() -> Vec<[Vector3; 3]> {
+        parse_stl(FOLD.as_bytes()).unwrap().into_iter().map(|(_, facet)| facet).collect()
+    }
+
+    #[test]
+    fn ascii_and_binary() {
+        let ascii = parse_stl(FOLD.as_bytes()).unwrap();
+        assert_eq!(ascii.len(), 2);
+        let mut binary = vec![0u8; 80];
+        binary.extend(2u32.to_le_bytes());
+        for (normal, facet) in ascii.iter() {
+            for v in [*normal, facet[0], facet[1], facet[2]].iter() {
+                for x in [v.x, v.y, v.z].iter() {
+                    binary.extend(x.to_le_bytes());
+                }
+            }
+            binary.extend([0, 0]);
+        }
+        assert_eq!(parse_stl(&binary).unwrap(), ascii);
+    }
+
+    #[test]
+    fn crease_angle_keeps_sharp_edges() {
+        let facets = facets();
+        let ids = weld(&facets);
+        assert_eq!(ids[0][0], ids[1][0]);
+        assert_eq!(ids[0][1], ids[1][2]);
+        let face_normals = vec![Vector3::new(0., 0., 1.), Vector3::new(0., 1., 0.)];
+        let sharp = smooth_normals(&facets, &ids, &face_normals, 30.);
+        assert_eq!(sharp[0][0], face_normals[0]);
+        let smooth = smooth_normals(&facets, &ids, &face_normals, 100.);
+        let n = smooth[0][0];
+        assert!((n.y - 0.5f32.sqrt()).abs() < 1e-5 && (n.z - 0.5f32.sqrt()).abs() < 1e-5);
+        // 共有していない頂点は自分の面の法線のまま
+        assert_eq!(smooth[0][2], face_normals[0]);
+    }
+}
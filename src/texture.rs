@@ -0,0 +1,330 @@
+use image::RgbImage;
+use serde::Deserialize;
+
+use crate::vector::{Vector3, Vector2};
+use crate::color::Color;
+
+/// EWAで楕円の長軸と短軸の比をこれ以下に抑える. 大きいほど斜めの面がくっきりするが遅い
+const MAX_ANISOTROPY: f32 = 8.;
+/// EWAのガウス関数の鋭さ
+const EWA_ALPHA: f32 = 2.;
+
+/// UVが0-1の外に出たときの扱い
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WrapMode {
+    /// 繰り返す
+    #[default]
+    Repeat,
+    /// 端の色を伸ばす
+    Clamp,
+    /// 折り返しながら繰り返す
+    Mirror,
+}
+impl WrapMode {
+    /// `n`個の画素の`i`番目に直す
+    fn apply(self, i: i64, n: i64) -> i64 {
+        match self {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n { i } else { 2 * n - 1 - i }
+            },
+        }
+    }
+}
+
+/// テクスチャの引き方
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFilter {
+    /// 最も近い画素
+    Nearest,
+    /// 周りの4画素を線形補間
+    Bilinear,
+    /// 画素の大きさに合ったミップマップの2段を線形補間
+    #[default]
+    Trilinear,
+    /// 画素の広がりを楕円として重みをつける. 斜めから見た面でもぼけにくい
+    Ewa,
+}
+
+/// 画面上で隣の画素へ移ったときのUVの変化. 光線の微分から求める
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Footprint {
+    pub dx: Vector2,
+    pub dy: Vector2,
+}
+impl Footprint {
+    /// 位置の変化 (dp/dx, dp/dy) をdp/du, dp/dvの張る面で最小二乗に解いてUVの変化にする
+    pub fn from_offsets(dpdx: &Vector3, dpdy: &Vector3, dpdu: &Vector3, dpdv: &Vector3) -> Option<Footprint> {
+        let (a, b, c) = (dpdu.inner(dpdu), dpdu.inner(dpdv), dpdv.inner(dpdv));
+        let det = a * c - b * b;
+        // UVが潰れている
+        if det <= a * c * 1e-6 {
+            return None;
+        }
+        let solve = |d: &Vector3| {
+            let (e, f) = (dpdu.inner(d), dpdv.inner(d));
+            Vector2::new((c * e - b * f) / det, (a * f - b * e) / det)
+        };
+        Some(Footprint {
+            dx: solve(dpdx),
+            dy: solve(dpdy),
+        })
+    }
+}
+
+/// 縦横を半分ずつにした画像の列. UVはOBJと同じく左下が原点で, vが増えると画像の上へ進む
+#[derive(Debug, Clone, PartialEq)]
+pub struct MipMap {
+    levels: Vec<RgbImage>,
+    /// u, vそれぞれの方向の扱い
+    pub wrap: [WrapMode; 2],
+}
+impl MipMap {
+    pub fn new(image: RgbImage) -> MipMap {
+        let mut levels = vec![image];
+        while let Some(next) = levels.last().and_then(downsample) {
+            levels.push(next);
+        }
+        MipMap {
+            levels,
+            wrap: [WrapMode::Repeat; 2],
+        }
+    }
+    /// 元の画像
+    pub fn image(&self) -> &RgbImage {
+        &self.levels[0]
+    }
+    pub fn level(&self, level: usize) -> Option<&RgbImage> {
+        self.levels.get(level)
+    }
+    pub fn num_of_levels(&self) -> usize {
+        self.levels.len()
+    }
+    /// `uv`の色(0-255). 範囲が分からなければ元の画像を線形補間する
+    pub fn lookup(&self, uv: &Vector2, footprint: Option<&Footprint>, filter: TextureFilter) -> Color {
+        match (filter, footprint) {
+            (TextureFilter::Nearest, _) => self.nearest(uv),
+            (TextureFilter::Bilinear, _) | (_, None) => self.bilinear(0, uv),
+            (TextureFilter::Trilinear, Some(footprint)) => {
+                let width = 2. * [footprint.dx.x, footprint.dx.y, footprint.dy.x, footprint.dy.y].iter()
+                    .fold(0_f32, |acc, d| acc.max(d.abs()));
+                let (level, t) = self.lod(width);
+                self.blend(level, t, |level| self.bilinear(level, uv))
+            },
+            (TextureFilter::Ewa, Some(footprint)) => self.ewa(uv, footprint),
+        }
+    }
+    /// `level`段目の画素. はみ出したら`wrap`に従う
+    fn texel(&self, level: usize, x: i64, y: i64) -> Color {
+        let image = &self.levels[level];
+        let (width, height) = (image.width() as i64, image.height() as i64);
+        let x = self.wrap[0].apply(x, width);
+        let y = self.wrap[1].apply(y, height);
+        Color::from_pixel(image.get_pixel(x as u32, y as u32))
+    }
+    /// `level`段目の画素を単位とした座標. 画素の中心が整数になる
+    fn texel_coord(&self, level: usize, uv: &Vector2) -> (f32, f32) {
+        let image = &self.levels[level];
+        (uv.x * image.width() as f32 - 0.5, (1. - uv.y) * image.height() as f32 - 0.5)
+    }
+    fn nearest(&self, uv: &Vector2) -> Color {
+        let (x, y) = self.texel_coord(0, uv);
+        self.texel(0, (x + 0.5).floor() as i64, (y + 0.5).floor() as i64)
+    }
+    pub fn bilinear(&self, level: usize, uv: &Vector2) -> Color {
+        let (x, y) = self.texel_coord(level, uv);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        self.texel(level, x0, y0) * ((1. - dx) * (1. - dy))
+            + self.texel(level, x0 + 1, y0) * (dx * (1. - dy))
+            + self.texel(level, x0, y0 + 1) * ((1. - dx) * dy)
+            + self.texel(level, x0 + 1, y0 + 1) * (dx * dy)
+    }
+    /// UVで`width`の大きさが1画素になる段と, その次の段との補間の割合
+    fn lod(&self, width: f32) -> (usize, f32) {
+        let image = self.image();
+        let resolution = image.width().max(image.height()) as f32;
+        let last = (self.levels.len() - 1) as f32;
+        let lod = (width * resolution).max(f32::MIN_POSITIVE).log2().clamp(0., last);
+        let level = lod.floor();
+        (level as usize, lod - level)
+    }
+    fn blend<F: Fn(usize) -> Color>(&self, level: usize, t: f32, f: F) -> Color {
+        if t <= 0. || self.levels.len() <= level + 1 {
+            f(level)
+        } else {
+            f(level) * (1. - t) + f(level + 1) * t
+        }
+    }
+    /// 楕円の範囲で重みをつけた平均 (Heckbert 1989). 細長すぎる楕円は短軸を伸ばし, 短軸に合った段を使う
+    fn ewa(&self, uv: &Vector2, footprint: &Footprint) -> Color {
+        let length = |v: &Vector2| (v.x * v.x + v.y * v.y).sqrt();
+        let (mut major, mut minor) = (footprint.dx, footprint.dy);
+        if length(&major) < length(&minor) {
+            std::mem::swap(&mut major, &mut minor);
+        }
+        if !length(&major).is_finite() {
+            return self.texel(self.levels.len() - 1, 0, 0);
+        }
+        // 地平線の近くでは画像より何桁も大きくなるので, 軸の長さを画像1枚分までに抑える
+        let cap = |v: Vector2| if 1. < length(&v) { v * (1. / length(&v)) } else { v };
+        let (major, mut minor) = (cap(major), cap(minor));
+        let (major_length, minor_length) = (length(&major), length(&minor));
+        if minor_length <= 0. {
+            return self.bilinear(0, uv);
+        }
+        if minor_length * MAX_ANISOTROPY < major_length {
+            minor = minor * (major_length / (minor_length * MAX_ANISOTROPY));
+        }
+        let (level, t) = self.lod(length(&minor));
+        self.blend(level, t, |level| self.ewa_level(level, uv, &major, &minor))
+    }
+    fn ewa_level(&self, level: usize, uv: &Vector2, major: &Vector2, minor: &Vector2) -> Color {
+        let image = &self.levels[level];
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let (s, t) = self.texel_coord(level, uv);
+        // 画像の行はvと逆向き
+        let (d0, d1) = ((major.x * width, -major.y * height), (minor.x * width, -minor.y * height));
+        // 楕円 a s² + b s t + c t² < 1. 1を足して少なくとも1画素は入るようにする
+        let mut a = d0.1 * d0.1 + d1.1 * d1.1 + 1.;
+        let mut b = -2. * (d0.0 * d0.1 + d1.0 * d1.1);
+        let mut c = d0.0 * d0.0 + d1.0 * d1.0 + 1.;
+        let inv_f = 1. / (a * c - b * b / 4.);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+        let det = 4. * a * c - b * b;
+        let (half_s, half_t) = (2. * (det * c).sqrt() / det, 2. * (det * a).sqrt() / det);
+        let mut sum = Color::zeros();
+        let mut total = 0.;
+        for y in (t - half_t).ceil() as i64..=(t + half_t).floor() as i64 {
+            let dt = y as f32 - t;
+            for x in (s - half_s).ceil() as i64..=(s + half_s).floor() as i64 {
+                let ds = x as f32 - s;
+                let r2 = a * ds * ds + b * ds * dt + c * dt * dt;
+                if r2 < 1. {
+                    let weight = (-EWA_ALPHA * r2).exp() - (-EWA_ALPHA).exp();
+                    sum = sum + self.texel(level, x, y) * weight;
+                    total += weight;
+                }
+            }
+        }
+        if total <= 0. {
+            return self.bilinear(level, uv);
+        }
+        sum / total
+    }
+}
+
+/// 縦横を半分にした画像. 1×1なら次はない. 奇数の辺は端の画素も平均に入れる
+fn downsample(image: &RgbImage) -> Option<RgbImage> {
+    let (width, height) = image.dimensions();
+    if width <= 1 && height <= 1 {
+        return None;
+    }
+    let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+    let range = |i: u32, n: u32, next: u32| (i * n / next, ((i + 1) * n / next).max(i * n / next + 1));
+    Some(RgbImage::from_fn(next_width, next_height, |x, y| {
+        let (x0, x1) = range(x, width, next_width);
+        let (y0, y1) = range(y, height, next_height);
+        let mut sum = [0_u32; 3];
+        for sy in y0..y1 {
+            for sx in x0..x1 {
+                let pixel = image.get_pixel(sx, sy);
+                for c in 0..3 {
+                    sum[c] += pixel[c] as u32;
+                }
+            }
+        }
+        let count = (x1 - x0) * (y1 - y0);
+        image::Rgb([
+            ((sum[0] + count / 2) / count) as u8,
+            ((sum[1] + count / 2) / count) as u8,
+            ((sum[2] + count / 2) / count) as u8,
+        ])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn checker(size: u32) -> RgbImage {
+        RgbImage::from_fn(size, size, |x, y| if (x + y) % 2 == 0 { Rgb([255; 3]) } else { Rgb([0; 3]) })
+    }
+
+    #[test]
+    fn pyramid_averages_down_to_one_pixel() {
+        let mip = MipMap::new(checker(8));
+        assert_eq!(mip.num_of_levels(), 4);
+        assert_eq!(mip.level(3).unwrap().dimensions(), (1, 1));
+        assert!((mip.level(1).unwrap().get_pixel(0, 0)[0] as i32 - 128).abs() <= 1);
+        // 奇数や細長い画像でも1×1まで縮める
+        let mip = MipMap::new(RgbImage::new(5, 2));
+        let sizes = (0..mip.num_of_levels()).map(|i| mip.level(i).unwrap().dimensions()).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(5, 2), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn v_points_up_and_wrap_modes() {
+        // 上の行が白, 下の行が黒
+        let image = RgbImage::from_fn(4, 2, |x, y| Rgb([if y == 0 { 255 } else { 0 }, x as u8 * 10, 0]));
+        let mut mip = MipMap::new(image);
+        let at = |mip: &MipMap, u: f32, v: f32| mip.lookup(&Vector2::new(u, v), None, TextureFilter::Nearest);
+        assert_eq!(at(&mip, 0.1, 0.9).r, 255.);
+        assert_eq!(at(&mip, 0.1, 0.1).r, 0.);
+        // 幅と高さを取り違えない
+        assert_eq!(at(&mip, 0.9, 0.9).g, 30.);
+        assert_eq!(at(&mip, 1.1, 0.9).g, 0.);
+        mip.wrap = [WrapMode::Clamp; 2];
+        assert_eq!(at(&mip, 1.1, 0.9).g, 30.);
+        assert_eq!(at(&mip, -0.1, 0.9).g, 0.);
+        mip.wrap = [WrapMode::Mirror; 2];
+        assert_eq!(at(&mip, 1.1, 0.9).g, 30.);
+        assert_eq!(at(&mip, -0.1, 0.9).g, 0.);
+        assert_eq!(at(&mip, 0.1, 1.1).r, 255.);
+        assert_eq!(at(&mip, 0.1, 1.6).r, 0.);
+    }
+
+    #[test]
+    fn footprint_from_surface_offsets() {
+        let (dpdu, dpdv) = (Vector3::new(2., 0., 0.), Vector3::new(0., 4., 0.));
+        let footprint = Footprint::from_offsets(&Vector3::new(1., 0., 0.), &Vector3::new(0., 1., 1.), &dpdu, &dpdv).unwrap();
+        assert_eq!(footprint, Footprint { dx: Vector2::new(0.5, 0.), dy: Vector2::new(0., 0.25) });
+        assert_eq!(Footprint::from_offsets(&dpdu, &dpdv, &dpdu, &(dpdu * 2.)), None);
+    }
+
+    #[test]
+    fn wide_footprints_blur_the_checker() {
+        let mip = MipMap::new(checker(64));
+        // 画素の中心
+        let uv = Vector2::new(18.5 / 64., 1. - 25.5 / 64.);
+        let small = Footprint { dx: Vector2::new(1e-4, 0.), dy: Vector2::new(0., 1e-4) };
+        let wide = Footprint { dx: Vector2::new(0.5, 0.), dy: Vector2::new(0., 0.5) };
+        // 斜めから見たように片方だけ長い
+        let oblique = Footprint { dx: Vector2::new(0.2, 0.), dy: Vector2::new(0., 0.01) };
+        for &filter in [TextureFilter::Trilinear, TextureFilter::Ewa].iter() {
+            let sharp = mip.lookup(&uv, Some(&small), filter);
+            let texel = mip.bilinear(0, &uv).r;
+            assert!((texel == 0. || texel == 255.) && (sharp.r - texel).abs() < 1., "{:?} {}", filter, sharp.r);
+            for footprint in [wide, oblique].iter() {
+                let gray = mip.lookup(&uv, Some(footprint), filter).r;
+                assert!((gray - 127.5).abs() < 8., "{:?} {}", filter, gray);
+            }
+        }
+        // 地平線近くの巨大な範囲でも画像1枚分の平均で済ませる
+        let horizon = Footprint { dx: Vector2::new(1e7, 3e6), dy: Vector2::new(0., 1e-3) };
+        let gray = mip.lookup(&uv, Some(&horizon), TextureFilter::Ewa).r;
+        assert!((gray - 127.5).abs() < 8., "{}", gray);
+        let infinite = Footprint { dx: Vector2::new(f32::INFINITY, 0.), dy: Vector2::new(0., 1.) };
+        assert!((mip.lookup(&uv, Some(&infinite), TextureFilter::Ewa).r - 127.5).abs() < 1.);
+        // 範囲が分からなければ元の画像
+        assert_eq!(mip.lookup(&uv, None, TextureFilter::Ewa), mip.bilinear(0, &uv));
+    }
+}
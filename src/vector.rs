@@ -1,6 +1,6 @@
 use std::ops::{Add, Sub, Mul, Div};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
 pub struct Vector3 {
     pub x: f32,
     pub y: f32,
@@ -19,22 +19,38 @@ impl Vector3 {
         let denom = (self.x*self.x+self.y*self.y+self.z*self.z).sqrt();
         *self/denom
     }
+    /// スネルの法則で屈折させた方向. `norm`は外向きの法線で, 裏側から入ると物体の内側から出ていくとみなす.
+    /// 全反射するときは反射方向
     pub fn refraction(&self, norm: &Vector3, optical_density: f32) -> Vector3 {
-        let mut norm = *norm*-1.;
-        let mut ni = 1.;
-        let mut no = optical_density;
-        if self.inner(&norm) < 0.{
-            ni = optical_density;
-            no = 1.;
+        let mut norm = *norm;
+        let mut cos_i = -self.inner(&norm);
+        let mut eta = 1. / optical_density;
+        if cos_i < 0. {
             norm = norm*-1.;
+            cos_i = -cos_i;
+            eta = optical_density;
+        }
+        let sin2_t = eta*eta*(1.-cos_i*cos_i);
+        if 1. < sin2_t {
+            return self.reflection(&norm);
         }
-        
-        *self + norm*(no*no-ni*ni+(self.inner(&norm).powf(2.)).sqrt()-self.inner(&norm))
+        *self*eta + norm*(eta*cos_i-(1.-sin2_t).sqrt())
     }
     pub fn reflection(&self, norm: &Vector3) -> Vector3 {
         *self+*norm*2.*(-(*self).inner(norm))
     }
+    /// 自身 (正規化済み) に直交する正規直交基底の残り2軸
+    pub fn orthonormal_basis(&self) -> (Vector3, Vector3) {
+        let sign = 1f32.copysign(self.z);
+        let a = -1. / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Vector3::new(1. + sign * self.x * self.x * a, sign * b, -sign * self.x),
+            Vector3::new(b, sign + self.y * self.y * a, -self.y),
+        )
+    }
     #[allow(dead_code)]
+    #[allow(clippy::clone_on_copy)]
     pub fn rotate(&self, r: &Vector3) -> Vector3 {
         let mut r = (*r).clone();
         r = r/180.*std::f32::consts::PI;